@@ -1,12 +1,29 @@
-use clap::Arg;
+use clap::{Arg, ArgGroup};
+use semver::VersionReq;
+use serde::{Deserialize, Deserializer};
 use std::{
-    net::{AddrParseError, SocketAddr},
+    collections::HashMap,
+    convert::TryFrom,
+    ffi::OsString,
+    future::Future,
+    net::{AddrParseError, IpAddr, SocketAddr},
+    num::NonZeroU32,
     num::ParseIntError,
+    path::{Path, PathBuf},
     time::Duration,
 };
 use thiserror::Error;
 
-use crate::fping::{version::VersionError, Launcher};
+use crate::{
+    event_stream::DEFAULT_MAX_LINE_LENGTH,
+    exit::EXIT_CODES_HELP,
+    fping::{
+        for_program, version::VersionError, AddressFamily, Generate, PatternError,
+        PatternOverrides, SpawnOptions, DEFAULT_PERIOD, PACKET_SIZES, PING_GROUPS, SUMMARY_GROUPS,
+    },
+    prom::DEFAULT_RTT_BUCKETS,
+    targets::{self, TargetLabels, TargetsError},
+};
 
 #[derive(Debug, Error)]
 pub enum ArgsError {
@@ -16,25 +33,218 @@ pub enum ArgsError {
     MalformedBind(#[from] AddrParseError),
     #[error(transparent)]
     FpingProblem(#[from] VersionError),
-    #[error("runtime-limit is not valid duration: {0}")]
-    NotAValidTimeout(#[from] humantime::DurationError),
+    #[error("{0} is not a valid duration: {1}")]
+    NotAValidDuration(&'static str, #[source] humantime::DurationError),
+    #[error("{0} is not a valid number: {1}")]
+    NotANumber(&'static str, #[source] ParseIntError),
+    #[error(transparent)]
+    InvalidPattern(#[from] PatternError),
+    #[error("assume-fping-version is not a valid version: {0:?}")]
+    InvalidAssumedVersion(String, #[source] semver::Error),
+    #[error("inject-loss expects <target>=<ratio> with a ratio between 0 and 1, got {0:?}")]
+    InvalidInjectedLoss(String),
+    #[error(
+        "generate expects <address>/<prefix> or a start and end address of one family, got {0:?}"
+    )]
+    InvalidGenerate(String),
+    #[error("metrics-namespace must match [a-zA-Z_:][a-zA-Z0-9_:]*, got {0:?}")]
+    InvalidNamespace(String),
+    #[error("source-address is not a valid ip: {0:?}")]
+    InvalidSourceAddress(String, #[source] AddrParseError),
+    #[error("source-address {0} cannot be used with --{1}")]
+    SourceFamilyMismatch(IpAddr, &'static str),
+    #[error("rtt-buckets expects non-negative seconds, got {0:?}")]
+    InvalidRttBucket(String),
+    #[error("unable to read target file {0}: {1}")]
+    TargetFileUnreadable(PathBuf, #[source] std::io::Error),
+    #[error("unable to read config {0}: {1}")]
+    ConfigUnreadable(PathBuf, #[source] std::io::Error),
+    #[error("invalid config {0}: {1}")]
+    ConfigParse(PathBuf, #[source] toml::de::Error),
+    #[error("invalid target in {0}: {1}")]
+    InvalidTargetFile(PathBuf, #[source] TargetsError),
+    #[error("no targets given")]
+    NoTargets,
+    #[error("interval must be at least 1ms, got {}", humantime::format_duration(*.0))]
+    IntervalTooShort(Duration),
+    #[error("{0} must be longer than zero")]
+    ZeroInterval(&'static str),
+    #[error("max-line-length must be at least 1 byte, every line from fping would be discarded")]
+    ZeroMaxLineLength,
+    #[error(
+        "probe-timeout of {} exceeds the interval of {}",
+        humantime::format_duration(*.0),
+        humantime::format_duration(*.1)
+    )]
+    ProbeTimeoutTooLong(Duration, Duration),
+    #[error("nice must be between -20 and 19, got {0}")]
+    NiceOutOfRange(i32),
+    #[error(
+        "packet-size must be between {min} and {max} bytes, got {0}",
+        min = PACKET_SIZES.start(),
+        max = PACKET_SIZES.end()
+    )]
+    PacketSizeOutOfRange(u32),
+    #[error("ttl must be between 1 and 255, got {0}")]
+    TtlOutOfRange(u32),
     #[error(transparent)]
-    #[cfg(test)]
-    TestError(#[from] clap::Error),
+    InvalidUsage(#[from] clap::Error),
+}
+
+/// Where PEM material for the metrics server is read from
+#[derive(Debug, PartialEq)]
+pub enum PemSource {
+    File(PathBuf),
+    /// Name of an environment variable holding the PEM itself
+    Env(String),
+}
+
+impl std::fmt::Display for PemSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PemSource::File(path) => path.display().fmt(f),
+            PemSource::Env(name) => write!(f, "${}", name),
+        }
+    }
+}
+
+impl PemSource {
+    fn from_args(args: &clap::ArgMatches, file: &str, env: &str) -> Option<Self> {
+        args.value_of_os(file)
+            .map(|path| PemSource::File(path.into()))
+            .or_else(|| {
+                args.value_of(env)
+                    .map(|name| PemSource::Env(name.to_owned()))
+            })
+    }
+}
+
+/// Credentials scrapers have to present through HTTP basic auth
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicAuth {
+    pub user: String,
+    pub password: String,
+}
+
+#[derive(Debug)]
+pub struct TlsArgs {
+    pub cert: PemSource,
+    pub key: PemSource,
+    pub client_ca: Option<PathBuf>,
+}
+
+/// How scrapes are served when another summary request is already queued
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverloadBehavior {
+    /// respond with 503 Service Unavailable
+    Shed,
+    /// serve the results of the previous summary
+    Cached,
+}
+
+/// How log records are written to stderr
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// human readable, colored on a terminal
+    Pretty,
+    /// one JSON object per line
+    Json,
 }
 
 #[derive(Debug)]
 pub struct MetricArgs {
     pub addr: SocketAddr,
+    /// Unix socket to serve on instead of `addr`
+    pub socket: Option<PathBuf>,
     pub path: String,
+    /// Prefix of every exported metric name
+    pub namespace: String,
     pub runtime_limit: Option<Duration>,
+    pub scrape_cooldown: Duration,
+    pub drain_timeout: Duration,
+    pub overload: OverloadBehavior,
+    pub reuse_port: bool,
+    pub v6only: bool,
+    pub debug_endpoints: bool,
+    /// Serve POST /-/reload
+    pub lifecycle: bool,
+    pub emit_timestamps: bool,
+    /// Skip the packet delay variation histogram and its calculation
+    pub disable_ipdv: bool,
+    /// Sorted upper bounds of the round-trip time histograms in seconds
+    pub rtt_buckets: Vec<f64>,
+    /// Fraction of replies to drop per target, for testing alerts
+    pub inject_loss: HashMap<String, f64>,
+    pub tls: Option<TlsArgs>,
+    pub auth: Option<BasicAuth>,
+}
+
+#[derive(Debug)]
+pub struct TextfileArgs {
+    pub path: PathBuf,
+    pub interval: Duration,
+}
+
+#[cfg(feature = "graphite")]
+#[derive(Debug, Clone)]
+pub struct GraphiteArgs {
+    pub addr: String,
+    pub interval: Duration,
+    pub prefix: String,
 }
 
 #[derive(Debug)]
 pub struct Args {
+    pub fping_bin: String,
     pub fping_version: semver::Version,
     pub metrics: MetricArgs,
+    pub textfile: Option<TextfileArgs>,
+    #[cfg(feature = "graphite")]
+    pub graphite: Option<GraphiteArgs>,
+    pub initial_summary: Option<Duration>,
+    pub child_memory_interval: Option<Duration>,
+    pub measurement_window: Option<Duration>,
+    pub exit_on_total_loss: Option<Duration>,
+    /// Consecutive times fping is restarted after exiting unexpectedly
+    pub max_restarts: u32,
+    pub heartbeat_interval: Option<Duration>,
+    pub summary_block_timeout: Option<Duration>,
+    pub align_interval: Option<Duration>,
+    pub warmup_samples: u64,
+    pub trace_sample_rate: u64,
+    pub max_line_length: usize,
+    pub spawn_options: SpawnOptions,
+    pub patterns: PatternOverrides,
+    pub targets_url: Option<String>,
+    pub target_file: Option<PathBuf>,
+    pub self_test: bool,
+    /// Probe the health of a running exporter instead of starting one
+    pub check: bool,
+    pub shuffle_targets: bool,
+    /// Seed for shuffling targets, random if not given
+    pub seed: Option<u64>,
+    /// Targets not read from the target file, which is merged on top of
+    /// these again when it is reloaded
+    pub static_targets: Vec<String>,
     pub targets: Vec<String>,
+    /// Labels of annotated targets in the target file
+    pub target_labels: HashMap<String, TargetLabels>,
+}
+
+impl Args {
+    /// Summaries on SIGQUIT were introduced in fping 4.3.0
+    pub fn supports_summary(&self) -> bool {
+        VersionReq::parse(">=4.3.0")
+            .unwrap()
+            .matches(&self.fping_version)
+    }
+
+    /// --print-ttl was introduced in fping 5.0
+    pub fn supports_print_ttl(&self) -> bool {
+        VersionReq::parse(">=5.0.0")
+            .unwrap()
+            .matches(&self.fping_version)
+    }
 }
 
 fn format_long_version(fping: Option<&semver::Version>) -> String {
@@ -45,14 +255,52 @@ fn format_long_version(fping: Option<&semver::Version>) -> String {
     )
 }
 
+#[cfg(feature = "graphite")]
+fn graphite_args(app: clap::App<'static, 'static>) -> clap::App<'static, 'static> {
+    app.arg(
+        Arg::with_name("graphite")
+            .takes_value(true)
+            .long("graphite")
+            .value_name("host:port")
+            .help("periodically push metrics to this graphite plaintext listener"),
+    )
+    .arg(
+        Arg::with_name("graphite-interval")
+            .takes_value(true)
+            .long("graphite-interval")
+            .default_value("60s")
+            .help("how often metrics are pushed to --graphite"),
+    )
+    .arg(
+        Arg::with_name("graphite-prefix")
+            .takes_value(true)
+            .long("graphite-prefix")
+            .default_value("")
+            .help("path prepended to every graphite metric"),
+    )
+}
+
+#[cfg(not(feature = "graphite"))]
+fn graphite_args(app: clap::App<'static, 'static>) -> clap::App<'static, 'static> {
+    app
+}
+
 fn clap_app() -> clap::App<'static, 'static> {
-    app_from_crate!()
+    graphite_args(app_from_crate!())
+        .after_help(EXIT_CODES_HELP)
         .arg(
             Arg::with_name("path")
                 .takes_value(true)
                 .long("metrics-path")
                 .default_value("metrics"),
         )
+        .arg(
+            Arg::with_name("metrics-namespace")
+                .takes_value(true)
+                .long("metrics-namespace")
+                .default_value("fping")
+                .help("prefix of all metric names, to tell several exporters apart"),
+        )
         .arg(
             Arg::with_name("port")
                 .takes_value(true)
@@ -66,57 +314,1002 @@ fn clap_app() -> clap::App<'static, 'static> {
                 .default_value("::"),
         )
         .arg(
-            Arg::with_name("timeout")
+            Arg::with_name("metrics-socket")
+                .takes_value(true)
+                .long("metrics-socket")
+                .value_name("PATH")
+                .conflicts_with_all(&["port", "bind", "reuse-port", "bind-v6only", "tls-cert-source", "check"])
+                .help("serve metrics on this unix socket instead of a TCP port, a stale socket left by a previous instance is replaced"),
+        )
+        .arg(
+            Arg::with_name("runtime-limit")
                 .takes_value(true)
                 .long("runtime-limit"),
         )
+        .arg(
+            Arg::with_name("http-drain-timeout")
+                .takes_value(true)
+                .long("http-drain-timeout")
+                .default_value("5s")
+                .help("how long scrapes in progress may take to finish when shutting down, no new connections are accepted meanwhile"),
+        )
+        .arg(
+            Arg::with_name("scrape-cooldown")
+                .takes_value(true)
+                .long("scrape-cooldown")
+                .default_value("1s")
+                .help("minimum time between summary requests, scrapes within it reuse the previous summary"),
+        )
+        .arg(
+            Arg::with_name("overload-behavior")
+                .takes_value(true)
+                .long("overload-behavior")
+                .possible_values(&["shed", "cached"])
+                .default_value("shed")
+                .help("how to serve a scrape while another summary request is queued, shed responds with 503"),
+        )
+        .arg(
+            Arg::with_name("reuse-port")
+                .long("reuse-port")
+                .conflicts_with("tls-cert-source")
+                .help("bind with SO_REUSEPORT, allowing a new instance to take over the port before this one exits"),
+        )
+        .arg(
+            Arg::with_name("bind-v6only")
+                .long("bind-v6only")
+                .conflicts_with("tls-cert-source")
+                .help("only accept IPv6 connections when binding an IPv6 address, by default IPv4 connections are accepted as well"),
+        )
+        .arg(
+            Arg::with_name("debug-endpoints")
+                .long("debug-endpoints")
+                .help("enable debugging aids: --inject-loss and POST /summary, which requests a summary from fping immediately and returns the resulting metrics"),
+        )
+        .arg(
+            Arg::with_name("enable-lifecycle")
+                .long("enable-lifecycle")
+                .help("enable POST /-/reload, which reloads --target-file and restarts fping like SIGHUP"),
+        )
+        .arg(
+            Arg::with_name("inject-loss")
+                .takes_value(true)
+                .long("inject-loss")
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("target=ratio")
+                .requires("debug-endpoints")
+                .help("report the given fraction of replies for a target as lost, to test alerting. Affected series are labeled synthetic=\"true\""),
+        )
+        .arg(
+            Arg::with_name("rtt-buckets")
+                .takes_value(true)
+                .long("rtt-buckets")
+                .use_delimiter(true)
+                .value_name("SECONDS,...")
+                .help("upper bounds of the round-trip time and delay variation histogram buckets, from 0.5ms up to 2.5s by default"),
+        )
+        .arg(
+            Arg::with_name("disable-ipdv")
+                .long("disable-ipdv")
+                .help("do not export the instantaneous packet delay variation histogram"),
+        )
+        .arg(
+            Arg::with_name("emit-timestamps")
+                .long("emit-timestamps")
+                .conflicts_with("textfile-output")
+                .help("export per-ping metrics with the time fping measured them, prometheus marks such series stale differently and drops samples older than its ingestion window"),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .takes_value(true)
+                .long("tls-cert")
+                .help("PEM encoded certificate chain, enables TLS for the metrics server"),
+        )
+        .arg(
+            Arg::with_name("tls-cert-env")
+                .takes_value(true)
+                .long("tls-cert-env")
+                .value_name("VAR")
+                .help("environment variable containing the PEM encoded certificate chain, instead of --tls-cert"),
+        )
+        .group(
+            ArgGroup::with_name("tls-cert-source")
+                .args(&["tls-cert", "tls-cert-env"])
+                .requires("tls-key-source"),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .takes_value(true)
+                .long("tls-key")
+                .help("PEM encoded private key for --tls-cert"),
+        )
+        .arg(
+            Arg::with_name("tls-key-env")
+                .takes_value(true)
+                .long("tls-key-env")
+                .value_name("VAR")
+                .help("environment variable containing the PEM encoded private key, instead of --tls-key"),
+        )
+        .group(
+            ArgGroup::with_name("tls-key-source")
+                .args(&["tls-key", "tls-key-env"])
+                .requires("tls-cert-source"),
+        )
+        .arg(
+            Arg::with_name("tls-client-ca")
+                .takes_value(true)
+                .long("tls-client-ca")
+                .requires("tls-cert-source")
+                .help("PEM encoded CA bundle, scrapers must present a certificate signed by it"),
+        )
+        .arg(
+            Arg::with_name("auth-user")
+                .takes_value(true)
+                .long("auth-user")
+                .requires("auth-password")
+                .help("require scrapers to log in with HTTP basic auth as this user, /health stays open"),
+        )
+        .arg(
+            Arg::with_name("auth-password")
+                .takes_value(true)
+                .long("auth-password")
+                .requires("auth-user")
+                .help("password for --auth-user"),
+        )
+        .arg(
+            Arg::with_name("textfile-output")
+                .takes_value(true)
+                .long("textfile-output")
+                .help("periodically write metrics to this file, for node_exporter's textfile collector"),
+        )
+        .arg(
+            Arg::with_name("textfile-interval")
+                .takes_value(true)
+                .long("textfile-interval")
+                .default_value("15s")
+                .help("how often --textfile-output is rewritten"),
+        )
+        .arg(
+            Arg::with_name("initial-summary")
+                .takes_value(true)
+                .long("initial-summary")
+                .help("request a summary this long after fping starts, instead of waiting for the first scrape"),
+        )
+        .arg(
+            Arg::with_name("child-memory-interval")
+                .takes_value(true)
+                .long("child-memory-interval")
+                .help("sample the resident memory and CPU time of fping at this interval, linux only"),
+        )
+        .arg(
+            Arg::with_name("measurement-window")
+                .takes_value(true)
+                .long("measurement-window")
+                .help("forget per target state such as the previous delay used for packet delay variation after this long, exported counters are unaffected so this is independent of rate() windows"),
+        )
+        .arg(
+            Arg::with_name("exit-on-total-loss")
+                .takes_value(true)
+                .long("exit-on-total-loss")
+                .value_name("DURATION")
+                .help("exit with code 5 once no target has responded for this long, so orchestration can reschedule the exporter"),
+        )
+        .arg(
+            Arg::with_name("max-restarts")
+                .takes_value(true)
+                .long("max-restarts")
+                .value_name("N")
+                .default_value("0")
+                .help("restart fping up to N times in a row when it exits unexpectedly, waiting 1s before the first attempt and twice as long after each failed one, up to a minute. Exits as soon as fping does by default"),
+        )
+        .arg(
+            Arg::with_name("heartbeat-interval")
+                .takes_value(true)
+                .long("heartbeat-interval")
+                .value_name("DURATION")
+                .help("log the number of targets up and down at this interval, at info level with the 'heartbeat' target"),
+        )
+        .arg(
+            Arg::with_name("warmup-samples")
+                .takes_value(true)
+                .long("warmup-samples")
+                .value_name("N")
+                .help("keep the first N replies of every target out of the round-trip time and delay variation histograms, they are often slowed down by address resolution. Loss is unaffected"),
+        )
+        .arg(
+            Arg::with_name("align-interval")
+                .takes_value(true)
+                .long("align-interval")
+                .value_name("DURATION")
+                .help("delay starting fping until the wall clock is a multiple of this duration, so exporters started with the same value probe in step. Delays startup, including the metrics server, by up to the duration"),
+        )
+        .arg(
+            Arg::with_name("summary-block-timeout")
+                .takes_value(true)
+                .long("summary-block-timeout")
+                .value_name("DURATION")
+                .help("complete a summary request after this long even if fping has not summarized every target yet, by default scrapes wait indefinitely"),
+        )
+        .arg(
+            Arg::with_name("trace-sample-rate")
+                .takes_value(true)
+                .long("trace-sample-rate")
+                .value_name("N")
+                .default_value("100")
+                .help("only log every Nth per-ping trace message"),
+        )
+        .arg(
+            Arg::with_name("trace-unthrottled")
+                .long("trace-unthrottled")
+                .conflicts_with("trace-sample-rate")
+                .help("log every per-ping trace message, equivalent to --trace-sample-rate 1"),
+        )
+        .arg(
+            Arg::with_name("max-line-length")
+                .takes_value(true)
+                .long("max-line-length")
+                .value_name("BYTES")
+                .help("discard lines from fping longer than this, defaults to 64KiB"),
+        )
+        .arg(
+            Arg::with_name("interval")
+                .takes_value(true)
+                .long("interval")
+                .value_name("DURATION")
+                .help("time between pings to the same target, passed to fping as -p. fping defaults to 1s"),
+        )
+        .arg(
+            Arg::with_name("probe-timeout")
+                .takes_value(true)
+                .long("probe-timeout")
+                .value_name("DURATION")
+                .help("time to wait for a reply before a ping counts as lost, passed to fping as -t in whole milliseconds. Must not exceed --interval"),
+        )
+        .arg(
+            Arg::with_name("count")
+                .takes_value(true)
+                .long("count")
+                .value_name("N")
+                .help("send N pings to every target and exit once fping is done, printing the final metrics to stdout"),
+        )
+        .arg(
+            Arg::with_name("packet-size")
+                .takes_value(true)
+                .long("packet-size")
+                .value_name("BYTES")
+                .help("ICMP data bytes sent with each ping, passed to fping as -b. fping sends 56 by default"),
+        )
+        .arg(
+            Arg::with_name("ttl")
+                .takes_value(true)
+                .long("ttl")
+                .value_name("HOPS")
+                .help("time to live of the pings, passed to fping as -H"),
+        )
+        .arg(
+            Arg::with_name("reply-ttl")
+                .long("reply-ttl")
+                .help("export the TTL of every reply as icmp_reply_ttl to notice path changes, needs fping 5.0 or later"),
+        )
+        .arg(
+            Arg::with_name("nice")
+                .takes_value(true)
+                .long("nice")
+                .allow_hyphen_values(true)
+                .help("run fping with this niceness, lowering its priority can skew latency measurements when the host is under contention"),
+        )
+        .arg(
+            Arg::with_name("ping-regex")
+                .takes_value(true)
+                .long("ping-regex")
+                .value_name("REGEX")
+                .help("replaces the pattern for ping results, requires the named groups ts, id, addr, seq and rtt"),
+        )
+        .arg(
+            Arg::with_name("summary-regex")
+                .takes_value(true)
+                .long("summary-regex")
+                .value_name("REGEX")
+                .help("replaces the pattern for summary lines, requires the named groups target, addr, xmt and rcv"),
+        )
+        .arg(
+            Arg::with_name("all-addresses")
+                .long("all-addresses")
+                .help("ping every address a target resolves to instead of only the first"),
+        )
+        .arg(
+            Arg::with_name("ipv4")
+                .long("ipv4")
+                .conflicts_with("ipv6")
+                .help("only ping IPv4 addresses, targets that do not resolve to one are skipped"),
+        )
+        .arg(
+            Arg::with_name("ipv6")
+                .long("ipv6")
+                .help("only ping IPv6 addresses, targets that do not resolve to one are skipped"),
+        )
+        .arg(
+            Arg::with_name("source-address")
+                .takes_value(true)
+                .long("source-address")
+                .value_name("ADDR")
+                .help("send pings from this local address, for hosts with several interfaces"),
+        )
+        .arg(
+            Arg::with_name("target-file")
+                .takes_value(true)
+                .long("target-file")
+                .value_name("FILE")
+                .help("read additional newline delimited targets from this file, blank lines and # comments are ignored. Targets may be followed by name=value labels, exported through target_info"),
+        )
+        .arg(
+            Arg::with_name("generate")
+                .takes_value(true)
+                .long("generate")
+                .min_values(1)
+                .max_values(2)
+                .value_name("NETWORK | START END")
+                .conflicts_with_all(&["TARGET", "target-file", "targets-url", "shuffle-targets", "self-test"])
+                .help("let fping ping every host of a network like 192.0.2.0/24, or every address from START to END, instead of a list of targets"),
+        )
+        .arg(
+            Arg::with_name("targets-url")
+                .takes_value(true)
+                .long("targets-url")
+                .value_name("URL")
+                .help("fetch additional newline delimited targets from this url at startup and on reload"),
+        )
+        .arg(
+            Arg::with_name("shuffle-targets")
+                .long("shuffle-targets")
+                .help("pass targets to fping in random order, so the same targets are not always probed first"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .takes_value(true)
+                .long("seed")
+                .requires("shuffle-targets")
+                .help("seed for --shuffle-targets, the same seed and targets always give the same order"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .takes_value(true)
+                .long("log-format")
+                .env("LOG_FORMAT")
+                .possible_values(&["pretty", "json"])
+                .default_value("pretty")
+                .help("format of the log on stderr, json writes one object with timestamp, level, target and message per line. RUST_LOG filters either"),
+        )
+        .arg(
+            Arg::with_name("fping-bin")
+                .takes_value(true)
+                .long("fping-bin")
+                .value_name("PATH")
+                .env("FPING_BIN")
+                .default_value("fping")
+                .help("fping binary to run. Taken from this flag, then the FPING_BIN environment variable, then fping on the PATH"),
+        )
+        .arg(
+            Arg::with_name("assume-fping-version")
+                .takes_value(true)
+                .long("assume-fping-version")
+                .value_name("X.Y.Z")
+                .help("skip running fping --version and gate features on this version instead, for fping builds with nonstandard version output"),
+        )
+        .arg(
+            Arg::with_name("self-test")
+                .long("self-test")
+                .help("check that fping works and its output can be parsed, then exit. Pings 127.0.0.1 unless a target is given"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .takes_value(true)
+                .long("config")
+                .value_name("FILE")
+                .help("read options from this TOML file, named after their flags like metrics-port = 9775, along with a list of targets. Flags and environment variables take precedence"),
+        )
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                // no client certificate is presented
+                .conflicts_with("tls-client-ca")
+                .help("ask the exporter listening on the metrics address and port whether fping is running, exiting 0 if it is. For container health checks without curl. Not available with --tls-client-ca"),
+        )
         .arg(
             Arg::with_name("TARGET")
-                .required(true)
+                .required_unless_one(&["target-file", "targets-url", "generate", "self-test", "check", "config"])
                 .multiple(true)
                 .help("hostname or ip address to ping"),
         )
 }
 
+/// fping takes -t in whole milliseconds, rounded up so the timeout is never
+/// shorter than requested.
+fn whole_millis(duration: Duration) -> Duration {
+    let millis = duration.as_nanos().div_ceil(1_000_000);
+    Duration::from_millis(millis.max(1) as u64)
+}
+
+fn duration_of(args: &clap::ArgMatches, name: &'static str) -> Result<Option<Duration>, ArgsError> {
+    args.value_of(name)
+        .map(humantime::parse_duration)
+        .transpose()
+        .map_err(|e| ArgsError::NotAValidDuration(name, e))
+}
+
+/// Like `duration_of`, for periods which are handed to `tokio::time::interval`
+fn interval_of(args: &clap::ArgMatches, name: &'static str) -> Result<Option<Duration>, ArgsError> {
+    match duration_of(args, name)? {
+        Some(interval) if interval.is_zero() => Err(ArgsError::ZeroInterval(name)),
+        interval => Ok(interval),
+    }
+}
+
+fn injected_loss(injection: &str) -> Result<(String, f64), ArgsError> {
+    let invalid = || ArgsError::InvalidInjectedLoss(injection.to_owned());
+    let (target, ratio) = injection.split_once('=').ok_or_else(invalid)?;
+    match ratio.parse::<f64>() {
+        Ok(ratio) if !target.is_empty() && (0.0..=1.0).contains(&ratio) => {
+            Ok((target.to_owned(), ratio))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// The prometheus crate panics on invalid metric names, so this is checked
+/// up front.
+fn namespace(value: &str) -> Result<String, ArgsError> {
+    let mut chars = value.chars();
+    let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':');
+    if valid {
+        Ok(value.to_owned())
+    } else {
+        Err(ArgsError::InvalidNamespace(value.to_owned()))
+    }
+}
+
+fn source_address(value: &str) -> Result<IpAddr, ArgsError> {
+    value
+        .parse()
+        .map_err(|e| ArgsError::InvalidSourceAddress(value.to_owned(), e))
+}
+
+fn rtt_buckets<'a>(bounds: impl Iterator<Item = &'a str>) -> Result<Vec<f64>, ArgsError> {
+    let mut buckets = bounds
+        .map(|bound| match bound.trim().parse::<f64>() {
+            Ok(seconds) if seconds.is_finite() && seconds >= 0.0 => Ok(seconds),
+            _ => Err(ArgsError::InvalidRttBucket(bound.to_owned())),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // prometheus rejects buckets that are not strictly increasing
+    buckets.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    buckets.dedup();
+    Ok(buckets)
+}
+
+fn generate(values: &[&str]) -> Result<Generate, ArgsError> {
+    let invalid = || ArgsError::InvalidGenerate(values.join(" "));
+    let addr = |addr: &str| addr.parse::<IpAddr>().map_err(|_| invalid());
+    match values {
+        [network] => {
+            let (network, prefix) = network.split_once('/').ok_or_else(invalid)?;
+            let network = addr(network)?;
+            let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+            match prefix.parse() {
+                Ok(prefix) if (1..=max_prefix).contains(&prefix) => {
+                    Ok(Generate::Network(network, prefix))
+                }
+                _ => Err(invalid()),
+            }
+        }
+        [start, end] => {
+            let (start, end) = (addr(start)?, addr(end)?);
+            if start.is_ipv4() != end.is_ipv4() || start > end {
+                return Err(invalid());
+            }
+            Ok(Generate::Range(start, end))
+        }
+        _ => Err(invalid()),
+    }
+}
+
 fn convert_to_args(
     args: clap::ArgMatches,
     fping_version: semver::Version,
 ) -> Result<Args, ArgsError> {
-    //FIXME: target specification through files?
     let targets = args
         .values_of("TARGET")
         .map_or_else(Vec::new, |iter| iter.map(|s| s.to_owned()).collect());
 
-    let runtime_limit = args
-        .value_of("timeout")
-        .map(humantime::parse_duration)
+    let runtime_limit = duration_of(&args, "runtime-limit")?;
+    let address_family = if args.is_present("ipv4") {
+        Some(AddressFamily::Ipv4)
+    } else if args.is_present("ipv6") {
+        Some(AddressFamily::Ipv6)
+    } else {
+        None
+    };
+    let source_address = args
+        .value_of("source-address")
+        .map(source_address)
         .transpose()?;
+    let period = match duration_of(&args, "interval")? {
+        Some(interval) if interval < Duration::from_millis(1) => {
+            return Err(ArgsError::IntervalTooShort(interval))
+        }
+        interval => interval,
+    };
+    let probe_timeout = duration_of(&args, "probe-timeout")?.map(whole_millis);
+    if let Some(timeout) = probe_timeout {
+        // fping warns about a timeout longer than the period and misbehaves
+        let period = period.unwrap_or(DEFAULT_PERIOD);
+        if timeout > period {
+            return Err(ArgsError::ProbeTimeoutTooLong(timeout, period));
+        }
+    }
+    // fping only reports a failed bind once it starts pinging
+    match (source_address, address_family) {
+        (Some(source @ IpAddr::V6(_)), Some(AddressFamily::Ipv4)) => {
+            return Err(ArgsError::SourceFamilyMismatch(source, "ipv4"))
+        }
+        (Some(source @ IpAddr::V4(_)), Some(AddressFamily::Ipv6)) => {
+            return Err(ArgsError::SourceFamilyMismatch(source, "ipv6"))
+        }
+        _ => {}
+    }
+
+    let textfile = match args.value_of_os("textfile-output") {
+        Some(path) => Some(TextfileArgs {
+            path: path.into(),
+            interval: interval_of(&args, "textfile-interval")?.unwrap(),
+        }),
+        None => None,
+    };
+
+    #[cfg(feature = "graphite")]
+    let graphite = match args.value_of("graphite") {
+        Some(addr) => Some(GraphiteArgs {
+            addr: addr.to_owned(),
+            interval: interval_of(&args, "graphite-interval")?.unwrap(),
+            prefix: args.value_of("graphite-prefix").unwrap().to_owned(),
+        }),
+        None => None,
+    };
 
     Ok(Args {
+        fping_bin: args.value_of("fping-bin").unwrap().to_owned(),
         fping_version,
         metrics: MetricArgs {
             addr: SocketAddr::new(
                 args.value_of("bind").unwrap().parse()?,
                 args.value_of("port").unwrap().parse()?,
             ),
-            path: args.value_of("path").unwrap().to_owned(),
+            socket: args.value_of_os("metrics-socket").map(PathBuf::from),
+            // served and logged as /<path>
+            path: args.value_of("path").unwrap().trim_matches('/').to_owned(),
+            namespace: namespace(args.value_of("metrics-namespace").unwrap())?,
             runtime_limit,
+            scrape_cooldown: duration_of(&args, "scrape-cooldown")?.unwrap(),
+            drain_timeout: duration_of(&args, "http-drain-timeout")?.unwrap(),
+            overload: match args.value_of("overload-behavior") {
+                Some("cached") => OverloadBehavior::Cached,
+                _ => OverloadBehavior::Shed,
+            },
+            reuse_port: args.is_present("reuse-port"),
+            v6only: args.is_present("bind-v6only"),
+            debug_endpoints: args.is_present("debug-endpoints"),
+            lifecycle: args.is_present("enable-lifecycle"),
+            emit_timestamps: args.is_present("emit-timestamps"),
+            disable_ipdv: args.is_present("disable-ipdv"),
+            rtt_buckets: match args.values_of("rtt-buckets") {
+                Some(bounds) => rtt_buckets(bounds)?,
+                None => DEFAULT_RTT_BUCKETS.to_vec(),
+            },
+            inject_loss: args
+                .values_of("inject-loss")
+                .into_iter()
+                .flatten()
+                .map(injected_loss)
+                .collect::<Result<_, _>>()?,
+            tls: PemSource::from_args(&args, "tls-cert", "tls-cert-env").map(|cert| TlsArgs {
+                cert,
+                key: PemSource::from_args(&args, "tls-key", "tls-key-env").unwrap(),
+                client_ca: args.value_of_os("tls-client-ca").map(PathBuf::from),
+            }),
+            auth: args.value_of("auth-user").map(|user| BasicAuth {
+                user: user.to_owned(),
+                password: args.value_of("auth-password").unwrap().to_owned(),
+            }),
+        },
+        textfile,
+        #[cfg(feature = "graphite")]
+        graphite,
+        initial_summary: duration_of(&args, "initial-summary")?,
+        child_memory_interval: interval_of(&args, "child-memory-interval")?,
+        measurement_window: duration_of(&args, "measurement-window")?,
+        exit_on_total_loss: duration_of(&args, "exit-on-total-loss")?,
+        max_restarts: args
+            .value_of("max-restarts")
+            .unwrap()
+            .parse()
+            .map_err(|e| ArgsError::NotANumber("max-restarts", e))?,
+        heartbeat_interval: interval_of(&args, "heartbeat-interval")?,
+        summary_block_timeout: duration_of(&args, "summary-block-timeout")?,
+        align_interval: duration_of(&args, "align-interval")?,
+        warmup_samples: args
+            .value_of("warmup-samples")
+            .map(str::parse)
+            .transpose()
+            .map_err(|e| ArgsError::NotANumber("warmup-samples", e))?
+            .unwrap_or(0),
+        trace_sample_rate: if args.is_present("trace-unthrottled") {
+            1
+        } else {
+            args.value_of("trace-sample-rate")
+                .unwrap()
+                .parse()
+                .map_err(|e| ArgsError::NotANumber("trace-sample-rate", e))?
+        },
+        max_line_length: match args.value_of("max-line-length") {
+            Some(max) => match max
+                .parse()
+                .map_err(|e| ArgsError::NotANumber("max-line-length", e))?
+            {
+                0 => return Err(ArgsError::ZeroMaxLineLength),
+                max => max,
+            },
+            None => DEFAULT_MAX_LINE_LENGTH,
+        },
+        spawn_options: SpawnOptions {
+            all_addresses: args.is_present("all-addresses"),
+            generate: match args.values_of("generate") {
+                Some(values) => Some(generate(&values.collect::<Vec<_>>())?),
+                None => None,
+            },
+            address_family,
+            source_address,
+            period,
+            probe_timeout,
+            count: args
+                .value_of("count")
+                .map(|count| count.parse::<NonZeroU32>().map(NonZeroU32::get))
+                .transpose()
+                .map_err(|e| ArgsError::NotANumber("count", e))?,
+            packet_size: args
+                .value_of("packet-size")
+                .map(|size| match size.parse::<u32>() {
+                    Ok(size) => u16::try_from(size)
+                        .ok()
+                        .filter(|size| PACKET_SIZES.contains(size))
+                        .ok_or(ArgsError::PacketSizeOutOfRange(size)),
+                    Err(e) => Err(ArgsError::NotANumber("packet-size", e)),
+                })
+                .transpose()?,
+            ttl: args
+                .value_of("ttl")
+                .map(|ttl| match ttl.parse::<u32>() {
+                    Ok(ttl) => u8::try_from(ttl)
+                        .ok()
+                        .filter(|&ttl| ttl > 0)
+                        .ok_or(ArgsError::TtlOutOfRange(ttl)),
+                    Err(e) => Err(ArgsError::NotANumber("ttl", e)),
+                })
+                .transpose()?,
+            print_ttl: args.is_present("reply-ttl"),
+            nice: args
+                .value_of("nice")
+                .map(|nice| match nice.parse() {
+                    Ok(nice) if (-20..=19).contains(&nice) => Ok(nice),
+                    Ok(nice) => Err(ArgsError::NiceOutOfRange(nice)),
+                    Err(e) => Err(ArgsError::NotANumber("nice", e)),
+                })
+                .transpose()?,
         },
+        patterns: PatternOverrides {
+            ping: args
+                .value_of("ping-regex")
+                .map(|re| PatternOverrides::compile("ping-regex", re, &PING_GROUPS))
+                .transpose()?,
+            summary: args
+                .value_of("summary-regex")
+                .map(|re| PatternOverrides::compile("summary-regex", re, &SUMMARY_GROUPS))
+                .transpose()?,
+        },
+        targets_url: args.value_of("targets-url").map(str::to_owned),
+        target_file: args.value_of_os("target-file").map(PathBuf::from),
+        self_test: args.is_present("self-test"),
+        check: args.is_present("check"),
+        shuffle_targets: args.is_present("shuffle-targets"),
+        seed: args
+            .value_of("seed")
+            .map(str::parse)
+            .transpose()
+            .map_err(|e| ArgsError::NotANumber("seed", e))?,
+        static_targets: targets.clone(),
         targets,
+        target_labels: HashMap::new(),
     })
 }
 
-pub async fn load_args(
-    launcher: &Launcher<'_>,
-    discover_timeout: Duration,
-) -> Result<Args, ArgsError> {
-    let version = launcher.version(discover_timeout).await;
-    convert_to_args(
-        clap_app()
-            .long_version(format_long_version(version.as_ref().ok()).as_str())
-            .get_matches(),
-        version?,
-    )
+/// Picks the value of `--<long>` out of `argv` ahead of parsing, for
+/// options needed to discover the fping version before clap handles
+/// `--version`.
+fn prescan<'a>(argv: &'a [OsString], long: &str) -> Option<&'a str> {
+    let flag = format!("--{}", long);
+    let mut args = argv
+        .iter()
+        .skip(1)
+        .map(|arg| arg.to_str().unwrap_or_default())
+        .take_while(|&arg| arg != "--");
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next();
+        }
+        if let Some(value) = arg.strip_prefix(&flag).and_then(|v| v.strip_prefix('=')) {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Whether `--<long>` is given at all, see `prescan`
+fn prescan_flag(argv: &[OsString], long: &str) -> bool {
+    let flag = format!("--{}", long);
+    let with_value = format!("--{}=", long);
+    argv.iter()
+        .skip(1)
+        .map(|arg| arg.to_str().unwrap_or_default())
+        .take_while(|&arg| arg != "--")
+        .any(|arg| arg == flag || arg.starts_with(&with_value))
+}
+
+/// `--log-format` or `LOG_FORMAT`, picked out ahead of parsing since the
+/// logger has to be installed before anything is logged
+fn prescan_log_format(argv: &[OsString], env: Option<&str>) -> LogFormat {
+    match prescan(argv, "log-format").or(env) {
+        Some("json") => LogFormat::Json,
+        _ => LogFormat::Pretty,
+    }
+}
+
+pub fn log_format() -> LogFormat {
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    prescan_log_format(&argv, std::env::var("LOG_FORMAT").ok().as_deref())
+}
+
+/// Options read from `--config`, named after their flags
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct FileConfig {
+    fping_bin: Option<String>,
+    metrics_bind: Option<IpAddr>,
+    metrics_port: Option<u16>,
+    metrics_path: Option<String>,
+    metrics_namespace: Option<String>,
+    interval: Option<String>,
+    probe_timeout: Option<String>,
+    packet_size: Option<u16>,
+    rtt_buckets: Option<Vec<f64>>,
+    target_file: Option<PathBuf>,
+    /// Pinged unless targets are given on the command line
+    #[serde(default, deserialize_with = "config_targets")]
+    targets: Option<Vec<String>>,
+}
+
+/// Targets are passed as arguments, fping would treat these as flags
+fn config_targets<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<String>>, D::Error> {
+    let targets = Vec::<String>::deserialize(d)?;
+    match targets
+        .iter()
+        .find(|t| t.starts_with('-') || t.contains(char::is_whitespace))
+    {
+        Some(invalid) => Err(serde::de::Error::custom(TargetsError::InvalidTarget(
+            invalid.clone(),
+        ))),
+        None => Ok(Some(targets)),
+    }
+}
+
+impl FileConfig {
+    async fn read(path: &Path) -> Result<Self, ArgsError> {
+        let config = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| ArgsError::ConfigUnreadable(path.to_owned(), e))?;
+        toml::from_str(&config).map_err(|e| ArgsError::ConfigParse(path.to_owned(), e))
+    }
+
+    /// Adds the options `argv` does not set to it, FPING_BIN still takes
+    /// precedence over `fping-bin` as it would over its default. Options
+    /// which conflict with a flag `argv` does set are left out as well, the
+    /// command line replaces them rather than clashing with them.
+    fn fill_in(&self, argv: &mut Vec<OsString>) {
+        let options: [(&str, Option<String>, &[&str]); 10] = [
+            (
+                "fping-bin",
+                self.fping_bin
+                    .clone()
+                    .filter(|_| std::env::var_os("FPING_BIN").is_none()),
+                &[],
+            ),
+            (
+                "metrics-bind",
+                self.metrics_bind.map(|ip| ip.to_string()),
+                &["metrics-socket"],
+            ),
+            (
+                "metrics-port",
+                self.metrics_port.map(|port| port.to_string()),
+                &["metrics-socket"],
+            ),
+            ("metrics-path", self.metrics_path.clone(), &[]),
+            ("metrics-namespace", self.metrics_namespace.clone(), &[]),
+            ("interval", self.interval.clone(), &[]),
+            ("probe-timeout", self.probe_timeout.clone(), &[]),
+            (
+                "packet-size",
+                self.packet_size.map(|size| size.to_string()),
+                &[],
+            ),
+            (
+                "rtt-buckets",
+                self.rtt_buckets.as_ref().map(|bounds| {
+                    let bounds: Vec<String> = bounds.iter().map(f64::to_string).collect();
+                    bounds.join(",")
+                }),
+                &[],
+            ),
+            (
+                "target-file",
+                self.target_file
+                    .as_ref()
+                    .map(|path| path.to_string_lossy().into_owned()),
+                &["generate"],
+            ),
+        ];
+        let mut filled = vec![];
+        for (long, value, conflicts) in options.iter() {
+            if let Some(value) = value {
+                let overridden = std::iter::once(long)
+                    .chain(conflicts.iter())
+                    .any(|flag| prescan_flag(argv, flag));
+                if !overridden {
+                    filled.push(OsString::from(format!("--{}", long)));
+                    filled.push(OsString::from(value));
+                }
+            }
+        }
+        // ahead of the targets and a possible `--`
+        argv.splice(1..1, filled);
+    }
+}
+
+/// Same precedence as the `fping-bin` argument, which resolves it again
+/// for `Args::fping_bin`.
+fn fping_binary(argv: &[OsString]) -> String {
+    prescan(argv, "fping-bin")
+        .map(str::to_owned)
+        .or_else(|| std::env::var("FPING_BIN").ok())
+        .unwrap_or_else(|| "fping".to_owned())
+}
+
+pub async fn read_target_file(path: &Path) -> Result<Vec<(String, TargetLabels)>, ArgsError> {
+    let list = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| ArgsError::TargetFileUnreadable(path.to_owned(), e))?;
+    targets::parse_annotated_target_list(&list)
+        .map_err(|e| ArgsError::InvalidTargetFile(path.to_owned(), e))
+}
+
+/// Splits the annotations off a target file, keeping those with labels
+pub fn split_target_labels(
+    listed: Vec<(String, TargetLabels)>,
+) -> (Vec<String>, HashMap<String, TargetLabels>) {
+    let mut labels = HashMap::new();
+    let targets = listed
+        .into_iter()
+        .map(|(target, annotations)| {
+            if !annotations.is_empty() {
+                // the first annotated entry wins, like merge_targets keeps the first
+                labels.entry(target.clone()).or_insert(annotations);
+            }
+            target
+        })
+        .collect();
+    (targets, labels)
+}
+
+/// Parses `argv` once the fping version is known, `version` discovers it
+/// for the fping binary first so `--version` can include it. It is never
+/// called if the version is given through `--assume-fping-version`.
+async fn load_args_from<I, T, V>(
+    argv: I,
+    version: impl FnOnce(String) -> V,
+) -> Result<Args, ArgsError>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+    V: Future<Output = Result<semver::Version, VersionError>>,
+{
+    let mut argv: Vec<OsString> = argv.into_iter().map(Into::into).collect();
+    let config = match prescan(&argv, "config") {
+        Some(path) => FileConfig::read(Path::new(path)).await?,
+        None => FileConfig::default(),
+    };
+    config.fill_in(&mut argv);
+
+    let version = match prescan(&argv, "assume-fping-version") {
+        Some(assumed) => {
+            let version = semver::Version::parse(assumed)
+                .map_err(|e| ArgsError::InvalidAssumedVersion(assumed.to_owned(), e))?;
+            info!(
+                "fping version discovery bypassed, assuming fping {}",
+                version
+            );
+            Ok(version)
+        }
+        // fping is not started, the exporter being checked has its own
+        None if prescan_flag(&argv, "check") => Ok(semver::Version::new(0, 0, 0)),
+        None => version(fping_binary(&argv)).await,
+    };
+    let matches = clap_app()
+        .long_version(format_long_version(version.as_ref().ok()).as_str())
+        .get_matches_from_safe(argv)
+        .map_err(|e| {
+            if e.use_stderr() {
+                e
+            } else {
+                // --help and --version are not failures
+                e.exit()
+            }
+        })?;
+    let mut args = convert_to_args(matches, version?)?;
+    if args.check {
+        return Ok(args);
+    }
+    if let Some(targets) = config.targets {
+        if args.targets.is_empty() && args.spawn_options.generate.is_none() {
+            args.static_targets = targets.clone();
+            args.targets = targets;
+        }
+    }
+
+    if let Some(path) = &args.target_file {
+        let (listed, labels) = split_target_labels(read_target_file(path).await?);
+        targets::merge_targets(&mut args.targets, listed);
+        args.target_labels = labels;
+    }
+    // targets-url is checked once it has been fetched
+    if args.targets.is_empty()
+        && args.targets_url.is_none()
+        && args.spawn_options.generate.is_none()
+        && !args.self_test
+    {
+        return Err(ArgsError::NoTargets);
+    }
+    Ok(args)
+}
+
+pub async fn load_args(discover_timeout: Duration) -> Result<Args, ArgsError> {
+    load_args_from(std::env::args_os(), |binary| async move {
+        for_program(&binary).version(discover_timeout).await
+    })
+    .await
+}
+
+/// Arguments as parsed from `cmd`, for tests outside of this module
+#[cfg(test)]
+pub fn test_args(cmd: &[&str]) -> Args {
+    let matches = clap_app()
+        .get_matches_from_safe(std::iter::once(&"program_path").chain(cmd))
+        .unwrap();
+    convert_to_args(matches, semver::Version::new(5, 0, 0)).unwrap()
+}
+
+/// Metric arguments as parsed from `cmd`, for the server tests
+#[cfg(test)]
+pub fn metric_args(cmd: &[&str]) -> MetricArgs {
+    test_args(&[&["dns.google"], cmd].concat()).metrics
 }
 
 #[cfg(test)]
@@ -129,8 +1322,837 @@ mod tests {
         convert_to_args(matches, semver::Version::new(1, 0, 0))
     }
 
+    async fn load_with_version(
+        args: Vec<&str>,
+        version: Result<semver::Version, VersionError>,
+    ) -> Result<Args, ArgsError> {
+        load_args_from(std::iter::once("program_path").chain(args), |_| {
+            std::future::ready(version)
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn discovered_version_gates_summary() {
+        let old = load_with_version(vec!["dns.google"], Ok(semver::Version::new(4, 2, 0)))
+            .await
+            .unwrap();
+        assert!(!old.supports_summary());
+
+        let new = load_with_version(vec!["dns.google"], Ok(semver::Version::new(5, 0, 0)))
+            .await
+            .unwrap();
+        assert!(new.supports_summary());
+    }
+
+    #[tokio::test]
+    async fn discovery_failure_is_reported() {
+        let res = load_with_version(vec!["dns.google"], Err(VersionError::BinaryNotFound)).await;
+        assert!(matches!(
+            res,
+            Err(ArgsError::FpingProblem(VersionError::BinaryNotFound))
+        ));
+
+        // invalid usage takes precedence over discovery failures
+        let res = load_with_version(vec![], Err(VersionError::BinaryNotFound)).await;
+        assert!(matches!(res, Err(ArgsError::InvalidUsage(_))));
+    }
+
     #[test]
     fn basic_usage() {
         parse_cmd(vec!["dns.google"]).unwrap();
     }
+
+    #[test]
+    fn targets_from_url_only() {
+        let args = parse_cmd(vec!["--targets-url", "https://config.example/targets"]).unwrap();
+        assert!(args.targets.is_empty());
+        assert!(parse_cmd(vec![]).is_err());
+    }
+
+    #[test]
+    fn tls_requires_both_halves() {
+        assert!(parse_cmd(vec!["--tls-cert", "cert.pem", "dns.google"]).is_err());
+        assert!(parse_cmd(vec!["--tls-client-ca", "ca.pem", "dns.google"]).is_err());
+
+        let args = parse_cmd(vec![
+            "--tls-cert",
+            "cert.pem",
+            "--tls-key",
+            "key.pem",
+            "--tls-client-ca",
+            "ca.pem",
+            "dns.google",
+        ])
+        .unwrap();
+        let tls = args.metrics.tls.unwrap();
+        assert_eq!(tls.client_ca, Some(PathBuf::from("ca.pem")));
+    }
+
+    #[test]
+    fn metrics_socket() {
+        let args = parse_cmd(vec![
+            "--metrics-socket",
+            "/run/fping_exporter.sock",
+            "localhost",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.metrics.socket,
+            Some(PathBuf::from("/run/fping_exporter.sock"))
+        );
+        assert_eq!(parse_cmd(vec!["localhost"]).unwrap().metrics.socket, None);
+
+        for conflicting in &[["--metrics-port", "9000"], ["--metrics-bind", "127.0.0.1"]] {
+            let mut argv = vec!["--metrics-socket", "/run/fping_exporter.sock", "localhost"];
+            argv.extend_from_slice(conflicting);
+            assert!(parse_cmd(argv).is_err(), "{:?}", conflicting);
+        }
+    }
+
+    #[test]
+    fn metrics_namespace() {
+        let namespace = |value: &str| {
+            parse_cmd(vec!["--metrics-namespace", value, "dns.google"])
+                .map(|args| args.metrics.namespace)
+        };
+        assert_eq!(
+            parse_cmd(vec!["dns.google"]).unwrap().metrics.namespace,
+            "fping"
+        );
+        assert_eq!(namespace("site_a:fping").unwrap(), "site_a:fping");
+        assert_eq!(namespace("_fping2").unwrap(), "_fping2");
+        for invalid in &["", "2fping", "site-a", "fping.lab"] {
+            assert!(
+                matches!(namespace(invalid), Err(ArgsError::InvalidNamespace(_))),
+                "{:?}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn basic_auth_requires_both_halves() {
+        assert!(parse_cmd(vec!["--auth-user", "prometheus", "dns.google"]).is_err());
+        assert!(parse_cmd(vec!["--auth-password", "secret", "dns.google"]).is_err());
+        let args = parse_cmd(vec![
+            "--auth-user",
+            "prometheus",
+            "--auth-password",
+            "secret",
+            "dns.google",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.metrics.auth,
+            Some(BasicAuth {
+                user: "prometheus".into(),
+                password: "secret".into()
+            })
+        );
+        assert_eq!(parse_cmd(vec!["dns.google"]).unwrap().metrics.auth, None);
+    }
+
+    #[test]
+    fn tls_from_env() {
+        let args = parse_cmd(vec![
+            "--tls-cert-env",
+            "METRICS_CERT",
+            "--tls-key",
+            "key.pem",
+            "dns.google",
+        ])
+        .unwrap();
+        let tls = args.metrics.tls.unwrap();
+        assert_eq!(tls.cert, PemSource::Env("METRICS_CERT".into()));
+        assert_eq!(tls.key, PemSource::File("key.pem".into()));
+
+        assert!(parse_cmd(vec!["--tls-key-env", "METRICS_KEY", "dns.google"]).is_err());
+        // a file and a variable for the same half is ambiguous
+        assert!(parse_cmd(vec![
+            "--tls-cert",
+            "cert.pem",
+            "--tls-cert-env",
+            "METRICS_CERT",
+            "--tls-key",
+            "key.pem",
+            "dns.google",
+        ])
+        .is_err());
+        assert!(parse_cmd(vec![
+            "--reuse-port",
+            "--tls-cert-env",
+            "METRICS_CERT",
+            "--tls-key-env",
+            "METRICS_KEY",
+            "dns.google",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn trace_sampling() {
+        assert_eq!(
+            parse_cmd(vec!["dns.google"]).unwrap().trace_sample_rate,
+            100
+        );
+        assert_eq!(
+            parse_cmd(vec!["--trace-unthrottled", "dns.google"])
+                .unwrap()
+                .trace_sample_rate,
+            1
+        );
+        assert!(parse_cmd(vec!["--trace-sample-rate", "ten", "dns.google"]).is_err());
+    }
+
+    #[test]
+    fn nice_range() {
+        let args = parse_cmd(vec!["--nice", "-5", "dns.google"]).unwrap();
+        assert_eq!(args.spawn_options.nice, Some(-5));
+        assert!(matches!(
+            parse_cmd(vec!["--nice", "20", "dns.google"]),
+            Err(ArgsError::NiceOutOfRange(20))
+        ));
+    }
+
+    #[test]
+    fn inject_loss() {
+        assert!(parse_cmd(vec!["--inject-loss", "dns.google=0.5", "dns.google"]).is_err());
+
+        let args = parse_cmd(vec![
+            "--debug-endpoints",
+            "--inject-loss",
+            "dns.google=0.5",
+            "--inject-loss",
+            "localhost=1",
+            "dns.google",
+            "localhost",
+        ])
+        .unwrap();
+        assert_eq!(args.metrics.inject_loss.get("dns.google"), Some(&0.5));
+        assert_eq!(args.metrics.inject_loss.get("localhost"), Some(&1.0));
+        assert_eq!(args.targets, vec!["dns.google", "localhost"]);
+
+        assert!(matches!(
+            parse_cmd(vec![
+                "--debug-endpoints",
+                "--inject-loss",
+                "dns.google=2",
+                "dns.google"
+            ]),
+            Err(ArgsError::InvalidInjectedLoss(_))
+        ));
+    }
+
+    #[test]
+    fn histogram_buckets() {
+        assert_eq!(
+            parse_cmd(vec!["dns.google"]).unwrap().metrics.rtt_buckets,
+            DEFAULT_RTT_BUCKETS
+        );
+        assert_eq!(
+            parse_cmd(vec!["--rtt-buckets", "0.1,0.01,0.5,0.1", "dns.google"])
+                .unwrap()
+                .metrics
+                .rtt_buckets,
+            vec![0.01, 0.1, 0.5]
+        );
+        for invalid in &["-0.1", "inf", "NaN", "fast", ""] {
+            assert!(matches!(
+                parse_cmd(vec![&format!("--rtt-buckets={}", invalid), "dns.google"]),
+                Err(ArgsError::InvalidRttBucket(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn assumed_version_skips_discovery() {
+        let args = load_args_from(
+            vec![
+                "program_path",
+                "--assume-fping-version",
+                "4.2.0",
+                "dns.google",
+            ],
+            |_| async { panic!("version discovery should be skipped") },
+        )
+        .await
+        .unwrap();
+        assert_eq!(args.fping_version, semver::Version::new(4, 2, 0));
+        assert!(!args.supports_summary());
+
+        assert!(matches!(
+            load_with_version(
+                vec!["--assume-fping-version=5", "dns.google"],
+                Ok(semver::Version::new(5, 0, 0))
+            )
+            .await,
+            Err(ArgsError::InvalidAssumedVersion(..))
+        ));
+    }
+
+    #[test]
+    fn fping_binary_flag() {
+        let argv = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(
+            fping_binary(&argv(&[
+                "program_path",
+                "--fping-bin",
+                "/opt/fping",
+                "dns.google"
+            ])),
+            "/opt/fping"
+        );
+        assert_eq!(
+            fping_binary(&argv(&[
+                "program_path",
+                "--fping-bin=/opt/fping",
+                "dns.google"
+            ])),
+            "/opt/fping"
+        );
+        // similar flags and targets after -- are not mistaken for it
+        assert_eq!(
+            prescan(
+                &argv(&["program_path", "--fping-binary=x", "--", "--fping-bin=y"]),
+                "fping-bin"
+            ),
+            None
+        );
+        assert_eq!(
+            parse_cmd(vec!["--fping-bin", "/opt/fping", "dns.google"])
+                .unwrap()
+                .fping_bin,
+            "/opt/fping"
+        );
+    }
+
+    #[test]
+    fn source_address_matches_family() {
+        let source = |args| parse_cmd(args).map(|args| args.spawn_options.source_address);
+        assert_eq!(source(vec!["dns.google"]).unwrap(), None);
+        assert_eq!(
+            source(vec![
+                "--source-address",
+                "192.0.2.1",
+                "--ipv4",
+                "dns.google"
+            ])
+            .unwrap(),
+            Some("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(
+            source(vec!["--source-address", "2001:db8::1", "dns.google"]).unwrap(),
+            Some("2001:db8::1".parse().unwrap())
+        );
+        assert!(matches!(
+            source(vec!["--source-address", "eth0", "dns.google"]),
+            Err(ArgsError::InvalidSourceAddress(_, _))
+        ));
+        assert!(matches!(
+            source(vec![
+                "--source-address",
+                "2001:db8::1",
+                "--ipv4",
+                "dns.google"
+            ]),
+            Err(ArgsError::SourceFamilyMismatch(_, "ipv4"))
+        ));
+        assert!(matches!(
+            source(vec![
+                "--source-address",
+                "192.0.2.1",
+                "--ipv6",
+                "dns.google"
+            ]),
+            Err(ArgsError::SourceFamilyMismatch(_, "ipv6"))
+        ));
+    }
+
+    #[test]
+    fn ping_count() {
+        let count = |args| parse_cmd(args).map(|args| args.spawn_options.count);
+        assert_eq!(count(vec!["dns.google"]).unwrap(), None);
+        assert_eq!(count(vec!["--count", "5", "dns.google"]).unwrap(), Some(5));
+        for invalid in &["0", "-1", "many"] {
+            assert!(matches!(
+                count(vec!["--count", invalid, "dns.google"]),
+                Err(ArgsError::NotANumber("count", _)) | Err(ArgsError::InvalidUsage(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn max_restarts() {
+        let restarts = |args| parse_cmd(args).map(|args| args.max_restarts);
+        assert_eq!(restarts(vec!["dns.google"]).unwrap(), 0);
+        assert_eq!(
+            restarts(vec!["--max-restarts", "10", "dns.google"]).unwrap(),
+            10
+        );
+        assert!(matches!(
+            restarts(vec!["--max-restarts", "-1", "dns.google"]),
+            Err(ArgsError::NotANumber("max-restarts", _)) | Err(ArgsError::InvalidUsage(_))
+        ));
+    }
+
+    #[test]
+    fn packet_size() {
+        let size = |args| parse_cmd(args).map(|args| args.spawn_options.packet_size);
+        assert_eq!(size(vec!["dns.google"]).unwrap(), None);
+        assert_eq!(
+            size(vec!["--packet-size", "1472", "dns.google"]).unwrap(),
+            Some(1472)
+        );
+        for (invalid, got) in &[("3", 3), ("65508", 65508), ("100000", 100000)] {
+            assert!(matches!(
+                size(vec!["--packet-size", invalid, "dns.google"]),
+                Err(ArgsError::PacketSizeOutOfRange(size)) if size == *got
+            ));
+        }
+        assert!(matches!(
+            size(vec!["--packet-size", "jumbo", "dns.google"]),
+            Err(ArgsError::NotANumber("packet-size", _))
+        ));
+    }
+
+    #[test]
+    fn log_format_ahead_of_parsing() {
+        let format = |args: &[&str], env| {
+            let argv: Vec<OsString> = std::iter::once("program_path")
+                .chain(args.iter().copied())
+                .map(OsString::from)
+                .collect();
+            prescan_log_format(&argv, env)
+        };
+        assert_eq!(format(&["dns.google"], None), LogFormat::Pretty);
+        assert_eq!(format(&["--log-format", "json"], None), LogFormat::Json);
+        assert_eq!(format(&["--log-format=json"], None), LogFormat::Json);
+        assert_eq!(format(&["dns.google"], Some("json")), LogFormat::Json);
+        assert_eq!(
+            format(&["--log-format", "pretty"], Some("json")),
+            LogFormat::Pretty
+        );
+        // a target named like the option is not the option
+        assert_eq!(
+            format(&["--", "--log-format", "json"], None),
+            LogFormat::Pretty
+        );
+
+        assert!(parse_cmd(vec!["--log-format", "json", "dns.google"]).is_ok());
+        assert!(parse_cmd(vec!["--log-format", "xml", "dns.google"]).is_err());
+    }
+
+    #[test]
+    fn metrics_path_slashes() {
+        let path = |args| parse_cmd(args).unwrap().metrics.path;
+        assert_eq!(path(vec!["dns.google"]), "metrics");
+        assert_eq!(
+            path(vec!["--metrics-path", "/probe/metrics/", "dns.google"]),
+            "probe/metrics"
+        );
+    }
+
+    #[test]
+    fn ttl() {
+        let options = |args| parse_cmd(args).map(|args| args.spawn_options);
+        let defaults = options(vec!["dns.google"]).unwrap();
+        assert_eq!((defaults.ttl, defaults.print_ttl), (None, false));
+        let set = options(vec!["--ttl", "16", "--reply-ttl", "dns.google"]).unwrap();
+        assert_eq!((set.ttl, set.print_ttl), (Some(16), true));
+        for (invalid, got) in &[("0", 0), ("256", 256)] {
+            assert!(matches!(
+                options(vec!["--ttl", invalid, "dns.google"]),
+                Err(ArgsError::TtlOutOfRange(ttl)) if ttl == *got
+            ));
+        }
+        assert!(matches!(
+            options(vec!["--ttl", "many", "dns.google"]),
+            Err(ArgsError::NotANumber("ttl", _))
+        ));
+    }
+
+    #[test]
+    fn address_family() {
+        let family = |args| parse_cmd(args).unwrap().spawn_options.address_family;
+        assert_eq!(family(vec!["dns.google"]), None);
+        assert_eq!(
+            family(vec!["--ipv4", "dns.google"]),
+            Some(AddressFamily::Ipv4)
+        );
+        assert_eq!(
+            family(vec!["--ipv6", "dns.google"]),
+            Some(AddressFamily::Ipv6)
+        );
+        assert!(matches!(
+            parse_cmd(vec!["--ipv4", "--ipv6", "dns.google"]),
+            Err(ArgsError::InvalidUsage(_))
+        ));
+    }
+
+    #[test]
+    fn generated_targets() {
+        let generated = |args: Vec<&str>| parse_cmd(args).map(|a| a.spawn_options.generate);
+        let network = generated(vec!["--generate", "192.0.2.0/24"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(network, Generate::Network("192.0.2.0".parse().unwrap(), 24));
+        assert_eq!(network.count(), Some(254));
+        let range = generated(vec!["--generate", "192.0.2.1", "192.0.2.10"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(range.count(), Some(10));
+        assert_eq!(
+            generated(vec!["--generate", "192.0.2.0/31"])
+                .unwrap()
+                .unwrap()
+                .count(),
+            Some(2)
+        );
+        assert_eq!(
+            generated(vec!["--generate", "2001:db8::/64"])
+                .unwrap()
+                .unwrap()
+                .count(),
+            None
+        );
+
+        for invalid in &[
+            vec!["192.0.2.10", "192.0.2.1"],
+            vec!["192.0.2.1", "2001:db8::1"],
+            vec!["192.0.2.0/33"],
+            vec!["192.0.2.0/0"],
+            vec!["dns.google"],
+        ] {
+            let mut args = vec!["--generate"];
+            args.extend(invalid);
+            assert!(
+                matches!(generated(args), Err(ArgsError::InvalidGenerate(_))),
+                "{:?}",
+                invalid
+            );
+        }
+        assert!(matches!(
+            generated(vec!["dns.google", "--generate", "192.0.2.0/24"]),
+            Err(ArgsError::InvalidUsage(_))
+        ));
+    }
+
+    #[test]
+    fn ping_interval() {
+        assert_eq!(
+            parse_cmd(vec!["dns.google"]).unwrap().spawn_options.period,
+            None
+        );
+        assert_eq!(
+            parse_cmd(vec!["--interval", "10s", "dns.google"])
+                .unwrap()
+                .spawn_options
+                .period,
+            Some(Duration::from_secs(10))
+        );
+        assert!(matches!(
+            parse_cmd(vec!["--interval", "500us", "dns.google"]),
+            Err(ArgsError::IntervalTooShort(_))
+        ));
+        assert!(matches!(
+            parse_cmd(vec!["--interval", "often", "dns.google"]),
+            Err(ArgsError::NotAValidDuration("interval", _))
+        ));
+    }
+
+    #[test]
+    fn textfile_interval() {
+        let textfile = |interval| {
+            parse_cmd(vec![
+                "--textfile-output",
+                "/tmp/fping.prom",
+                "--textfile-interval",
+                interval,
+                "dns.google",
+            ])
+        };
+        assert_eq!(
+            textfile("30s").unwrap().textfile.unwrap().interval,
+            Duration::from_secs(30)
+        );
+        assert!(matches!(
+            textfile("0s"),
+            Err(ArgsError::ZeroInterval("textfile-interval"))
+        ));
+    }
+
+    #[cfg(feature = "graphite")]
+    #[test]
+    fn graphite_interval() {
+        let graphite = |interval| {
+            parse_cmd(vec![
+                "--graphite",
+                "localhost:2003",
+                "--graphite-interval",
+                interval,
+                "dns.google",
+            ])
+        };
+        assert_eq!(
+            graphite("10s").unwrap().graphite.unwrap().interval,
+            Duration::from_secs(10)
+        );
+        assert!(matches!(
+            graphite("0s"),
+            Err(ArgsError::ZeroInterval("graphite-interval"))
+        ));
+    }
+
+    #[test]
+    fn child_memory_interval() {
+        let child = |interval| parse_cmd(vec!["--child-memory-interval", interval, "dns.google"]);
+        assert_eq!(
+            parse_cmd(vec!["dns.google"]).unwrap().child_memory_interval,
+            None
+        );
+        assert_eq!(
+            child("15s").unwrap().child_memory_interval,
+            Some(Duration::from_secs(15))
+        );
+        assert!(matches!(
+            child("0s"),
+            Err(ArgsError::ZeroInterval("child-memory-interval"))
+        ));
+    }
+
+    #[test]
+    fn heartbeat_interval() {
+        let heartbeat = |interval| parse_cmd(vec!["--heartbeat-interval", interval, "dns.google"]);
+        assert_eq!(
+            heartbeat("5m").unwrap().heartbeat_interval,
+            Some(Duration::from_secs(300))
+        );
+        assert!(matches!(
+            heartbeat("0s"),
+            Err(ArgsError::ZeroInterval("heartbeat-interval"))
+        ));
+    }
+
+    #[test]
+    fn max_line_length() {
+        let max = |length| parse_cmd(vec!["--max-line-length", length, "dns.google"]);
+        assert_eq!(
+            parse_cmd(vec!["dns.google"]).unwrap().max_line_length,
+            DEFAULT_MAX_LINE_LENGTH
+        );
+        assert_eq!(max("4096").unwrap().max_line_length, 4096);
+        assert!(matches!(max("0"), Err(ArgsError::ZeroMaxLineLength)));
+    }
+
+    #[test]
+    fn probe_timeout() {
+        let timeout = |args| parse_cmd(args).map(|args| args.spawn_options.probe_timeout);
+        assert_eq!(timeout(vec!["dns.google"]).unwrap(), None);
+        assert_eq!(
+            timeout(vec!["--probe-timeout", "500ms", "dns.google"]).unwrap(),
+            Some(Duration::from_millis(500))
+        );
+        // rounded up to whole milliseconds
+        for (input, millis) in &[("1500us", 2), ("300us", 1), ("0s", 1), ("2ms 1ns", 3)] {
+            assert_eq!(
+                timeout(vec!["--probe-timeout", input, "dns.google"]).unwrap(),
+                Some(Duration::from_millis(*millis)),
+                "{}",
+                input
+            );
+        }
+        assert!(matches!(
+            timeout(vec!["--probe-timeout", "2s", "dns.google"]),
+            Err(ArgsError::ProbeTimeoutTooLong(_, _))
+        ));
+        assert!(matches!(
+            timeout(vec![
+                "--probe-timeout",
+                "600ms",
+                "--interval",
+                "500ms",
+                "dns.google"
+            ]),
+            Err(ArgsError::ProbeTimeoutTooLong(_, _))
+        ));
+        assert_eq!(
+            timeout(vec![
+                "--probe-timeout",
+                "2s",
+                "--interval",
+                "5s",
+                "dns.google"
+            ])
+            .unwrap(),
+            Some(Duration::from_secs(2))
+        );
+    }
+
+    #[tokio::test]
+    async fn check_needs_no_targets_or_fping() {
+        let args = load_with_version(
+            vec!["--check", "--metrics-port", "9999"],
+            Err(VersionError::BinaryNotFound),
+        )
+        .await
+        .unwrap();
+        assert!(args.check);
+        assert_eq!(args.metrics.addr.port(), 9999);
+
+        assert!(matches!(
+            load_with_version(vec!["localhost"], Err(VersionError::BinaryNotFound)).await,
+            Err(ArgsError::FpingProblem(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn check_without_client_certificate() {
+        let mtls = load_with_version(
+            vec![
+                "--check",
+                "--tls-cert",
+                "cert.pem",
+                "--tls-key",
+                "key.pem",
+                "--tls-client-ca",
+                "ca.pem",
+            ],
+            Err(VersionError::BinaryNotFound),
+        )
+        .await;
+        assert!(
+            matches!(&mtls, Err(ArgsError::InvalidUsage(e)) if e.kind == clap::ErrorKind::ArgumentConflict),
+            "{:?}",
+            mtls
+        );
+    }
+
+    #[tokio::test]
+    async fn config_file() {
+        let sample = r#"
+            metrics-bind = "127.0.0.1"
+            metrics-port = 9000
+            interval = "2s"
+            rtt-buckets = [0.01, 0.1]
+            targets = ["dns.google", "localhost"]
+        "#;
+        assert_eq!(
+            toml::from_str::<FileConfig>(sample).unwrap(),
+            FileConfig {
+                metrics_bind: Some(IpAddr::from([127, 0, 0, 1])),
+                metrics_port: Some(9000),
+                interval: Some("2s".to_owned()),
+                rtt_buckets: Some(vec![0.01, 0.1]),
+                targets: Some(vec!["dns.google".to_owned(), "localhost".to_owned()]),
+                ..FileConfig::default()
+            }
+        );
+
+        let path =
+            std::env::temp_dir().join(format!("fping_exporter_config_{}", std::process::id()));
+        let path_arg = path.to_str().unwrap();
+        let version = || Ok(semver::Version::new(5, 0, 0));
+        std::fs::write(&path, sample).unwrap();
+
+        let args = load_with_version(vec!["--config", path_arg], version())
+            .await
+            .unwrap();
+        assert_eq!(args.metrics.addr, "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(args.spawn_options.period, Some(Duration::from_secs(2)));
+        assert_eq!(args.metrics.rtt_buckets, vec![0.01, 0.1]);
+        assert_eq!(args.targets, vec!["dns.google", "localhost"]);
+
+        // the command line takes precedence, targets included
+        let args = load_with_version(
+            vec!["--metrics-port=9100", "--config", path_arg, "1.1.1.1"],
+            version(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(args.metrics.addr, "127.0.0.1:9100".parse().unwrap());
+        assert_eq!(args.targets, vec!["1.1.1.1"]);
+
+        // as do flags which conflict with an option of the file
+        let socket = std::env::temp_dir().join("fping_exporter.sock");
+        let args = load_with_version(
+            vec![
+                "--config",
+                path_arg,
+                "--metrics-socket",
+                socket.to_str().unwrap(),
+            ],
+            version(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(args.metrics.socket, Some(socket));
+        std::fs::write(&path, "target-file = \"/nonexistent/targets.txt\"").unwrap();
+        let args = load_with_version(
+            vec!["--config", path_arg, "--generate", "192.0.2.0/30"],
+            version(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(args.target_file, None);
+        assert!(args.spawn_options.generate.is_some());
+
+        for invalid in &[
+            "metrics-port = \"http\"",
+            "unknown = 1",
+            "targets = [\"-f\"]",
+        ] {
+            std::fs::write(&path, invalid).unwrap();
+            assert!(
+                matches!(
+                    load_with_version(vec!["--config", path_arg], version()).await,
+                    Err(ArgsError::ConfigParse(..))
+                ),
+                "{}",
+                invalid
+            );
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            load_with_version(vec!["--config", path_arg], version()).await,
+            Err(ArgsError::ConfigUnreadable(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn targets_from_file() {
+        let path =
+            std::env::temp_dir().join(format!("fping_exporter_targets_{}", std::process::id()));
+        let path_arg = path.to_str().unwrap();
+        let version = || Ok(semver::Version::new(5, 0, 0));
+
+        std::fs::write(&path, "# lab\n  dns.google  \n\nlocalhost\n").unwrap();
+        let args = load_with_version(
+            vec!["--target-file", path_arg, "localhost", "1.1.1.1"],
+            version(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(args.targets, vec!["localhost", "1.1.1.1", "dns.google"]);
+        assert_eq!(args.static_targets, vec!["localhost", "1.1.1.1"]);
+        assert!(args.target_labels.is_empty());
+
+        std::fs::write(&path, "dns.google env=prod\nlocalhost\n").unwrap();
+        let args = load_with_version(vec!["--target-file", path_arg], version())
+            .await
+            .unwrap();
+        assert_eq!(args.targets, vec!["dns.google", "localhost"]);
+        assert_eq!(args.target_labels.len(), 1);
+        assert_eq!(args.target_labels["dns.google"]["env"], "prod");
+
+        std::fs::write(&path, "# nothing yet\n").unwrap();
+        let empty = load_with_version(vec!["--target-file", path_arg], version()).await;
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(empty, Err(ArgsError::NoTargets)));
+
+        assert!(matches!(
+            load_with_version(vec!["--target-file", path_arg], version()).await,
+            Err(ArgsError::TargetFileUnreadable(..))
+        ));
+    }
 }
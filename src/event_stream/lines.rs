@@ -0,0 +1,134 @@
+use std::io;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// Lines longer than this are discarded unless configured otherwise, fping
+/// itself never prints anything close to it.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum Line {
+    Complete(String),
+    /// The line exceeded the maximum length, its contents were discarded
+    Oversized,
+}
+
+/// Like `tokio::io::Lines`, but never buffers more than `max_length` bytes
+/// of a single line.
+#[derive(Debug)]
+pub struct BoundedLines<R> {
+    reader: R,
+    buf: Vec<u8>,
+    // kept with the reader so an interrupted read can be resumed
+    oversized: bool,
+    read_any: bool,
+    max_length: usize,
+}
+
+impl<R: AsyncBufRead + Unpin> BoundedLines<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: Vec::new(),
+            oversized: false,
+            read_any: false,
+            max_length: DEFAULT_MAX_LINE_LENGTH,
+        }
+    }
+
+    pub fn set_max_length(&mut self, max_length: usize) {
+        self.max_length = max_length;
+    }
+
+    /// Cancel safe, partial lines are kept until the next call
+    pub async fn next_line(&mut self) -> io::Result<Option<Line>> {
+        loop {
+            let available = self.reader.fill_buf().await?;
+            if available.is_empty() {
+                if !self.read_any {
+                    return Ok(None);
+                }
+                break;
+            }
+            self.read_any = true;
+
+            let (chunk, used, complete) = match available.iter().position(|&b| b == b'\n') {
+                Some(end) => (&available[..end], end + 1, true),
+                None => (available, available.len(), false),
+            };
+            if !self.oversized {
+                if self.buf.len() + chunk.len() > self.max_length {
+                    self.oversized = true;
+                    self.buf = Vec::new();
+                } else {
+                    self.buf.extend_from_slice(chunk);
+                }
+            }
+            self.reader.consume(used);
+
+            if complete {
+                break;
+            }
+        }
+
+        self.read_any = false;
+        if std::mem::take(&mut self.oversized) {
+            return Ok(Some(Line::Oversized));
+        }
+
+        let mut line = std::mem::take(&mut self.buf);
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        // error messages may still be localized, which should not end the stream
+        let line = String::from_utf8(line)
+            .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned());
+        Ok(Some(Line::Complete(line)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::BufReader;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn discards_oversized_lines() {
+        let input: &[u8] = b"short\r\nthis line is too long\nok\npartial";
+        // a tiny buffer makes every line span several reads
+        let mut lines = BoundedLines::new(BufReader::with_capacity(4, input));
+        lines.set_max_length(10);
+
+        let mut seen = vec![];
+        while let Some(line) = lines.next_line().await.unwrap() {
+            seen.push(line);
+        }
+        assert_eq!(
+            seen,
+            vec![
+                Line::Complete("short".into()),
+                Line::Oversized,
+                Line::Complete("ok".into()),
+                Line::Complete("partial".into()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn decodes_invalid_utf8_lossily() {
+        let input: &[u8] = b"dns.google: Zeit\xfcberschreitung\nok\n";
+        let mut lines = BoundedLines::new(BufReader::new(input));
+
+        assert_eq!(
+            lines.next_line().await.unwrap(),
+            Some(Line::Complete(
+                "dns.google: Zeit\u{fffd}berschreitung".into()
+            ))
+        );
+        assert_eq!(
+            lines.next_line().await.unwrap(),
+            Some(Line::Complete("ok".into()))
+        );
+    }
+}
@@ -1,13 +1,17 @@
 use std::{future, io};
 
+mod lines;
 pub mod util;
 
 use tokio::{
-    io::{AsyncRead, BufReader, Lines},
+    io::{AsyncRead, BufReader},
     process::Child,
     sync::mpsc,
 };
 
+pub use self::lines::DEFAULT_MAX_LINE_LENGTH;
+use self::lines::{BoundedLines, Line};
+
 pub trait EventHandler {
     type Output;
     type Error;
@@ -19,6 +23,33 @@ pub trait EventHandler {
     fn on_error(&mut self, event: Self::Error);
 
     fn on_control(&mut self, handle: &mut Self::Handle, token: Self::Token) -> io::Result<()>;
+
+    /// A line longer than the configured maximum was discarded
+    fn on_oversized_line(&mut self, stream: &str);
+}
+
+/// Lets a handler outlive a single stream, such as across fping restarts
+impl<H: EventHandler + ?Sized> EventHandler for &mut H {
+    type Output = H::Output;
+    type Error = H::Error;
+    type Handle = H::Handle;
+    type Token = H::Token;
+
+    fn on_output(&mut self, event: Self::Output) {
+        (**self).on_output(event)
+    }
+
+    fn on_error(&mut self, event: Self::Error) {
+        (**self).on_error(event)
+    }
+
+    fn on_control(&mut self, handle: &mut Self::Handle, token: Self::Token) -> io::Result<()> {
+        (**self).on_control(handle, token)
+    }
+
+    fn on_oversized_line(&mut self, stream: &str) {
+        (**self).on_oversized_line(stream)
+    }
 }
 
 #[derive(Debug)]
@@ -26,8 +57,8 @@ pub enum ControlDisabled {}
 
 pub struct PendingStream<ES: EventStreamSource + ?Sized, T = ControlDisabled> {
     handle: ES::Handle,
-    stdout: Option<Lines<BufReader<ES::Stdout>>>,
-    stderr: Option<Lines<BufReader<ES::Stderr>>>,
+    stdout: Option<BoundedLines<BufReader<ES::Stdout>>>,
+    stderr: Option<BoundedLines<BufReader<ES::Stderr>>>,
     control: Option<mpsc::Receiver<T>>,
 }
 
@@ -37,11 +68,10 @@ impl<ES: EventStreamSource> PendingStream<ES> {
         stdout: Option<ES::Stdout>,
         stderr: Option<ES::Stderr>,
     ) -> Self {
-        use tokio::io::AsyncBufReadExt;
         PendingStream {
             handle,
-            stdout: stdout.map(BufReader::new).map(AsyncBufReadExt::lines),
-            stderr: stderr.map(BufReader::new).map(AsyncBufReadExt::lines),
+            stdout: stdout.map(BufReader::new).map(BoundedLines::new),
+            stderr: stderr.map(BufReader::new).map(BoundedLines::new),
             control: None,
         }
     }
@@ -57,15 +87,34 @@ impl<ES: EventStreamSource> PendingStream<ES> {
 }
 
 impl<ES: EventStreamSource, T> PendingStream<ES, T> {
+    pub fn with_max_line_length(mut self, max_length: usize) -> Self {
+        for lines in self.stdout.iter_mut() {
+            lines.set_max_length(max_length);
+        }
+        for lines in self.stderr.iter_mut() {
+            lines.set_max_length(max_length);
+        }
+        self
+    }
+
+    pub fn handle(&self) -> &ES::Handle {
+        &self.handle
+    }
+
     pub fn dispose(self) -> ES::Handle {
         self.handle
     }
 
+    /// Like `dispose`, but keeps the controls to hand to a replacement
+    pub fn dispose_with_controls(self) -> (ES::Handle, Option<mpsc::Receiver<T>>) {
+        (self.handle, self.control)
+    }
+
     pub async fn listen(
         &mut self,
         mut handler: impl EventHandler<Output = String, Error = String, Handle = ES::Handle, Token = T>,
     ) -> io::Result<()> {
-        async fn next_line<R>(lines: &mut Lines<R>) -> Option<io::Result<String>>
+        async fn next_line<R>(lines: &mut BoundedLines<R>) -> Option<io::Result<Line>>
         where
             R: tokio::io::AsyncBufRead + Unpin,
         {
@@ -80,14 +129,21 @@ impl<ES: EventStreamSource, T> PendingStream<ES, T> {
         }
 
         #[inline]
-        fn handle_or_eof<E, Err>(
+        fn handle_or_eof<H: EventHandler, Err>(
             label: &str,
-            ev: Option<Result<E, Err>>,
+            ev: Option<Result<Line, Err>>,
             eof_flag: &mut bool,
-            handler: impl FnOnce(E),
+            handler: &mut H,
+            on_line: impl FnOnce(&mut H, String),
         ) -> Result<(), Err> {
             if let Some(ev) = ev {
-                handler(ev?);
+                match ev? {
+                    Line::Complete(line) => on_line(handler, line),
+                    Line::Oversized => {
+                        warn!("discarded oversized line on {}", label);
+                        handler.on_oversized_line(label);
+                    }
+                }
             } else {
                 *eof_flag = true;
                 debug!("{} EOF", label);
@@ -104,10 +160,10 @@ impl<ES: EventStreamSource, T> PendingStream<ES, T> {
                     handler.on_control(&mut self.handle, token)?
                 }
                 ev = poll(self.stdout.as_mut(), next_line), if !out_eof => {
-                    handle_or_eof("stdout", ev, &mut out_eof, |x| handler.on_output(x))?;
+                    handle_or_eof("stdout", ev, &mut out_eof, &mut handler, |h, x| h.on_output(x))?;
                 }
                 ev = poll(self.stderr.as_mut(), next_line), if !err_eof => {
-                    handle_or_eof("stderr", ev, &mut err_eof, |x| handler.on_error(x))?;
+                    handle_or_eof("stderr", ev, &mut err_eof, &mut handler, |h, x| h.on_error(x))?;
                 }
                 else => {
                     break;
@@ -124,6 +180,7 @@ pub trait EventStreamSource {
     type Stdout: AsyncRead + Unpin;
     type Stderr: AsyncRead + Unpin;
 
+    #[allow(clippy::wrong_self_convention)]
     fn as_eventstream(self) -> io::Result<PendingStream<Self>>;
 }
 
@@ -139,6 +196,10 @@ impl EventStreamSource for Child {
     }
 }
 
+#[cfg(test)]
+#[allow(unused_imports)]
+pub use synthetic::{as_stderr, as_stdout};
+
 #[cfg(test)]
 mod synthetic {
     use std::io;
@@ -181,6 +242,3 @@ mod synthetic {
         SyntheticStream::Stderr(stream).as_eventstream()
     }
 }
-
-#[cfg(test)]
-pub use synthetic::{as_stderr, as_stdout};
@@ -40,6 +40,11 @@ impl<F: EventHandler> EventHandler for NoPrelaunchControl<F> {
             Ok(())
         }
     }
+
+    fn on_oversized_line(&mut self, stream: &str) {
+        self.initialized = true;
+        self.handler.on_oversized_line(stream);
+    }
 }
 
 pub mod signal {
@@ -105,7 +110,7 @@ pub mod signal {
     #[derive(Debug)]
     pub struct Interrupted<T>(pub T);
 
-    impl<F, H: ?Sized> ControlToInterrupt<F, H::Signal>
+    impl<F, H> ControlToInterrupt<F, H::Signal>
     where
         F: EventHandler<Handle = H>,
         H: Interruptable,
@@ -146,6 +151,10 @@ pub mod signal {
                 Ok(())
             }
         }
+
+        fn on_oversized_line(&mut self, stream: &str) {
+            self.handler.on_oversized_line(stream)
+        }
     }
 }
 
@@ -159,7 +168,7 @@ pub mod lock {
 
     #[derive(Debug)]
     pub struct Claim {
-        inner: OwnedMutexGuard<()>,
+        _inner: OwnedMutexGuard<()>,
     }
 
     #[derive(Debug)]
@@ -169,11 +178,10 @@ pub mod lock {
     }
 
     impl<F> LockControl<F> {
-        pub fn new(handler: F) -> Self {
-            LockControl {
-                handler,
-                lock: Arc::new(Mutex::new(())),
-            }
+        /// `lock` is shared by the controls of successive listeners, so a
+        /// claim handed out before a listener is rebuilt still holds.
+        pub fn new(handler: F, lock: Arc<Mutex<()>>) -> Self {
+            LockControl { handler, lock }
         }
     }
 
@@ -202,11 +210,68 @@ pub mod lock {
         ) -> std::io::Result<()> {
             if let Ok(lock) = self.lock.clone().try_lock_owned() {
                 self.handler
-                    .on_control(handle, (token, Claim { inner: lock }))
+                    .on_control(handle, (token, Claim { _inner: lock }))
             } else {
                 debug!("try-lock failed, dropping {:?}", token);
                 Ok(())
             }
         }
+
+        fn on_oversized_line(&mut self, stream: &str) {
+            self.handler.on_oversized_line(stream)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::Mutex;
+
+    use super::lock::{Claim, LockControl};
+    use crate::event_stream::EventHandler;
+
+    /// Holds on to every claim, like a summary in progress
+    #[derive(Default)]
+    struct Holder(Vec<(u32, Claim)>);
+
+    impl EventHandler for Holder {
+        type Output = String;
+        type Error = String;
+        type Handle = ();
+        type Token = (u32, Claim);
+
+        fn on_output(&mut self, _: String) {}
+
+        fn on_error(&mut self, _: String) {}
+
+        fn on_control(&mut self, _: &mut (), token: Self::Token) -> std::io::Result<()> {
+            self.0.push(token);
+            Ok(())
+        }
+
+        fn on_oversized_line(&mut self, _: &str) {}
+    }
+
+    #[test]
+    fn claim_survives_rebuilt_control() {
+        let lock = Arc::new(Mutex::new(()));
+        let mut holder = Holder::default();
+
+        LockControl::new(&mut holder, lock.clone())
+            .on_control(&mut (), 1)
+            .unwrap();
+        // the listener is rebuilt after a reload while the summary is held
+        LockControl::new(&mut holder, lock.clone())
+            .on_control(&mut (), 2)
+            .unwrap();
+        assert_eq!(holder.0.iter().map(|(t, _)| *t).collect::<Vec<_>>(), [1]);
+
+        holder.0.clear();
+        LockControl::new(&mut holder, lock)
+            .on_control(&mut (), 3)
+            .unwrap();
+        assert_eq!(holder.0.iter().map(|(t, _)| *t).collect::<Vec<_>>(), [3]);
     }
 }
@@ -0,0 +1,103 @@
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::{
+    args::ArgsError,
+    fping::{version::VersionError, SpawnError},
+    prom::{BindError, TlsError},
+    targets::TargetsError,
+};
+
+pub const EXIT_CODES_HELP: &str = "EXIT CODES:
+    1    runtime failure
+    2    fping could not be found or is unusable
+    3    invalid arguments or configuration
+    4    unable to bind the metrics server
+    5    all targets were unreachable for --exit-on-total-loss";
+
+/// Process exit codes, kept in sync with `EXIT_CODES_HELP`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailureClass {
+    Runtime = 1,
+    FpingUnavailable = 2,
+    InvalidArguments = 3,
+    BindFailure = 4,
+    TotalLoss = 5,
+}
+
+#[derive(Debug, Error)]
+#[error("no target responded for {}", humantime::format_duration(*.0))]
+pub struct TotalLoss(pub Duration);
+
+impl FailureClass {
+    pub fn of(err: &anyhow::Error) -> Self {
+        if let Some(e) = err.downcast_ref::<ArgsError>() {
+            match e {
+                ArgsError::FpingProblem(_) => FailureClass::FpingUnavailable,
+                _ => FailureClass::InvalidArguments,
+            }
+        } else if err.is::<VersionError>() {
+            FailureClass::FpingUnavailable
+        } else if let Some(e) = err.downcast_ref::<SpawnError>() {
+            match e {
+                SpawnError::BinaryNotFound(_) | SpawnError::PermissionDenied(_) => {
+                    FailureClass::FpingUnavailable
+                }
+                _ => FailureClass::Runtime,
+            }
+        } else if err.is::<TlsError>() {
+            FailureClass::InvalidArguments
+        } else if let Some(e) = err.downcast_ref::<TargetsError>() {
+            match e {
+                TargetsError::Fetch(..) => FailureClass::Runtime,
+                _ => FailureClass::InvalidArguments,
+            }
+        } else if err.is::<BindError>() {
+            FailureClass::BindFailure
+        } else if err.is::<TotalLoss>() {
+            FailureClass::TotalLoss
+        } else {
+            FailureClass::Runtime
+        }
+    }
+
+    pub fn exit(self) -> ! {
+        std::process::exit(self as i32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_errors() {
+        assert_eq!(
+            FailureClass::of(&ArgsError::FpingProblem(VersionError::BinaryNotFound).into()),
+            FailureClass::FpingUnavailable
+        );
+        assert_eq!(
+            FailureClass::of(
+                &ArgsError::MalformedBind("x".parse::<std::net::IpAddr>().unwrap_err()).into()
+            ),
+            FailureClass::InvalidArguments
+        );
+        assert_eq!(
+            FailureClass::of(&SpawnError::PermissionDenied("/usr/bin/fping".into()).into()),
+            FailureClass::FpingUnavailable
+        );
+        assert_eq!(
+            FailureClass::of(&SpawnError::TooManyOpenFiles("fping".into()).into()),
+            FailureClass::Runtime
+        );
+        assert_eq!(
+            FailureClass::of(&TotalLoss(Duration::from_secs(60)).into()),
+            FailureClass::TotalLoss
+        );
+        assert_eq!(
+            FailureClass::of(&anyhow::anyhow!("fping listener terminated")),
+            FailureClass::Runtime
+        );
+    }
+}
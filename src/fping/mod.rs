@@ -1,5 +1,9 @@
-use std::{ffi::OsStr, io, process::Stdio, time::Duration};
+use std::{
+    convert::TryInto, ffi::OsStr, io, net::IpAddr, ops::RangeInclusive, process::Stdio,
+    time::Duration,
+};
 
+use thiserror::Error;
 use tokio::{
     process::{Child, Command},
     time::error::Elapsed,
@@ -10,13 +14,157 @@ use crate::event_stream::{EventStreamSource, PendingStream};
 mod protocol;
 pub mod version;
 
-pub use self::protocol::{Control, Ping, SentReceivedSummary, LABEL_NAMES};
+pub use self::protocol::{
+    icmp_error_kind, Control, PatternError, PatternOverrides, Ping, SentReceivedSummary,
+    LABEL_NAMES, PING_GROUPS, SUMMARY_GROUPS,
+};
+
+/// How targets are probed, exported as the `protocol` label so series stay
+/// distinct if other probe types are ever added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Icmp,
+}
+
+impl Protocol {
+    pub fn label(self) -> &'static str {
+        match self {
+            Protocol::Icmp => "icmp",
+        }
+    }
+}
+
+/// Address family fping is restricted to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    /// -4
+    Ipv4,
+    /// -6
+    Ipv6,
+}
+
+/// Targets fping generates itself (-g)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Generate {
+    /// every host address of a network, such as 192.0.2.0/24
+    Network(IpAddr, u8),
+    /// every address from start to end, inclusive
+    Range(IpAddr, IpAddr),
+}
+
+impl Generate {
+    /// Number of targets fping will ping, `None` if it cannot be predicted
+    pub fn count(&self) -> Option<u32> {
+        match *self {
+            // the network and broadcast address are skipped, except for /31 and /32
+            Generate::Network(IpAddr::V4(_), prefix) => {
+                let hosts = 1u64 << (32 - u32::from(prefix));
+                (if prefix < 31 { hosts - 2 } else { hosts })
+                    .try_into()
+                    .ok()
+            }
+            Generate::Range(IpAddr::V4(start), IpAddr::V4(end)) => {
+                (u64::from(u32::from(end)) - u64::from(u32::from(start)) + 1)
+                    .try_into()
+                    .ok()
+            }
+            Generate::Range(IpAddr::V6(start), IpAddr::V6(end)) => {
+                let span = u128::from(end) - u128::from(start);
+                span.checked_add(1)?.try_into().ok()
+            }
+            // how IPv6 networks are expanded differs between fping versions
+            _ => None,
+        }
+    }
+
+    fn args(&self) -> Vec<String> {
+        match self {
+            Generate::Network(addr, prefix) => vec![format!("{}/{}", addr, prefix)],
+            Generate::Range(start, end) => vec![start.to_string(), end.to_string()],
+        }
+    }
+}
+
+/// Time between pings to the same target if -p is not given
+pub const DEFAULT_PERIOD: Duration = Duration::from_secs(1);
+
+/// ICMP data bytes per ping if -b is not given
+pub const DEFAULT_PACKET_SIZE: u16 = 56;
+/// Range of -b fping accepts, the upper bound fills an IPv4 packet
+pub const PACKET_SIZES: RangeInclusive<u16> = 4..=65507;
+
+/// Options affecting how fping probes its targets
+#[derive(Debug, Default)]
+pub struct SpawnOptions {
+    /// ping every address a target resolves to (-m)
+    pub all_addresses: bool,
+    /// only resolve and ping addresses of this family, fping decides if unset
+    pub address_family: Option<AddressFamily>,
+    /// address pings are sent from (-S)
+    pub source_address: Option<IpAddr>,
+    /// ping generated targets instead of the given ones (-g)
+    pub generate: Option<Generate>,
+    /// time between pings to the same target (-p)
+    pub period: Option<Duration>,
+    /// time to wait for each reply (-t), whole milliseconds
+    pub probe_timeout: Option<Duration>,
+    /// pings per target before fping exits (-c), loops forever if unset
+    pub count: Option<u32>,
+    /// ICMP data bytes per ping (-b)
+    pub packet_size: Option<u16>,
+    /// TTL of outgoing pings (-H)
+    pub ttl: Option<u8>,
+    /// report the TTL of every reply (--print-ttl), fping 5.0 and later
+    pub print_ttl: bool,
+    /// scheduling priority for the fping process, between -20 and 19
+    pub nice: Option<i32>,
+}
+
+#[cfg(unix)]
+fn set_nice(nice: i32) -> io::Result<()> {
+    use nix::libc;
+
+    // only affects the forked child, called before exec
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SpawnError {
+    #[error("fping was not found at {0:?}, check --fping-bin, FPING_BIN or PATH")]
+    BinaryNotFound(String),
+    #[error("{0:?} is not executable, check its permissions or point --fping-bin at fping")]
+    PermissionDenied(String),
+    #[error("too many open files to start {0:?}, raise the limit with ulimit -n or LimitNOFILE")]
+    TooManyOpenFiles(String),
+    #[error("unable to start {0:?}")]
+    Other(String, #[source] io::Error),
+}
+
+impl SpawnError {
+    fn new(program: &str, e: io::Error) -> Self {
+        use nix::libc;
+
+        let program = program.to_owned();
+        match (e.kind(), e.raw_os_error()) {
+            (io::ErrorKind::NotFound, _) => SpawnError::BinaryNotFound(program),
+            (io::ErrorKind::PermissionDenied, _) => SpawnError::PermissionDenied(program),
+            (_, Some(libc::EMFILE)) | (_, Some(libc::ENFILE)) => {
+                SpawnError::TooManyOpenFiles(program)
+            }
+            _ => SpawnError::Other(program, e),
+        }
+    }
+}
 
 pub struct Launcher<'t> {
     program: &'t str,
 }
 
-pub fn for_program<S>(program: &S) -> Launcher
+pub fn for_program<S>(program: &S) -> Launcher<'_>
 where
     S: AsRef<str> + ?Sized,
 {
@@ -27,11 +175,23 @@ where
 
 impl From<Elapsed> for version::VersionError {
     fn from(_: Elapsed) -> Self {
-        Self::SpecificFailure("fping failed to exit in a reasonable timespan, please ensure FPING_BIN points to a valid version of fping".to_string())
+        Self::SpecificFailure("fping failed to exit in a reasonable timespan, please ensure --fping-bin or FPING_BIN points to a valid version of fping".to_string())
     }
 }
 
 impl<'t> Launcher<'t> {
+    pub fn program(&self) -> &'t str {
+        self.program
+    }
+
+    /// The C locale keeps fping output in the English, ASCII form the
+    /// patterns expect.
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(self.program);
+        cmd.env("LC_ALL", "C");
+        cmd
+    }
+
     pub async fn version(
         &self,
         timeout: Duration,
@@ -39,23 +199,98 @@ impl<'t> Launcher<'t> {
         version::output_to_version(
             tokio::time::timeout(
                 timeout,
-                Command::new(self.program)
-                    .arg("--version")
-                    .kill_on_drop(true)
-                    .output(),
+                self.command().arg("--version").kill_on_drop(true).output(),
             )
             .await?,
         )
     }
 
-    pub async fn spawn<S: AsRef<OsStr>>(&self, targets: &[S]) -> io::Result<PendingStream<Child>> {
-        Command::new(self.program)
-            .arg("-ADln")
-            .args(targets)
+    pub async fn spawn<S: AsRef<OsStr>>(
+        &self,
+        targets: &[S],
+        options: &SpawnOptions,
+    ) -> Result<PendingStream<Child>, SpawnError> {
+        let mut cmd = self.command();
+        cmd.arg("-ADn");
+        match options.count {
+            Some(count) => cmd.arg("-c").arg(count.to_string()),
+            None => cmd.arg("-l"),
+        };
+        if options.all_addresses {
+            cmd.arg("-m");
+        }
+        match options.address_family {
+            Some(AddressFamily::Ipv4) => {
+                cmd.arg("-4");
+            }
+            Some(AddressFamily::Ipv6) => {
+                cmd.arg("-6");
+            }
+            None => {}
+        }
+        if let Some(source) = options.source_address {
+            cmd.arg("-S").arg(source.to_string());
+        }
+        if let Some(period) = options.period {
+            cmd.arg("-p").arg(period.as_millis().to_string());
+        }
+        if let Some(timeout) = options.probe_timeout {
+            cmd.arg("-t").arg(timeout.as_millis().to_string());
+        }
+        if let Some(size) = options.packet_size {
+            cmd.arg("-b").arg(size.to_string());
+        }
+        if let Some(ttl) = options.ttl {
+            cmd.arg("-H").arg(ttl.to_string());
+        }
+        if options.print_ttl {
+            cmd.arg("--print-ttl");
+        }
+        if let Some(generate) = &options.generate {
+            cmd.arg("-g").args(generate.args());
+        }
+        #[cfg(unix)]
+        if let Some(nice) = options.nice {
+            unsafe {
+                cmd.pre_exec(move || set_nice(nice));
+            }
+        }
+        cmd.args(targets)
             .stdin(Stdio::null())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
-            .spawn()?
-            .as_eventstream()
+            .spawn()
+            .and_then(|child| child.as_eventstream())
+            .map_err(|e| SpawnError::new(self.program, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn spawn_errors_name_the_binary() {
+        let missing = "/nonexistent/fping";
+        let err = for_program(missing)
+            .spawn(&["localhost"], &SpawnOptions::default())
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(&err, SpawnError::BinaryNotFound(program) if program == missing));
+        assert!(err.to_string().contains(missing));
+
+        // a file without the executable bit
+        let path =
+            std::env::temp_dir().join(format!("fping_exporter_noexec_{}", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        let program = path.to_str().unwrap();
+        let err = for_program(program)
+            .spawn(&["localhost"], &SpawnOptions::default())
+            .await
+            .err()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(err, SpawnError::PermissionDenied(_)), "{:?}", err);
     }
 }
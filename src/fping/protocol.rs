@@ -1,8 +1,60 @@
 use regex::Regex;
-use std::time::Duration;
+use std::{sync::OnceLock, time::Duration};
+use thiserror::Error;
 
+/// Labels of every per-target series, `protocol` is added by the metrics
 #[allow(dead_code)]
-pub const LABEL_NAMES: [&str; 2] = ["target", "addr"];
+pub const LABEL_NAMES: [&str; 3] = ["target", "addr", "protocol"];
+
+pub const PING_GROUPS: [&str; 5] = ["ts", "id", "addr", "seq", "rtt"];
+pub const SUMMARY_GROUPS: [&str; 4] = ["target", "addr", "xmt", "rcv"];
+
+#[derive(Debug, Error)]
+pub enum PatternError {
+    #[error("--{0} is not a valid regex: {1}")]
+    Invalid(&'static str, #[source] regex::Error),
+    #[error("--{0} is missing the named capture group {1:?}")]
+    MissingGroup(&'static str, &'static str),
+}
+
+/// User supplied replacements for the built-in patterns, for fping
+/// versions or locales with a different output format.
+#[derive(Debug, Default)]
+pub struct PatternOverrides {
+    pub ping: Option<Regex>,
+    pub summary: Option<Regex>,
+}
+
+static OVERRIDES: OnceLock<PatternOverrides> = OnceLock::new();
+
+impl PatternOverrides {
+    /// Compiles `pattern`, ensuring it has every capture group the parser reads
+    pub fn compile(
+        arg: &'static str,
+        pattern: &str,
+        groups: &[&'static str],
+    ) -> Result<Regex, PatternError> {
+        let regex = Regex::new(pattern).map_err(|e| PatternError::Invalid(arg, e))?;
+        match groups
+            .iter()
+            .find(|group| !regex.capture_names().any(|name| name == Some(group)))
+        {
+            Some(group) => Err(PatternError::MissingGroup(arg, group)),
+            None => Ok(regex),
+        }
+    }
+
+    /// Can only be installed once, before any output is parsed
+    pub fn install(self) {
+        if OVERRIDES.set(self).is_err() {
+            warn!("output patterns were already overridden");
+        }
+    }
+
+    fn get(select: impl FnOnce(&PatternOverrides) -> Option<&Regex>) -> Option<&'static Regex> {
+        OVERRIDES.get().and_then(select)
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub struct Ping<S> {
@@ -11,6 +63,15 @@ pub struct Ping<S> {
     pub addr: S,
     pub seq: u64,
     pub result: Option<Duration>,
+    /// Running average round-trip time in milliseconds, `None` until a
+    /// reply arrives or if fping does not print it
+    pub avg: Option<f64>,
+    /// Running loss percentage
+    pub loss: Option<f64>,
+    /// A second reply to an already answered request, marked `(DUP!)`
+    pub duplicate: bool,
+    /// TTL of the reply as fping prints it with `--print-ttl`
+    pub ttl: Option<u8>,
 }
 
 impl<'y> Ping<&'y str> {
@@ -23,17 +84,33 @@ impl<'y> Ping<&'y str> {
                     \s\((?P<addr>[^\)]+)\)\s+:   # (8.8.8.8)                       :
                     \s\[(?P<seq>\d+)\],          # [0],
                     \s(?:
-                        timed|                   # timed out
+                        timed\sout|              # timed out
                         \d+\sbytes,\s(?P<rtt>    # 64 bytes,
                             [^\s]+               # 18.3 ms || 283 ms
                         )\s ms
                     )
+                    (?:\s\(TTL\s(?:            # (TTL 58)
+                        (?P<ttl>\d{1,3})|unknown
+                    )\))?
+                    (?:\s\(
+                        (?P<avg>[^\s]+)\savg,   # (0.040 avg,
+                        \s(?P<loss>[^\s]+)%     # 0%
+                        \sloss\)                # loss)
+                    )?
+                    (?:.*?(?P<dup>\(DUP!\)))?   # (DUP!)
                     .*$
                 "
             )
             .unwrap();
         }
 
+        Self::parse_with(
+            PatternOverrides::get(|o| o.ping.as_ref()).unwrap_or(&FPING_LINE),
+            raw,
+        )
+    }
+
+    fn parse_with<S: AsRef<str> + ?Sized>(pattern: &Regex, raw: &'y S) -> Option<Self> {
         fn millis_to_duration(time: f64) -> Option<Duration> {
             lazy_static! {
                 static ref MILLISECOND: Duration = Duration::from_millis(1);
@@ -45,7 +122,12 @@ impl<'y> Ping<&'y str> {
             }
         }
 
-        let caps = FPING_LINE.captures(raw.as_ref())?;
+        // optional, overridden patterns may not capture these
+        fn optional(caps: &regex::Captures, name: &str) -> Option<f64> {
+            caps.name(name)?.as_str().parse().ok()
+        }
+
+        let caps = pattern.captures(raw.as_ref())?;
         Some(Ping {
             timestamp: caps.name("ts")?.as_str(),
             target: caps.name("id")?.as_str(),
@@ -61,6 +143,11 @@ impl<'y> Ping<&'y str> {
                         .map(Some)
                 },
             )?,
+            // fping prints NaN as the average of a target without replies
+            avg: optional(&caps, "avg").filter(|avg| avg.is_finite()),
+            loss: optional(&caps, "loss"),
+            duplicate: caps.name("dup").is_some(),
+            ttl: caps.name("ttl").and_then(|ttl| ttl.as_str().parse().ok()),
         })
     }
 }
@@ -76,6 +163,12 @@ pub struct SentReceivedSummary<S> {
     pub addr: S,
     pub sent: u32,
     pub received: u32,
+    /// Packet loss percentage as calculated by fping, rounded down
+    pub loss: Option<u32>,
+    /// Round-trip times in milliseconds, absent if nothing was received
+    pub min: Option<f64>,
+    pub avg: Option<f64>,
+    pub max: Option<f64>,
 }
 
 impl<S: Copy> SentReceivedSummary<S> {
@@ -86,14 +179,54 @@ impl<S: Copy> SentReceivedSummary<S> {
 
 #[derive(Debug, PartialEq)]
 pub enum Control<S> {
-    IcmpError { target: S, addr: S, error: S },
-    FpingError { target: S, message: S },
+    IcmpError {
+        target: S,
+        addr: S,
+        error: S,
+    },
+    /// The first hop suggested a better route, not an error since the echo
+    /// request is still forwarded.
+    IcmpRedirect {
+        target: S,
+        addr: S,
+    },
+    FpingError {
+        target: S,
+        message: S,
+    },
+    /// fping gave up, it prints this prefixed with its own name right
+    /// before exiting
+    ProcessExit {
+        message: S,
+    },
     BlankLine,
     SummaryLocalTime,
     TargetSummary(SentReceivedSummary<S>),
+    /// Looks like a target summary, but the counts could not be parsed
+    MalformedSummary(S),
     Unhandled(S),
 }
 
+/// Bounded label value for the error of `Control::IcmpError`, fping spells
+/// out every unreachable code separately.
+pub fn icmp_error_kind(error: &str) -> &'static str {
+    let error = error.to_ascii_lowercase();
+    if error.contains("prohibited") {
+        // filtered by a firewall rather than a missing route
+        "prohibited"
+    } else if error.contains("unreachable") {
+        "unreachable"
+    } else if error.contains("time exceeded") {
+        "time_exceeded"
+    } else if error.contains("parameter problem") {
+        "parameter_problem"
+    } else if error.contains("source quench") {
+        "source_quench"
+    } else {
+        "other"
+    }
+}
+
 impl<'t> Control<&'t str> {
     fn parse_icmp_error(raw: &'t str) -> Option<Self> {
         lazy_static! {
@@ -110,10 +243,34 @@ impl<'t> Control<&'t str> {
         }
 
         let caps: regex::Captures = ICMP_ERROR.captures(raw)?;
-        Some(Control::IcmpError {
-            error: caps.name("error")?.as_str(),
-            addr: caps.name("addr")?.as_str(),
-            target: caps.name("target")?.as_str(),
+        let error = caps.name("error")?.as_str();
+        let addr = caps.name("addr")?.as_str();
+        let target = caps.name("target")?.as_str();
+        if error == "ICMP Redirect" {
+            Some(Control::IcmpRedirect { target, addr })
+        } else {
+            Some(Control::IcmpError {
+                error,
+                addr,
+                target,
+            })
+        }
+    }
+
+    fn parse_process_exit(raw: &'t str) -> Option<Self> {
+        lazy_static! {
+            static ref PROCESS_EXIT: Regex = Regex::new(
+                r"(?x)
+                ^(?:\S*/)?fping6?:          # argv[0], which may be a path
+                \ (?P<msg>.+)$               # can't create socket (must run as root?)
+            "
+            )
+            .unwrap();
+        }
+
+        let caps: regex::Captures = PROCESS_EXIT.captures(raw)?;
+        Some(Control::ProcessExit {
+            message: caps.name("msg")?.as_str(),
         })
     }
 
@@ -121,7 +278,7 @@ impl<'t> Control<&'t str> {
         lazy_static! {
             static ref FPING_ERROR: Regex = Regex::new(
                 r"(?x)
-                ^(?P<target>[^:]+):
+                ^(?P<target>.+?):            # up to the first ': ', IPv6 targets contain colons
                 \ (?P<msg>.*)$
             "
             )
@@ -144,21 +301,57 @@ impl<'t> Control<&'t str> {
                 \ [^\s]+\ =                  # xmt/rcv/%loss =
                 \ (?P<xmt>\d+)               # 1
                 /(?P<rcv>\d+)                # /1
-                .*$                          # /0%, min/avg/max = 16.3/16.3/16.3
+                (?:/(?P<loss>\d+)%)?         # /0%
+                (?:,\ [^\s]+\ =              # , min/avg/max =
+                \ (?P<min>[\d.]+)            # 16.3
+                /(?P<avg>[\d.]+)             # /16.3
+                /(?P<max>[\d.]+))?           # /16.3
+                .*$
             "
             )
             .unwrap();
         }
 
-        let caps: regex::Captures = STATUS_LINE.captures(raw)?;
+        let pattern = PatternOverrides::get(|o| o.summary.as_ref()).unwrap_or(&STATUS_LINE);
+        Self::parse_status_with(pattern, raw)
+    }
+
+    fn parse_status_with(pattern: &Regex, raw: &'t str) -> Option<Self> {
+        let caps: regex::Captures = pattern.captures(raw)?;
+        // optional, overridden patterns may not capture these
+        fn optional<T: std::str::FromStr>(caps: &regex::Captures, name: &str) -> Option<T> {
+            caps.name(name)?.as_str().parse().ok()
+        }
         Some(Control::TargetSummary(SentReceivedSummary {
             target: caps.name("target")?.as_str(),
             addr: caps.name("addr")?.as_str(),
             received: caps.name("rcv")?.as_str().parse().ok()?,
             sent: caps.name("xmt")?.as_str().parse().ok()?,
+            loss: optional(&caps, "loss"),
+            min: optional(&caps, "min"),
+            avg: optional(&caps, "avg"),
+            max: optional(&caps, "max"),
         }))
     }
 
+    fn parse_malformed_summary(raw: &'t str) -> Option<Self> {
+        lazy_static! {
+            static ref SUMMARY_PREFIX: Regex = Regex::new(
+                r"(?x)
+                ^.+?\ \([^\)]+\)\s+:        # dns.google (8.8.8.8)              :
+                \ xmt/rcv/%loss\ =          # xmt/rcv/%loss =
+            "
+            )
+            .unwrap();
+        }
+
+        if SUMMARY_PREFIX.is_match(raw) {
+            Some(Control::MalformedSummary(raw))
+        } else {
+            None
+        }
+    }
+
     pub fn parse<S: AsRef<str> + ?Sized>(raw: &'t S) -> Self {
         #[inline]
         fn wrap_option<T, E: Copy>(
@@ -180,6 +373,9 @@ impl<'t> Control<&'t str> {
             }))
             .or_else(wrap_option(Self::parse_icmp_error))
             .or_else(wrap_option(Self::parse_status_line))
+            .or_else(wrap_option(Self::parse_malformed_summary))
+            // fping's own errors would pass for those of a target named fping
+            .or_else(wrap_option(Self::parse_process_exit))
             .or_else(wrap_option(Self::parse_fping_error))
             .unwrap_or_else(Control::Unhandled)
     }
@@ -203,15 +399,307 @@ mod tests {
                 addr: "127.0.0.1",
                 seq: 9,
                 result: Some(Duration::from_micros(29)),
+                avg: Some(0.040),
+                loss: Some(0.0),
+                duplicate: false,
+                ttl: None,
+            })
+        );
+        assert_eq!(
+            Ping::parse(
+                "[1611765997.71135] dns.google (8.8.8.8) : [0], timed out (NaN avg, 100% loss)"
+            ),
+            Some(Ping {
+                timestamp: "1611765997.71135",
+                target: "dns.google",
+                addr: "8.8.8.8",
+                seq: 0,
+                result: None,
+                avg: None,
+                loss: Some(100.0),
+                duplicate: false,
+                ttl: None,
+            })
+        );
+        // without -l fping does not print the running statistics
+        assert_eq!(
+            Ping::parse("[1611765997.71135] localhost (127.0.0.1) : [9], 64 bytes, 0.029 ms"),
+            Some(Ping {
+                timestamp: "1611765997.71135",
+                target: "localhost",
+                addr: "127.0.0.1",
+                seq: 9,
+                result: Some(Duration::from_micros(29)),
+                avg: None,
+                loss: None,
+                duplicate: false,
+                ttl: None,
             })
         );
     }
 
+    #[test]
+    fn detect_duplicate() {
+        assert_eq!(
+            Ping::parse("[1611765997.71135] localhost (127.0.0.1) : [9], 64 bytes, 0.031 ms (0.040 avg, 0% loss) (DUP!)"),
+            Some(Ping {
+                timestamp: "1611765997.71135",
+                target: "localhost",
+                addr: "127.0.0.1",
+                seq: 9,
+                result: Some(Duration::from_micros(31)),
+                avg: Some(0.040),
+                loss: Some(0.0),
+                duplicate: true,
+                ttl: None,
+            })
+        );
+        let ping = Ping::parse(
+            "[1611765997.71135] localhost (127.0.0.1) : [9], 64 bytes, 0.031 ms (DUP!)",
+        );
+        assert!(ping.unwrap().duplicate);
+    }
+
+    #[test]
+    fn reply_ttl() {
+        assert_eq!(
+            Ping::parse("[1611765997.71135] dns.google (8.8.8.8) : [3], 64 bytes, 18.3 ms (TTL 58) (18.1 avg, 0% loss)"),
+            Some(Ping {
+                timestamp: "1611765997.71135",
+                target: "dns.google",
+                addr: "8.8.8.8",
+                seq: 3,
+                result: Some(Duration::from_micros(18300)),
+                avg: Some(18.1),
+                loss: Some(0.0),
+                duplicate: false,
+                ttl: Some(58),
+            })
+        );
+        // fping could not read the TTL from the reply
+        let ping = Ping::parse("[1611765997.71135] dns.google (8.8.8.8) : [3], 64 bytes, 18.3 ms (TTL unknown) (18.1 avg, 0% loss)").unwrap();
+        assert_eq!((ping.ttl, ping.avg), (None, Some(18.1)));
+        let ping = Ping::parse(
+            "[1611765997.71135] dns.google (8.8.8.8) : [3], timed out (18.1 avg, 25% loss)",
+        )
+        .unwrap();
+        assert_eq!(ping.ttl, None);
+    }
+
     #[test]
     fn reject_native_rtt() {
         assert_eq!(Ping::parse("[1611765997.71135] localhost (127.0.0.1) : [9], 64 bytes, -7.4 ms (0.040 avg, 0% loss)"), None);
     }
 
+    #[test]
+    fn overridden_patterns() {
+        // output without the packet size
+        let ping = PatternOverrides::compile(
+            "ping-regex",
+            r"^\[(?P<ts>[^\]]+)\] (?P<id>\S+) \((?P<addr>[^)]+)\) : \[(?P<seq>\d+)\], (?:timed out|(?P<rtt>[\d.]+) ms)",
+            &PING_GROUPS,
+        )
+        .unwrap();
+        assert_eq!(
+            Ping::parse_with(
+                &ping,
+                "[1611765997.71135] localhost (127.0.0.1) : [9], 0.029 ms"
+            ),
+            Some(Ping {
+                timestamp: "1611765997.71135",
+                target: "localhost",
+                addr: "127.0.0.1",
+                seq: 9,
+                result: Some(Duration::from_micros(29)),
+                avg: None,
+                loss: None,
+                duplicate: false,
+                ttl: None,
+            })
+        );
+
+        let summary = PatternOverrides::compile(
+            "summary-regex",
+            r"^(?P<target>\S+) \((?P<addr>[^)]+)\) sent (?P<xmt>\d+) received (?P<rcv>\d+)",
+            &SUMMARY_GROUPS,
+        )
+        .unwrap();
+        assert_eq!(
+            Control::parse_status_with(&summary, "localhost (127.0.0.1) sent 10 received 9"),
+            Some(Control::TargetSummary(SentReceivedSummary {
+                target: "localhost",
+                addr: "127.0.0.1",
+                sent: 10,
+                received: 9,
+                loss: None,
+                min: None,
+                avg: None,
+                max: None
+            }))
+        );
+
+        assert!(matches!(
+            PatternOverrides::compile(
+                "summary-regex",
+                r"^(?P<target>\S+) (?P<xmt>\d+)/(?P<rcv>\d+)",
+                &SUMMARY_GROUPS
+            ),
+            Err(PatternError::MissingGroup("summary-regex", "addr"))
+        ));
+        assert!(matches!(
+            PatternOverrides::compile("ping-regex", "(", &PING_GROUPS),
+            Err(PatternError::Invalid(..))
+        ));
+    }
+
+    #[test]
+    fn detect_malformed_summary() {
+        let raw = "dns.google (8.8.4.4) : xmt/rcv/%loss = ?/104/0%, min/avg/max = 10.5/18.6/77.9";
+        assert_eq!(Control::parse(raw), Control::MalformedSummary(raw));
+        // ordinary fping errors are unaffected
+        assert_eq!(
+            Control::parse("dns.google: error while sending ping: No route to host"),
+            Control::FpingError {
+                target: "dns.google",
+                message: "error while sending ping: No route to host"
+            }
+        );
+    }
+
+    #[test]
+    fn detect_process_exit() {
+        assert_eq!(
+            Control::parse("fping: can't create socket (must run as root?)"),
+            Control::ProcessExit {
+                message: "can't create socket (must run as root?)"
+            }
+        );
+        assert_eq!(
+            Control::parse("/usr/local/sbin/fping: select : Bad file descriptor"),
+            Control::ProcessExit {
+                message: "select : Bad file descriptor"
+            }
+        );
+        // targets which merely contain the name
+        assert_eq!(
+            Control::parse("fping.example: Name or service not known"),
+            Control::FpingError {
+                target: "fping.example",
+                message: "Name or service not known"
+            }
+        );
+    }
+
+    #[test]
+    fn detect_icmp_redirect() {
+        assert_eq!(
+            Control::parse("ICMP Redirect from 192.168.1.1 for ICMP Echo sent to dns.google"),
+            Control::IcmpRedirect {
+                target: "dns.google",
+                addr: "192.168.1.1"
+            }
+        );
+        assert_eq!(
+            Control::parse("ICMP Time Exceeded from 10.0.0.1 for ICMP Echo sent to dns.google"),
+            Control::IcmpError {
+                target: "dns.google",
+                addr: "10.0.0.1",
+                error: "ICMP Time Exceeded"
+            }
+        );
+    }
+
+    #[test]
+    fn classify_icmp_errors() {
+        for (error, kind) in &[
+            ("ICMP Host Unreachable", "unreachable"),
+            ("ICMP Unreachable (Fragmentation Needed)", "unreachable"),
+            (
+                "ICMP Unreachable (Communication with Host Prohibited)",
+                "prohibited",
+            ),
+            ("ICMP Time Exceeded", "time_exceeded"),
+            ("ICMP Parameter Problem", "parameter_problem"),
+            ("ICMP Source Quench", "source_quench"),
+            ("ICMP Timestamp Reply", "other"),
+        ] {
+            assert_eq!(icmp_error_kind(error), *kind, "{}", error);
+        }
+    }
+
+    #[test]
+    fn scoped_addresses_and_unusual_names() {
+        for (line, target, addr) in &[
+            (
+                "[1611765997.71135] fe80::1%eth0 (fe80::1%eth0) : [0], 64 bytes, 0.05 ms (0.05 avg, 0% loss)",
+                "fe80::1%eth0",
+                "fe80::1%eth0",
+            ),
+            (
+                "[1611765997.71135] router.lan (fe80::1%br-lan.10) : [0], 64 bytes, 0.05 ms (0.05 avg, 0% loss)",
+                "router.lan",
+                "fe80::1%br-lan.10",
+            ),
+            (
+                "[1611765997.71135] odd(name)_1.example (192.0.2.1) : [0], timed out (NaN avg, 100% loss)",
+                "odd(name)_1.example",
+                "192.0.2.1",
+            ),
+            (
+                "[1611765997.71135] host (lab) (192.0.2.1) : [0], 64 bytes, 0.05 ms (0.05 avg, 0% loss)",
+                "host (lab)",
+                "192.0.2.1",
+            ),
+        ] {
+            let ping = Ping::parse(line).unwrap_or_else(|| panic!("{}", line));
+            assert_eq!((ping.target, ping.addr), (*target, *addr));
+        }
+
+        assert!(matches!(
+            Control::parse("fe80::1%eth0 (fe80::1%eth0) : xmt/rcv/%loss = 3/3/0%, min/avg/max = 0.04/0.05/0.06"),
+            Control::TargetSummary(SentReceivedSummary {
+                target: "fe80::1%eth0",
+                addr: "fe80::1%eth0",
+                sent: 3,
+                ..
+            })
+        ));
+        assert!(matches!(
+            Control::parse("odd(name)_1.example (192.0.2.1) : xmt/rcv/%loss = 3/0/100%"),
+            Control::TargetSummary(SentReceivedSummary {
+                target: "odd(name)_1.example",
+                addr: "192.0.2.1",
+                ..
+            })
+        ));
+        assert_eq!(
+            Control::parse("fe80::1%eth0: error while sending ping: No route to host"),
+            Control::FpingError {
+                target: "fe80::1%eth0",
+                message: "error while sending ping: No route to host"
+            }
+        );
+        assert_eq!(
+            Control::parse(
+                "ICMP Host Unreachable from fe80::1%eth0 for ICMP Echo sent to fe80::2%eth0"
+            ),
+            Control::IcmpError {
+                target: "fe80::2%eth0",
+                addr: "fe80::1%eth0",
+                error: "ICMP Host Unreachable"
+            }
+        );
+
+        for garbage in &[
+            "(((",
+            "[1611765997.71135] ) (",
+            "fe80::1%eth0 (fe80::1%eth0",
+        ] {
+            assert!(Ping::parse(*garbage).is_none());
+            assert_eq!(Control::parse(*garbage), Control::Unhandled(*garbage));
+        }
+    }
+
     #[test]
     fn parse_signal_summary() {
         assert_eq!(parse_lines(
@@ -231,31 +719,51 @@ mod tests {
                 target: "dns.google",
                 addr: "8.8.4.4",
                 sent: 104,
-                received: 104
+                received: 104,
+                loss: Some(0),
+                min: Some(10.5),
+                avg: Some(18.6),
+                max: Some(77.9)
             }),
             Control::TargetSummary(SentReceivedSummary  {
                 target: "localhost",
                 addr: "127.0.0.1",
                 sent: 104,
-                received: 104
+                received: 104,
+                loss: Some(0),
+                min: Some(0.025),
+                avg: Some(0.063),
+                max: Some(0.189)
             }),
             Control::TargetSummary(SentReceivedSummary  {
                 target: "8.8.8.7",
                 addr: "8.8.8.7",
                 sent: 0,
-                received: 0
+                received: 0,
+                loss: Some(0),
+                min: None,
+                avg: None,
+                max: None
             }),
             Control::TargetSummary(SentReceivedSummary  {
                 target: "ipv6.google.com",
                 addr: "2a00:1450:400e:806::200e",
                 sent: 104,
-                received: 0
+                received: 0,
+                loss: Some(100),
+                min: None,
+                avg: None,
+                max: None
             }),
             Control::TargetSummary(SentReceivedSummary  {
                 target: "ns1.webtraf.com.au",
                 addr: "103.224.162.40",
                 sent: 104,
-                received: 104
+                received: 104,
+                loss: Some(0),
+                min: Some(338.0),
+                avg: Some(346.0),
+                max: Some(461.0)
             }),
         ]);
     }
@@ -29,7 +29,7 @@ fn parse_fping_version(raw: &str) -> Option<semver::Version> {
 pub enum VersionError {
     #[error("could not extract version data from output:\n{0}")]
     UnknownFormat(String),
-    #[error("fping was not found in FPING_BIN or PATH")]
+    #[error("fping was not found, check --fping-bin, FPING_BIN or PATH")]
     BinaryNotFound,
     #[error("libc failure, required file /etc/protocols missing")]
     DependenciesMissing,
@@ -57,13 +57,13 @@ pub(crate) fn output_to_version(
     trace!(target: "fping", "version: {:?}", output);
     match output.status.code() {
         Some(0) => {
-            let raw = std::str::from_utf8(&output.stdout).unwrap();
-            parse_fping_version(raw).ok_or_else(|| VersionError::UnknownFormat(raw.to_string()))
+            let raw = String::from_utf8_lossy(&output.stdout);
+            parse_fping_version(&raw).ok_or_else(|| VersionError::UnknownFormat(raw.into_owned()))
         }
         Some(4) => Err(VersionError::DependenciesMissing),
         _ => Err(VersionError::ProcessFailure(
             output.status,
-            String::from_utf8(output.stdout).unwrap(),
+            String::from_utf8_lossy(&output.stdout).into_owned(),
         )),
     }
 }
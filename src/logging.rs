@@ -0,0 +1,88 @@
+use std::{
+    io::{self, Write},
+    time::SystemTime,
+};
+
+use env_logger::filter::{Builder, Filter};
+use log::{Log, Metadata, Record};
+
+use crate::args::LogFormat;
+
+/// Writes every record as a single line JSON object for log aggregators,
+/// `RUST_LOG` filters the same way it does for the pretty format.
+struct JsonLogger {
+    filter: Filter,
+}
+
+/// Multiline messages such as unhandled fping output stay on one line, the
+/// newlines are escaped
+fn format_record(record: &Record, time: SystemTime) -> String {
+    serde_json::json!({
+        "timestamp": humantime::format_rfc3339_millis(time).to_string(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    })
+    .to_string()
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.filter.matches(record) {
+            let line = format_record(record, SystemTime::now());
+            let _ = writeln!(io::stderr().lock(), "{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+pub fn init(format: LogFormat) {
+    match format {
+        LogFormat::Pretty => pretty_env_logger::init(),
+        LogFormat::Json => {
+            let filter = Builder::from_env("RUST_LOG").build();
+            log::set_max_level(filter.filter());
+            log::set_boxed_logger(Box::new(JsonLogger { filter }))
+                .expect("a logger was already installed");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use log::Level;
+
+    use super::*;
+
+    #[test]
+    fn records_are_single_lines() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_611_765_997_711);
+        let line = format_record(
+            &Record::builder()
+                .level(Level::Error)
+                .target("fping_exporter")
+                .args(format_args!(
+                    "unhandled stderr: {}",
+                    "dns.google: \"odd\"\n\tcontinued"
+                ))
+                .build(),
+            time,
+        );
+        assert_eq!(
+            line,
+            concat!(
+                r#"{"level":"ERROR","message":"unhandled stderr: dns.google: \"odd\"\n\tcontinued","#,
+                r#""target":"fping_exporter","timestamp":"2021-01-27T16:46:37.711Z"}"#,
+            )
+        );
+    }
+}
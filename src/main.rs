@@ -11,24 +11,35 @@ extern crate log;
 extern crate clap;
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     convert::Infallible,
     env, io,
     marker::PhantomData,
-    sync::{Arc, Mutex},
-    time::Duration,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use anyhow::Context;
 use clap::crate_version;
 use prom::{LockedCollector, PingMetrics};
 use prometheus::{labels, opts};
-use semver::VersionReq;
-use tokio::sync::oneshot;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, oneshot, watch},
+};
 
 mod args;
 mod event_stream;
+mod exit;
 mod fping;
+mod logging;
 mod prom;
+mod selftest;
+mod targets;
 
 use crate::event_stream::util::{
     lock::{Claim, LockControl},
@@ -53,6 +64,124 @@ async fn terminate_signal() -> Option<&'static str> {
     tokio::signal::ctrl_c().await.ok().map(|_| "SIGINT")
 }
 
+/// Why the exporter stopped, always logged as the final line
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ShutdownReason {
+    Signal(&'static str),
+    SignalHandlerFailed,
+    FpingExited,
+    /// fping sent every ping requested with --count
+    CountComplete,
+    TextfileFailed,
+    RuntimeLimit,
+    BindFailure,
+    TotalLoss,
+}
+
+impl ShutdownReason {
+    fn label(self) -> &'static str {
+        match self {
+            ShutdownReason::Signal(_) => "signal",
+            ShutdownReason::SignalHandlerFailed => "signal_handler_failed",
+            ShutdownReason::FpingExited => "fping_exited",
+            ShutdownReason::CountComplete => "count_complete",
+            ShutdownReason::TextfileFailed => "textfile_failed",
+            ShutdownReason::RuntimeLimit => "runtime_limit",
+            ShutdownReason::BindFailure => "bind_failure",
+            ShutdownReason::TotalLoss => "total_loss",
+        }
+    }
+
+    fn is_failure(self) -> bool {
+        !matches!(
+            self,
+            ShutdownReason::Signal(_)
+                | ShutdownReason::RuntimeLimit
+                | ShutdownReason::CountComplete
+        )
+    }
+
+    /// Only registered once shutting down, so the final textfile output
+    /// records why the exporter stopped.
+    fn register_metric(self, namespace: &str) -> prometheus::Result<()> {
+        let metric = prometheus::IntGaugeVec::new(
+            opts!(
+                "shutdown_reason",
+                "reason the exporter shut down, only present in the final output"
+            )
+            .namespace(namespace),
+            &["reason"],
+        )?;
+        metric.with_label_values(&[self.label()]).set(1);
+        prometheus::register(Box::new(metric))
+    }
+}
+
+/// Signals are encoded as their negated number, exit codes are never negative.
+fn exit_code(status: std::process::ExitStatus) -> i64 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return -i64::from(signal);
+        }
+    }
+    status.code().map_or(i64::MIN, i64::from)
+}
+
+/// Only registered if fping exits on its own, a clean shutdown has no code to report.
+fn register_exit_code(status: std::process::ExitStatus, namespace: &str) -> prometheus::Result<()> {
+    let metric = prometheus::IntGauge::with_opts(
+        opts!(
+            "last_exit_code",
+            "exit code of fping after it exited unexpectedly, negative signal number if it was killed"
+        )
+        .namespace(namespace),
+    )?;
+    metric.set(exit_code(status));
+    prometheus::register(Box::new(metric))
+}
+
+/// Why the main loop woke up without shutting down
+#[derive(Debug)]
+enum Wakeup {
+    /// SIGHUP, or POST /-/reload which waits for the outcome, changed the
+    /// targets and their labels
+    Reload(
+        Vec<String>,
+        HashMap<String, targets::TargetLabels>,
+        Option<prom::ReloadRequest>,
+    ),
+    /// fping exited unexpectedly and will be respawned
+    Exited,
+    Respawn,
+}
+
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+/// Doubles for each consecutive restart of fping
+fn restart_delay(restarts: u32) -> Duration {
+    MIN_RESTART_DELAY
+        .checked_mul(1 << restarts.min(31))
+        .map_or(MAX_RESTART_DELAY, |delay| delay.min(MAX_RESTART_DELAY))
+}
+
+impl std::fmt::Display for ShutdownReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShutdownReason::Signal(signal) => write!(f, "received {}", signal),
+            ShutdownReason::SignalHandlerFailed => f.write_str("failed to register signal handler"),
+            ShutdownReason::FpingExited => f.write_str("fping exited"),
+            ShutdownReason::CountComplete => f.write_str("fping finished pinging"),
+            ShutdownReason::TextfileFailed => f.write_str("textfile output failed"),
+            ShutdownReason::RuntimeLimit => f.write_str("runtime limit reached"),
+            ShutdownReason::BindFailure => f.write_str("unable to bind metrics server"),
+            ShutdownReason::TotalLoss => f.write_str("no target responded"),
+        }
+    }
+}
+
 #[cfg(debug_assertions)]
 fn discovery_timeout() -> Duration {
     humantime::parse_duration(option_env!("DEV_PROGRAM_TIMEOUT").unwrap_or("50ms"))
@@ -65,44 +194,435 @@ fn discovery_timeout() -> Duration {
     Duration::from_millis(50)
 }
 
+/// Passes one in every `every` per-ping trace messages, at high ping rates
+/// logging them all makes logging itself the bottleneck.
+#[derive(Debug)]
+struct TraceSampler {
+    every: u64,
+    seen: AtomicU64,
+}
+
+impl TraceSampler {
+    fn new(every: u64) -> Self {
+        Self {
+            every: std::cmp::max(every, 1),
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    fn sample(&self) -> bool {
+        log_enabled!(log::Level::Trace)
+            && self
+                .seen
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(self.every)
+    }
+}
+
+/// Counters for the heartbeat log, atomics so it never waits on the
+/// metrics lock
+#[derive(Debug, Default)]
+struct Vitals {
+    pings: AtomicU64,
+    targets_up: AtomicU64,
+    targets_down: AtomicU64,
+}
+
+impl Vitals {
+    async fn heartbeat(self: Arc<Self>, period: Duration) {
+        let mut interval = tokio::time::interval(period);
+        // the first tick completes immediately
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            info!(
+                target: "heartbeat",
+                "{} targets up, {} down, {} pings processed",
+                self.targets_up.load(Ordering::Relaxed),
+                self.targets_down.load(Ordering::Relaxed),
+                self.pings.load(Ordering::Relaxed)
+            );
+        }
+    }
+}
+
+/// Fires once no target has responded for `threshold`
+#[derive(Debug)]
+struct TotalLossWatch {
+    threshold: Duration,
+    last_reply: Instant,
+    tx: Option<oneshot::Sender<()>>,
+}
+
+impl TotalLossWatch {
+    fn observe(&mut self, replied: bool) {
+        let now = Instant::now();
+        if replied {
+            self.last_reply = now;
+        } else if now.duration_since(self.last_reply) >= self.threshold {
+            if let Some(tx) = self.tx.take() {
+                error!(
+                    "no target responded for {}, exiting",
+                    humantime::format_duration(self.threshold)
+                );
+                let _ = tx.send(());
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 struct MetricsState<T, P> {
-    last_result: HashMap<String, f64>,
+    // one-way delay of the last reply from each address of a target
+    last_result: HashMap<(String, String), f64>,
+    // whether to calculate the packet delay variation at all
+    ipdv: bool,
+    // per target start of the current measurement window
+    window_start: HashMap<String, Instant>,
+    measurement_window: Option<Duration>,
+    // configured targets that have not been confirmed by fping yet
+    unconfirmed_targets: Option<HashSet<String>>,
+    // addresses can outnumber targets when fping pings all of them (-m),
+    // along with the pings observed for each since their last summary
+    known_addresses: HashMap<(String, String), u32>,
     expected_targets: u32,
+    // number of targets fping generates itself, which restarts keep
+    generated_targets: Option<u32>,
     current_targets: u32,
+    // summaries seen since the current summary block started
+    summarized: HashSet<(String, String)>,
     held_token: Option<T>,
+    // when the held token was handed out, for summary_block_timeout
+    summary_requested: Option<Instant>,
+    summary_block_timeout: Option<Duration>,
+    // pings per target still to be kept out of the histograms
+    warmup: HashMap<String, u64>,
+    warmup_samples: u64,
     metrics: Arc<Mutex<PingMetrics>>,
+    trace_sampler: TraceSampler,
+    total_loss: Option<TotalLossWatch>,
+    // whether the last ping of each target got a reply
+    target_up: HashMap<String, bool>,
+    // why fping said it is exiting, if it did
+    exit_message: Option<String>,
+    vitals: Arc<Vitals>,
     _marker: PhantomData<P>,
 }
 
 impl<T, P> MetricsState<T, P> {
-    fn new(metrics: Arc<Mutex<PingMetrics>>) -> Self {
+    fn new<S: AsRef<str>>(metrics: Arc<Mutex<PingMetrics>>, targets: &[S]) -> Self {
         Self {
             last_result: HashMap::default(),
-            expected_targets: 1,
+            ipdv: true,
+            window_start: HashMap::default(),
+            measurement_window: None,
+            unconfirmed_targets: Some(targets.iter().map(|t| t.as_ref().to_owned()).collect()),
+            known_addresses: HashMap::default(),
+            expected_targets: std::cmp::max(targets.len() as u32, 1),
+            generated_targets: None,
             current_targets: 0,
+            summarized: HashSet::default(),
             held_token: None,
+            summary_requested: None,
+            summary_block_timeout: None,
+            warmup: HashMap::default(),
+            warmup_samples: 0,
             metrics,
+            trace_sampler: TraceSampler::new(1),
+            total_loss: None,
+            target_up: HashMap::default(),
+            exit_message: None,
+            vitals: Arc::default(),
             _marker: PhantomData,
         }
     }
 
-    fn calc_ipdv(&mut self, target: &str, rtt: Duration) -> Option<f64> {
+    fn with_trace_sampling(self, every: u64) -> Self {
+        Self {
+            trace_sampler: TraceSampler::new(every),
+            ..self
+        }
+    }
+
+    fn with_total_loss_exit(self, threshold: Duration, tx: oneshot::Sender<()>) -> Self {
+        Self {
+            total_loss: Some(TotalLossWatch {
+                threshold,
+                last_reply: Instant::now(),
+                tx: Some(tx),
+            }),
+            ..self
+        }
+    }
+
+    fn with_measurement_window(self, measurement_window: Option<Duration>) -> Self {
+        Self {
+            measurement_window,
+            ..self
+        }
+    }
+
+    /// Overrides the number of targets, for targets fping generates itself.
+    /// Without it the count is learned from the addresses fping pings.
+    fn with_expected_targets(self, expected: Option<u32>) -> Self {
+        match expected {
+            Some(expected) => Self {
+                expected_targets: std::cmp::max(expected, 1),
+                generated_targets: Some(expected),
+                ..self
+            },
+            None => self,
+        }
+    }
+
+    fn with_ipdv(self, ipdv: bool) -> Self {
+        Self { ipdv, ..self }
+    }
+
+    fn with_warmup_samples(self, warmup_samples: u64) -> Self {
+        Self {
+            warmup_samples,
+            ..self
+        }
+    }
+
+    /// The first pings to a target are often slow due to ARP/ND resolution
+    fn warming_up(&mut self, target: &str) -> bool {
+        if self.warmup_samples == 0 {
+            return false;
+        }
+        let remaining = self
+            .warmup
+            .entry(target.to_owned())
+            .or_insert(self.warmup_samples);
+        match remaining.checked_sub(1) {
+            Some(left) => {
+                *remaining = left;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn with_summary_block_timeout(self, summary_block_timeout: Option<Duration>) -> Self {
+        Self {
+            summary_block_timeout,
+            ..self
+        }
+    }
+
+    /// Forgets the state derived from earlier pings once the window of a
+    /// target has elapsed, the exported counters and histograms are
+    /// cumulative and unaffected.
+    fn check_window(&mut self, target: &str) {
+        let window = match self.measurement_window {
+            Some(window) => window,
+            None => return,
+        };
+        let now = Instant::now();
+        match self.window_start.get_mut(target) {
+            Some(start) if now.duration_since(*start) >= window => {
+                trace!("measurement window of {} elapsed", target);
+                *start = now;
+                self.last_result.retain(|(t, _), _| t != target);
+            }
+            Some(_) => {}
+            None => {
+                self.window_start.insert(target.to_owned(), now);
+            }
+        }
+    }
+
+    fn vitals(&self) -> Arc<Vitals> {
+        self.vitals.clone()
+    }
+
+    fn update_vitals(&mut self, target: &str, up: bool) {
+        self.vitals.pings.fetch_add(1, Ordering::Relaxed);
+        let previous = match self.target_up.get_mut(target) {
+            Some(status) => Some(std::mem::replace(status, up)),
+            None => {
+                self.target_up.insert(target.to_owned(), up);
+                None
+            }
+        };
+        if previous != Some(up) {
+            let counter = |up| {
+                if up {
+                    &self.vitals.targets_up
+                } else {
+                    &self.vitals.targets_down
+                }
+            };
+            counter(up).fetch_add(1, Ordering::Relaxed);
+            if let Some(previous) = previous {
+                counter(previous).fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// fping reports targets it cannot resolve during startup and then
+    /// silently drops them, they will never show up in a summary.
+    fn check_skipped(&mut self, target: &str, message: &str) -> bool {
+        let skipped = !message.starts_with("error while sending ping")
+            && self
+                .unconfirmed_targets
+                .as_mut()
+                .is_some_and(|targets| targets.remove(target));
+        if skipped {
+            warn!("fping skipped {}: {}", target, message);
+            self.expected_targets = self.expected_targets.saturating_sub(1);
+            self.metrics.lock().unwrap().skipped(target);
+        }
+        skipped
+    }
+
+    /// Every address fping pings gets its own summary line
+    fn observe_address(&mut self, target: &str, addr: &str) {
+        let known = self.known_addresses.len();
+        *self
+            .known_addresses
+            .entry((target.to_owned(), addr.to_owned()))
+            .or_default() += 1;
+        if self.known_addresses.len() > known {
+            self.expected_targets =
+                std::cmp::max(self.expected_targets, self.known_addresses.len() as u32);
+        }
+    }
+
+    /// Pings observed on stdout since the previous summary of this address,
+    /// these should match the packets fping reports as sent.
+    fn take_observed(&mut self, target: &str, addr: &str) -> u32 {
+        self.known_addresses
+            .get_mut(&(target.to_owned(), addr.to_owned()))
+            .map_or(0, std::mem::take)
+    }
+
+    fn start_summary_cycle(&mut self) {
+        // Reset expected targets
+        self.expected_targets = std::cmp::max(self.expected_targets, self.current_targets);
+        self.metrics
+            .lock()
+            .unwrap()
+            .summary_cycle(self.current_targets, self.expected_targets);
+        self.current_targets = 0;
+        self.summarized.clear();
+    }
+
+    /// Some versions of fping do not print the local time before every
+    /// summary block, a target being summarized twice also means a new
+    /// block has started.
+    fn check_new_cycle(&mut self, target: &str, addr: &str) {
+        if !self.summarized.insert((target.to_owned(), addr.to_owned())) {
+            debug!(
+                "{} ({}) summarized twice, assuming a new summary",
+                target, addr
+            );
+            self.start_summary_cycle();
+            self.summarized.insert((target.to_owned(), addr.to_owned()));
+        }
+    }
+
+    /// Addresses of a target are different paths, each varies on its own
+    fn calc_ipdv(&mut self, target: &str, addr: &str, rtt: Duration) -> Option<f64> {
         let one_way_delay = rtt.div_f64(2.0).as_secs_f64();
-        match self.last_result.get_mut(target) {
+        match self
+            .last_result
+            .get_mut(&(target.to_owned(), addr.to_owned()))
+        {
             Some(prev) => {
                 let delta = (*prev - one_way_delay).abs();
                 *prev = one_way_delay;
                 Some(delta)
             }
             None => {
-                self.last_result.insert(target.to_owned(), one_way_delay);
+                self.last_result
+                    .insert((target.to_owned(), addr.to_owned()), one_way_delay);
                 None
             }
         }
     }
 }
 
+impl<T: OnSummaryComplete, P> MetricsState<T, P> {
+    fn target_summarized(&mut self) {
+        self.current_targets += 1;
+        trace!(
+            "{} out of {} targets summarized",
+            self.current_targets,
+            self.expected_targets
+        );
+        if self.current_targets == self.expected_targets {
+            if let Some(token) = self.held_token.take() {
+                self.summary_requested = None;
+                token.on_completed();
+            } else {
+                warn!("summary received, but no token held")
+            }
+        }
+    }
+
+    /// Targets fping never summarizes would otherwise hold the token, and
+    /// with it every scrape, forever. Checked on every event since fping
+    /// keeps printing pings while a summary is outstanding.
+    fn check_summary_timeout(&mut self) {
+        let (timeout, requested) = match (self.summary_block_timeout, self.summary_requested) {
+            (Some(timeout), Some(requested)) => (timeout, requested),
+            _ => return,
+        };
+        if requested.elapsed() < timeout {
+            return;
+        }
+        self.summary_requested = None;
+        if let Some(token) = self.held_token.take() {
+            warn!(
+                "only {} out of {} targets summarized within {}, releasing the summary",
+                self.current_targets,
+                self.expected_targets,
+                humantime::format_duration(timeout)
+            );
+            self.metrics.lock().unwrap().incomplete_summary();
+            token.on_completed();
+        }
+    }
+
+    /// fping has been restarted with `targets`, state tied to the previous
+    /// process is dropped along with everything about the `removed` targets.
+    fn retarget<S: AsRef<str>>(&mut self, targets: &[S], removed: &[String]) {
+        // the previous process will never finish the summary
+        self.summary_requested = None;
+        if let Some(token) = self.held_token.take() {
+            token.on_completed();
+        }
+        self.unconfirmed_targets = Some(targets.iter().map(|t| t.as_ref().to_owned()).collect());
+        self.known_addresses.clear();
+        let expected = self.generated_targets.unwrap_or(targets.len() as u32);
+        self.expected_targets = std::cmp::max(expected, 1);
+        self.current_targets = 0;
+        self.summarized.clear();
+        // the delay variation should not span the restart
+        self.last_result.clear();
+        self.exit_message = None;
+
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.clear_skipped();
+        metrics.reset_sequences();
+        for target in removed {
+            self.window_start.remove(target);
+            self.warmup.remove(target);
+            if let Some(up) = self.target_up.remove(target) {
+                let counter = if up {
+                    &self.vitals.targets_up
+                } else {
+                    &self.vitals.targets_down
+                };
+                counter.fetch_sub(1, Ordering::Relaxed);
+            }
+            metrics.remove_target(target);
+        }
+    }
+}
+
 trait OnSummaryComplete {
     fn on_completed(self);
 
@@ -142,19 +662,57 @@ impl<O: AsRef<str>, E: AsRef<str>, H, T: OnSummaryComplete> event_stream::EventH
     type Token = T;
 
     fn on_output(&mut self, event: Self::Output) {
-        if let Some(ping) = fping::Ping::parse(&event) {
-            let labels = ping.labels();
-            let delta = if let Some(rtt) = ping.result {
-                let delta = self.calc_ipdv(ping.target, rtt);
+        // fping has finished resolving targets once it starts pinging
+        self.unconfirmed_targets = None;
 
-                trace!("rtt {:?} on {:?}", ping.result, labels);
-                trace!("ipvd {:?} on {:?}", delta, labels);
-                delta
+        let ping = fping::Ping::parse(&event);
+        self.metrics.lock().unwrap().line("stdout", ping.is_some());
+        if let Some(
+            ping @ fping::Ping {
+                duplicate: true, ..
+            },
+        ) = ping
+        {
+            // says nothing new about the target, only counted
+            if self.trace_sampler.sample() {
+                trace!("duplicate reply on {:?}", ping.labels());
+            }
+            self.metrics.lock().unwrap().ping(ping, None);
+        } else if let Some(ping) = ping {
+            self.observe_address(ping.target, ping.addr);
+            let labels = ping.labels();
+            let sampled = self.trace_sampler.sample();
+            self.check_window(ping.target);
+            if let Some(watch) = self.total_loss.as_mut() {
+                watch.observe(ping.result.is_some());
+            }
+            self.update_vitals(ping.target, ping.result.is_some());
+            if self.warming_up(ping.target) {
+                if sampled {
+                    trace!("warmup ping {:?} on {:?}", ping.result, labels);
+                }
+                self.metrics.lock().unwrap().warmup_ping(ping);
             } else {
-                trace!("timeout on {:?}", labels);
-                None
-            };
-            self.metrics.lock().unwrap().ping(ping, delta);
+                let delta = if let Some(rtt) = ping.result {
+                    let delta = if self.ipdv {
+                        self.calc_ipdv(ping.target, ping.addr, rtt)
+                    } else {
+                        None
+                    };
+
+                    if sampled {
+                        trace!("rtt {:?} on {:?}", ping.result, labels);
+                        trace!("ipvd {:?} on {:?}", delta, labels);
+                    }
+                    delta
+                } else {
+                    if sampled {
+                        trace!("timeout on {:?}", labels);
+                    }
+                    None
+                };
+                self.metrics.lock().unwrap().ping(ping, delta);
+            }
         } else {
             error!("unhandled stdout: {}", event.as_ref());
         }
@@ -165,12 +723,21 @@ impl<O: AsRef<str>, E: AsRef<str>, H, T: OnSummaryComplete> event_stream::EventH
                 self.held_token = None;
             }
         }
+        self.check_summary_timeout();
     }
 
     fn on_error(&mut self, event: Self::Error) {
         use fping::Control;
 
-        match Control::parse(&event) {
+        let control = Control::parse(&event);
+        // blank lines and other expected output still count as parsed
+        let parsed = !matches!(
+            control,
+            Control::Unhandled(_) | Control::MalformedSummary(_)
+        );
+        self.metrics.lock().unwrap().line("stderr", parsed);
+
+        match control {
             Control::TargetSummary(summary) => {
                 trace!(
                     "packet loss ({}/{}) on {:?}",
@@ -178,127 +745,1308 @@ impl<O: AsRef<str>, E: AsRef<str>, H, T: OnSummaryComplete> event_stream::EventH
                     summary.sent,
                     summary.labels()
                 );
-                self.metrics.lock().unwrap().summary(summary);
-                self.current_targets += 1;
-                trace!(
-                    "{} out of {} targets summarized",
-                    self.current_targets,
-                    self.expected_targets
-                );
-                if self.current_targets == self.expected_targets {
-                    if let Some(token) = self.held_token.take() {
-                        token.on_completed();
-                    } else {
-                        warn!("summary received, but no token held")
-                    }
-                }
+                self.check_new_cycle(summary.target, summary.addr);
+                let observed = self.take_observed(summary.target, summary.addr);
+                let metrics = self.metrics.lock().unwrap();
+                metrics.observed_drift(&summary, observed);
+                metrics.summary(summary);
+                drop(metrics);
+                self.target_summarized();
+            }
+            Control::MalformedSummary(raw) => {
+                warn!("malformed summary: {}", raw);
+                self.metrics
+                    .lock()
+                    .unwrap()
+                    .error(Control::MalformedSummary(raw));
+                // still part of the summary block
+                self.target_summarized();
             }
             Control::SummaryLocalTime => {
                 if self.held_token.is_none() {
                     warn!("summary manually triggered, may race with metrics output");
                 }
 
-                // Reset expected targets
-                self.expected_targets = std::cmp::max(self.expected_targets, self.current_targets);
-                self.current_targets = 0;
+                self.start_summary_cycle();
             }
             Control::Unhandled(err) => {
                 debug!("unexpected stderr:\n{}", err);
             }
+            Control::ProcessExit { message } => {
+                error!("fping is exiting: {}", message);
+                self.exit_message = Some(message.to_owned());
+            }
+            Control::FpingError { target, message } if self.check_skipped(target, message) => {}
             e => {
                 trace!("ignored output: {:?}", e);
                 self.metrics.lock().unwrap().error(e);
             }
         }
+        self.check_summary_timeout();
     }
 
     fn on_control(&mut self, _: &mut Self::Handle, token: Self::Token) -> io::Result<()> {
         trace!("control token received");
         self.held_token = Some(token);
+        self.summary_requested = Some(Instant::now());
         Ok(())
     }
+
+    fn on_oversized_line(&mut self, stream: &str) {
+        self.metrics.lock().unwrap().oversized_line(stream);
+    }
 }
 
-fn info_metric(ver: semver::Version) -> Box<dyn prometheus::core::Collector> {
+fn info_metric(ver: semver::Version, namespace: &str) -> Box<dyn prometheus::core::Collector> {
     let ver = ver.to_string();
-    let metric = prometheus::Counter::with_opts(opts!(
-        "fping_info",
-        "exporter runtime information",
-        labels! {
-            "version" => crate_version!(),
-            "fping_version" => &ver
-        }
-    ))
+    let metric = prometheus::Counter::with_opts(
+        opts!(
+            "info",
+            "exporter runtime information",
+            labels! {
+                "version" => crate_version!(),
+                "fping_version" => &ver
+            }
+        )
+        .namespace(namespace),
+    )
     .unwrap();
     metric.inc();
     Box::new(metric)
 }
 
+fn packet_size_metric(size: u16, namespace: &str) -> Box<dyn prometheus::core::Collector> {
+    let metric = prometheus::IntGauge::with_opts(
+        opts!("packet_size_bytes", "ICMP data bytes sent with each ping").namespace(namespace),
+    )
+    .unwrap();
+    metric.set(size.into());
+    Box::new(metric)
+}
+
+/// Largest MTU among the interfaces other than loopback
+fn largest_mtu() -> Option<u32> {
+    std::fs::read_dir("/sys/class/net")
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|interface| interface.file_name() != "lo")
+        .filter_map(|interface| std::fs::read_to_string(interface.path().join("mtu")).ok())
+        .filter_map(|mtu| mtu.trim().parse().ok())
+        .max()
+}
+
+/// Whether pings with `size` data bytes fit within `mtu` without being
+/// fragmented, IPv6 carries a larger header.
+fn fits_mtu(size: u16, family: Option<fping::AddressFamily>, mtu: u32) -> bool {
+    let headers = match family {
+        Some(fping::AddressFamily::Ipv6) => 40 + 8,
+        _ => 20 + 8,
+    };
+    u32::from(size) + headers <= mtu
+}
+
+fn targets_metric(
+    targets: &[String],
+    namespace: &str,
+) -> prometheus::Result<prometheus::IntGaugeVec> {
+    let metric = prometheus::IntGaugeVec::new(
+        opts!(
+            "targets_by_type",
+            "configured targets which are ip addresses or hostnames fping has to resolve"
+        )
+        .namespace(namespace),
+        &["type"],
+    )?;
+    count_targets(&metric, targets);
+    Ok(metric)
+}
+
+fn count_targets(metric: &prometheus::IntGaugeVec, targets: &[String]) {
+    for target_type in &["ip", "hostname"] {
+        metric.with_label_values(&[target_type]).set(0);
+    }
+    for target in targets {
+        metric
+            .with_label_values(&[targets::target_type(target)])
+            .inc();
+    }
+}
+
+/// Rebuilds the target list and its labels with the current contents of
+/// the target file and targets url. `fetched` holds what the url served
+/// last, which is kept if it cannot be fetched again.
+async fn reload_targets(
+    args: &args::Args,
+    fetched: &mut Vec<String>,
+) -> anyhow::Result<(Vec<String>, HashMap<String, targets::TargetLabels>)> {
+    let mut targets = args.static_targets.clone();
+    let mut labels = HashMap::new();
+    if let Some(url) = &args.targets_url {
+        match targets::fetch_targets(url).await {
+            Ok(refetched) => *fetched = refetched,
+            Err(e) => warn!("{}, keeping the previously fetched targets", e),
+        }
+        targets::merge_targets(&mut targets, fetched.clone());
+    }
+    if let Some(path) = &args.target_file {
+        let (listed, listed_labels) =
+            args::split_target_labels(args::read_target_file(path).await?);
+        targets::merge_targets(&mut targets, listed);
+        labels = listed_labels;
+    }
+    if targets.is_empty() {
+        return Err(args::ArgsError::NoTargets.into());
+    }
+    if args.shuffle_targets {
+        targets::shuffle_targets(&mut targets, args.seed);
+    }
+    Ok((targets, labels))
+}
+
+/// Handles SIGHUP or POST /-/reload up to restarting fping, which is only
+/// needed if the targets changed. Those are returned, anything else has
+/// already been answered.
+async fn reload(
+    args: &args::Args,
+    fetched: &mut Vec<String>,
+    target_info: &prom::TargetInfo,
+    responder: Option<prom::ReloadRequest>,
+) -> Option<Wakeup> {
+    let outcome = match reload_targets(args, fetched).await {
+        Ok((targets, labels))
+            if targets.iter().collect::<HashSet<_>>() == args.targets.iter().collect() =>
+        {
+            info!("targets unchanged, fping keeps running");
+            // annotations may have changed regardless
+            target_info.set_labels(&labels);
+            Ok(())
+        }
+        Ok((targets, labels)) => return Some(Wakeup::Reload(targets, labels, responder)),
+        Err(e) => {
+            error!(
+                "unable to reload targets, keeping the previous targets: {:#}",
+                e
+            );
+            Err(format!("unable to reload targets: {}", e))
+        }
+    };
+    if let Some(responder) = responder {
+        let _ = responder.send(outcome);
+    }
+    None
+}
+
+/// Time until the wall clock is next a multiple of `interval`
+fn alignment_delay(now: SystemTime, interval: Duration) -> Duration {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+    match since_epoch.as_nanos() % interval.as_nanos().max(1) {
+        0 => Duration::ZERO,
+        // the remainder is smaller than interval, so this fits
+        elapsed => interval - Duration::from_nanos(elapsed as u64),
+    }
+}
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Asks the exporter at `addr` whether fping is running, answering with
+/// the body of its health endpoint. Scraping the metrics would request a
+/// summary from fping and compete with prometheus for it.
+async fn health_check(mut addr: SocketAddr, tls: bool) -> anyhow::Result<String> {
+    // an exporter bound to every address is reachable through loopback
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    let url = format!("{}://{}/health", if tls { "https" } else { "http" }, addr);
+    let check = async {
+        reqwest::Client::builder()
+            .timeout(HEALTH_CHECK_TIMEOUT)
+            // the certificate names the exporter, not the loopback address
+            .danger_accept_invalid_certs(true)
+            .build()?
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await
+    };
+    check
+        .await
+        .with_context(|| format!("health check of {} failed", url))
+}
+
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    pretty_env_logger::init();
-    let fping_binary = env::var("FPING_BIN").unwrap_or_else(|_| "fping".into());
-    let launcher = fping::for_program(&fping_binary);
-    let args = args::load_args(&launcher, discovery_timeout()).await?;
+async fn main() {
+    logging::init(args::log_format());
+    if let Err(e) = run().await {
+        eprintln!("Error: {:?}", e);
+        exit::FailureClass::of(&e).exit();
+    }
+}
+
+async fn run() -> anyhow::Result<()> {
+    let mut args = args::load_args(discovery_timeout()).await?;
+    if args.check {
+        let status = health_check(args.metrics.addr, args.metrics.tls.is_some()).await?;
+        println!("{}", status);
+        return Ok(());
+    }
+    let launcher = fping::for_program(&args.fping_bin);
+    std::mem::take(&mut args.patterns).install();
+    if args.spawn_options.print_ttl && !args.supports_print_ttl() {
+        warn!(
+            "--reply-ttl ignored, fping {} does not print the TTL of replies",
+            args.fping_version
+        );
+        args.spawn_options.print_ttl = false;
+    }
+    // reloads fetch the url again
+    let mut fetched = Vec::new();
+    if let Some(url) = &args.targets_url {
+        fetched = targets::fetch_targets(url).await?;
+        targets::merge_targets(&mut args.targets, fetched.clone());
+    }
+    if args.self_test {
+        return selftest::run(&launcher, &args).await;
+    }
+    if args.shuffle_targets {
+        targets::shuffle_targets(&mut args.targets, args.seed);
+    }
+    let tls = args
+        .metrics
+        .tls
+        .as_ref()
+        .map(prom::TlsMaterial::load)
+        .transpose()?;
 
-    let metrics = prom::PingMetrics::new("fping");
+    let namespace = args.metrics.namespace.as_str();
+    let metrics =
+        prom::PingMetrics::new(namespace, fping::Protocol::Icmp, &args.metrics.rtt_buckets);
+    if args.metrics.emit_timestamps {
+        metrics.lock().unwrap().emit_timestamps();
+    }
+    if args.metrics.disable_ipdv {
+        metrics.lock().unwrap().disable_ipdv();
+    }
+    if !args.metrics.inject_loss.is_empty() {
+        warn!(target: "metrics", "injecting synthetic loss for {:?}", args.metrics.inject_loss);
+        metrics
+            .lock()
+            .unwrap()
+            .inject_loss(args.metrics.inject_loss.clone());
+    }
     prometheus::register(Box::new(LockedCollector::from(metrics.clone())))?;
-    prometheus::register(info_metric(args.fping_version.clone()))?;
+    prometheus::register(info_metric(args.fping_version.clone(), namespace))?;
+    let packet_size = args
+        .spawn_options
+        .packet_size
+        .unwrap_or(fping::DEFAULT_PACKET_SIZE);
+    prometheus::register(packet_size_metric(packet_size, namespace))?;
+    match largest_mtu() {
+        Some(mtu) if !fits_mtu(packet_size, args.spawn_options.address_family, mtu) => warn!(
+            "pings of {} data bytes exceed the largest interface MTU of {}, they will be fragmented",
+            packet_size, mtu
+        ),
+        _ => {}
+    }
+    let targets_by_type = targets_metric(&args.targets, namespace)?;
+    prometheus::register(Box::new(targets_by_type.clone()))?;
+    let target_info = prom::TargetInfo::new(namespace);
+    target_info.set_labels(&args.target_labels);
+    prometheus::register(Box::new(target_info.clone()))?;
 
-    let (http_tx, rx) = if VersionReq::parse(">=4.3.0")
-        .unwrap()
-        .matches(&args.fping_version)
-    {
+    let scrape_metrics = prom::ScrapeMetrics::new(namespace);
+    prometheus::register(Box::new(scrape_metrics.clone()))?;
+    let countdown = prom::ShutdownCountdown::new(namespace);
+    prometheus::register(Box::new(countdown.clone()))?;
+
+    let buffer = if let Some(count) = args.spawn_options.count {
+        // splits would be counted again by the summary fping prints on exit
+        info!(
+            "sending {} pings per target, packet loss is reported once fping exits",
+            count
+        );
+        None
+    } else if args.supports_summary() {
         info!("SIGQUIT signal summary enabled");
-        prom::RegistryAccess::new(prometheus::default_registry(), Some(1))
+        Some(1)
     } else {
         warn!(
             "fping {} does not support summary requests, accurate packet loss will not be available",
             args.fping_version
         );
-        prom::RegistryAccess::new(prometheus::default_registry(), None)
+        None
     };
+    let (http_tx, rx) = prom::RegistryAccess::new(
+        prometheus::default_registry(),
+        buffer,
+        args.metrics.scrape_cooldown,
+        args.metrics.overload,
+        scrape_metrics,
+    );
 
-    let mut fping = launcher.spawn(&args.targets).await?.with_controls(rx);
+    if let Some(interval) = args.align_interval {
+        let delay = alignment_delay(SystemTime::now(), interval);
+        info!(
+            "delaying fping by {} to align with {}",
+            humantime::format_duration(delay),
+            humantime::format_duration(interval)
+        );
+        tokio::time::sleep(delay).await;
+    }
 
-    tokio::select! {
-        e = terminate_signal() => {
-            match e {
-                Some(signal) => debug!("received {}", signal),
-                None => error!("failure registering signal handler")
+    let mut fping = launcher
+        .spawn(&args.targets, &args.spawn_options)
+        .await?
+        .with_max_line_length(args.max_line_length)
+        .with_controls(rx);
+
+    let child_memory = match args.child_memory_interval {
+        Some(interval) => {
+            let child_memory = prom::ChildProcess::new(namespace);
+            prometheus::register(Box::new(child_memory.clone()))?;
+            Some((interval, child_memory))
+        }
+        None => None,
+    };
+    let watch_child_memory = |child: &tokio::process::Child| {
+        if let Some((interval, child_memory)) = &child_memory {
+            match child.id() {
+                Some(pid) => {
+                    tokio::spawn(prom::watch_child_process(
+                        pid,
+                        *interval,
+                        child_memory.clone(),
+                    ));
+                }
+                None => warn!("fping exited before it could be monitored"),
             }
+        }
+    };
+    watch_child_memory(fping.handle());
+
+    #[cfg(feature = "graphite")]
+    if let Some(graphite) = args.graphite.clone() {
+        tokio::spawn(prom::push_graphite(graphite, http_tx.clone()));
+    }
+
+    match args.initial_summary {
+        Some(delay) if args.supports_summary() => {
+            tokio::spawn(prom::initial_summary(delay, http_tx.clone()));
+        }
+        Some(_) => warn!("--initial-summary ignored, fping does not support summary requests"),
+        None => {}
+    }
+
+    let mut state = MetricsState::new(metrics, &args.targets)
+        .with_trace_sampling(args.trace_sample_rate)
+        .with_measurement_window(args.measurement_window)
+        .with_summary_block_timeout(args.summary_block_timeout)
+        .with_warmup_samples(args.warmup_samples)
+        .with_ipdv(!args.metrics.disable_ipdv)
+        .with_expected_targets(
+            args.spawn_options
+                .generate
+                .as_ref()
+                .and_then(fping::Generate::count),
+        );
+    if let Some(period) = args.heartbeat_interval {
+        tokio::spawn(state.vitals().heartbeat(period));
+    }
+    let mut total_loss = match args.exit_on_total_loss {
+        Some(threshold) => {
+            let (tx, rx) = oneshot::channel();
+            state = state.with_total_loss_exit(threshold, tx);
+            Some((threshold, rx))
+        }
+        None => None,
+    };
+
+    let (stop_http, http_stop) = watch::channel(false);
+    let (reload_tx, mut reload_rx) = if args.metrics.lifecycle {
+        let (tx, rx) = mpsc::channel(1);
+        (Some(tx), Some(rx))
+    } else {
+        (None, None)
+    };
+    let fping_alive = Arc::new(AtomicBool::new(true));
+    let publish = prom::publish_metrics(
+        &args.metrics,
+        tls,
+        http_tx.clone(),
+        prom::ServerContext {
+            fping_alive: fping_alive.clone(),
+            countdown,
+            version: prom::VersionInfo {
+                fping_version: args.fping_version.to_string(),
+                summaries: buffer.is_some(),
+            },
+            reload: reload_tx,
         },
-        res = fping.listen(NoPrelaunchControl::new(
-            LockControl::new(
-                ControlToInterrupt::new(
-                    MetricsState::new(metrics),
-                    KnownSignals::sigquit()
-                )
-            )
-        )) => {
-            // fping should be in a permanent loop
-            error!("fping listener terminated:\n{:#?}", res);
-            res?;
-        },
-        res = prom::publish_metrics(&args.metrics, http_tx) => {
-            debug!("http handler terminated:\n{:#?}", res);
-            res?;
+        http_stop,
+    );
+    tokio::pin!(publish);
+    // boxed so it can be dropped before fping is cleaned up or restarted,
+    // the state outlives it to keep the metrics of remaining targets
+    let summary_lock = Arc::new(tokio::sync::Mutex::new(()));
+    let mut listen = Box::pin(fping.listen(NoPrelaunchControl::new(LockControl::new(
+        ControlToInterrupt::new(&mut state, KnownSignals::sigquit()),
+        summary_lock.clone(),
+    ))));
+    // SIGHUP still terminates the exporter if there is nothing to reload
+    let mut hangup = if args.target_file.is_some() || args.targets_url.is_some() {
+        Some(signal(SignalKind::hangup())?)
+    } else {
+        None
+    };
+
+    let restarts_total = prometheus::IntCounter::with_opts(
+        opts!(
+            "restarts_total",
+            "times fping was restarted after exiting unexpectedly"
+        )
+        .namespace(namespace),
+    )?;
+    prometheus::register(Box::new(restarts_total.clone()))?;
+    // consecutive restarts, reset once fping stays up past the backoff
+    let mut restarts = 0;
+    let mut respawn_at = None;
+    let mut spawned_at = Instant::now();
+
+    let (reason, res): (_, anyhow::Result<()>) = loop {
+        let wakeup = tokio::select! {
+            e = terminate_signal() => {
+                break match e {
+                    Some(signal) => (ShutdownReason::Signal(signal), Ok(())),
+                    None => (ShutdownReason::SignalHandlerFailed, Ok(())),
+                };
+            },
+            res = &mut listen, if respawn_at.is_none() => {
+                fping_alive.store(false, Ordering::Relaxed);
+                if spawned_at.elapsed() >= MAX_RESTART_DELAY {
+                    restarts = 0;
+                }
+                match res {
+                    // fping prints the final summaries before it exits
+                    Ok(()) if args.spawn_options.count.is_some() => {
+                        break (ShutdownReason::CountComplete, Ok(()));
+                    }
+                    res if restarts < args.max_restarts => {
+                        let delay = restart_delay(restarts);
+                        warn!(
+                            "fping listener terminated, restarting in {}: {:?}",
+                            humantime::format_duration(delay),
+                            res
+                        );
+                        respawn_at = Some(tokio::time::Instant::now() + delay);
+                        Wakeup::Exited
+                    }
+                    // fping should be in a permanent loop
+                    res => {
+                        error!("fping listener terminated:\n{:#?}", res);
+                        break (ShutdownReason::FpingExited, res.map_err(Into::into));
+                    }
+                }
+            },
+            res = async {
+                match &args.textfile {
+                    Some(textfile) => prom::write_textfile(textfile, http_tx.clone()).await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                error!("textfile writer terminated:\n{:#?}", res);
+                break (ShutdownReason::TextfileFailed, res.map_err(Into::into));
+            },
+            threshold = async {
+                match &mut total_loss {
+                    Some((threshold, rx)) => match rx.await {
+                        Ok(()) => *threshold,
+                        // the listener has stopped, its branch handles that
+                        Err(_) => std::future::pending().await,
+                    },
+                    None => std::future::pending().await,
+                }
+            } => {
+                break (ShutdownReason::TotalLoss, Err(exit::TotalLoss(threshold).into()));
+            },
+            res = &mut publish => {
+                debug!("http handler terminated:\n{:#?}", res);
+                break match res {
+                    Ok(()) => (ShutdownReason::RuntimeLimit, Ok(())),
+                    Err(e) => (ShutdownReason::BindFailure, Err(e.into())),
+                };
+            }
+            _ = async {
+                match respawn_at {
+                    Some(at) => tokio::time::sleep_until(at).await,
+                    None => std::future::pending().await,
+                }
+            } => Wakeup::Respawn,
+            // reloads wait for fping to be respawned, fping and its listener
+            // are left alone unless the targets changed
+            Some(()) = async {
+                match hangup.as_mut() {
+                    Some(hangup) => hangup.recv().await,
+                    None => std::future::pending().await,
+                }
+            }, if respawn_at.is_none() => {
+                match reload(&args, &mut fetched, &target_info, None).await {
+                    Some(wakeup) => wakeup,
+                    None => continue,
+                }
+            },
+            Some(responder) = async {
+                match reload_rx.as_mut() {
+                    Some(reload_rx) => reload_rx.recv().await,
+                    None => std::future::pending().await,
+                }
+            }, if respawn_at.is_none() => {
+                match reload(&args, &mut fetched, &target_info, Some(responder)).await {
+                    Some(wakeup) => wakeup,
+                    None => continue,
+                }
+            },
+        };
+
+        drop(listen);
+        let gave_up = match wakeup {
+            // SIGHUP or POST /-/reload changed the targets
+            Wakeup::Reload(targets, labels, responder) => {
+                let outcome = match launcher.spawn(&targets, &args.spawn_options).await {
+                    Ok(spawned) => {
+                        let (mut previous, controls) = fping.dispose_with_controls();
+                        fping = spawned
+                            .with_max_line_length(args.max_line_length)
+                            .with_controls(controls);
+                        let stopped = async {
+                            if previous.try_wait()?.is_none() {
+                                previous.interrupt(KnownSignals::sigint())?;
+                                previous.wait().await?;
+                            }
+                            Ok::<_, io::Error>(())
+                        }
+                        .await;
+                        if let Err(e) = stopped {
+                            warn!("unable to stop the previous fping: {}", e);
+                        }
+
+                        let removed: Vec<String> = args
+                            .targets
+                            .iter()
+                            .filter(|target| !targets.contains(target))
+                            .cloned()
+                            .collect();
+                        info!(
+                            "restarted fping with {} targets, removed {:?}",
+                            targets.len(),
+                            removed
+                        );
+                        state.retarget(&targets, &removed);
+                        count_targets(&targets_by_type, &targets);
+                        target_info.set_labels(&labels);
+                        watch_child_memory(fping.handle());
+                        args.targets = targets;
+                        args.target_labels = labels;
+                        Ok(())
+                    }
+                    Err(e) => {
+                        error!(
+                            "unable to restart fping, keeping the previous targets: {}",
+                            e
+                        );
+                        Err(format!("unable to restart fping: {}", e))
+                    }
+                };
+                if let Some(responder) = responder {
+                    let _ = responder.send(outcome);
+                }
+                None
+            }
+            Wakeup::Exited => None,
+            Wakeup::Respawn => {
+                respawn_at = None;
+                restarts += 1;
+                restarts_total.inc();
+                if args.shuffle_targets {
+                    targets::shuffle_targets(&mut args.targets, args.seed);
+                }
+                match launcher.spawn(&args.targets, &args.spawn_options).await {
+                    Ok(spawned) => {
+                        let (mut previous, controls) = fping.dispose_with_controls();
+                        fping = spawned
+                            .with_max_line_length(args.max_line_length)
+                            .with_controls(controls);
+                        match previous.wait().await {
+                            Ok(status) => match &state.exit_message {
+                                Some(message) => warn!("fping exited with {}: {}", status, message),
+                                None => warn!("fping exited with {}", status),
+                            },
+                            Err(e) => warn!("unable to reap the previous fping: {}", e),
+                        }
+                        info!(
+                            "restarted fping, attempt {} out of {}",
+                            restarts, args.max_restarts
+                        );
+                        // nothing carries over from the previous process
+                        state.retarget(&args.targets, &[]);
+                        watch_child_memory(fping.handle());
+                        fping_alive.store(true, Ordering::Relaxed);
+                        spawned_at = Instant::now();
+                        None
+                    }
+                    Err(e) if restarts < args.max_restarts => {
+                        let delay = restart_delay(restarts);
+                        error!(
+                            "unable to restart fping, retrying in {}: {}",
+                            humantime::format_duration(delay),
+                            e
+                        );
+                        respawn_at = Some(tokio::time::Instant::now() + delay);
+                        None
+                    }
+                    Err(e) => Some(e),
+                }
+            }
+        };
+        listen = Box::pin(fping.listen(NoPrelaunchControl::new(LockControl::new(
+            ControlToInterrupt::new(&mut state, KnownSignals::sigquit()),
+            summary_lock.clone(),
+        ))));
+        if let Some(e) = gave_up {
+            error!("unable to restart fping, giving up: {}", e);
+            break (ShutdownReason::FpingExited, Err(e.into()));
+        }
+    };
+
+    // Let scrapes in progress finish, fping keeps running to answer them
+    let _ = stop_http.send(true);
+    match reason {
+        ShutdownReason::RuntimeLimit | ShutdownReason::BindFailure => {}
+        ShutdownReason::FpingExited | ShutdownReason::CountComplete => {
+            let _ = publish.await;
+        }
+        _ => {
+            tokio::select! {
+                _ = &mut publish => {}
+                res = &mut listen => error!("fping listener terminated while draining:\n{:#?}", res),
+            }
         }
     }
+    drop(listen);
 
     // Clean up fping
-    let mut handle = fping.dispose();
-    match handle.try_wait()? {
-        //TODO: try to diagnose based on status
-        //TODO: check for unhandled stderr output for reason?
-        Some(status) => error!("{:?}", status),
-        // Exit not caused by unexpected fping exit, clean up the child process
-        None => {
-            // Send SIGINT and clean up
-            handle.interrupt(KnownSignals::sigint())?;
-            handle.wait().await?;
+    let cleanup = async {
+        let mut handle = fping.dispose();
+        if reason == ShutdownReason::CountComplete {
+            // the exit code only tells whether every target replied
+            let status = handle.wait().await?;
+            info!("fping finished with {}", status);
+            return Ok(());
+        }
+        match handle.try_wait()? {
+            //TODO: try to diagnose based on status
+            Some(status) => {
+                match &state.exit_message {
+                    Some(message) => error!("fping exited with {}: {}", status, message),
+                    None => error!("fping exited with {}", status),
+                }
+                if let Err(e) = register_exit_code(status, &args.metrics.namespace) {
+                    warn!("unable to record fping exit code: {}", e);
+                }
+            }
+            // Exit not caused by unexpected fping exit, clean up the child process
+            None => {
+                // Send SIGINT and clean up
+                handle.interrupt(KnownSignals::sigint())?;
+                handle.wait().await?;
+            }
+        }
+        Ok::<_, io::Error>(())
+    }
+    .await;
+
+    if let Err(e) = reason.register_metric(&args.metrics.namespace) {
+        warn!("unable to record shutdown reason: {}", e);
+    }
+    if let Some(textfile) = &args.textfile {
+        if let Err(e) = prom::write_textfile_once(textfile, prometheus::default_registry()).await {
+            warn!("final textfile output failed: {}", e);
+        }
+    }
+    if reason == ShutdownReason::CountComplete {
+        print!(
+            "{}",
+            prometheus::TextEncoder::new().encode_to_string(&prometheus::gather())?
+        );
+    }
+
+    if reason.is_failure() {
+        error!("shutting down: {}", reason);
+    } else {
+        info!("shutting down: {}", reason);
+    }
+    res.and(cleanup.map_err(Into::into))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use event_stream::EventHandler;
+    use prometheus::core::Collector;
+
+    use super::*;
+
+    #[derive(Debug, Default, Clone)]
+    struct TestToken(Rc<Cell<bool>>);
+
+    impl OnSummaryComplete for TestToken {
+        fn on_completed(self) {
+            self.0.set(true);
+        }
+
+        fn is_alive(&self) -> bool {
+            true
+        }
+    }
+
+    type TestState = MetricsState<TestToken, (&'static str, &'static str, ())>;
+
+    fn test_state(targets: &[&str]) -> TestState {
+        MetricsState::new(
+            PingMetrics::new("fping", fping::Protocol::Icmp, &prom::DEFAULT_RTT_BUCKETS),
+            targets,
+        )
+    }
+
+    /// Hands out a summary token, returning whether it has been released
+    fn request_summary(state: &mut TestState) -> Rc<Cell<bool>> {
+        let token = TestToken::default();
+        let released = token.0.clone();
+        state.on_control(&mut (), token).unwrap();
+        released
+    }
+
+    #[test]
+    fn skipped_target_does_not_stall_summary() {
+        let mut state = test_state(&["dns.google", "nonexistent.invalid"]);
+        state.on_error("nonexistent.invalid: Name or service not known");
+        state.on_output(
+            "[1611765997.71135] dns.google (8.8.8.8) : [0], 64 bytes, 10.2 ms (10.2 avg, 0% loss)",
+        );
+        assert_eq!(state.expected_targets, 1);
+
+        let released = request_summary(&mut state);
+        state.on_error("[16:55:13]");
+        state.on_error(
+            "dns.google (8.8.8.8) : xmt/rcv/%loss = 1/1/0%, min/avg/max = 10.2/10.2/10.2",
+        );
+        assert!(released.get());
+
+        let active = state
+            .metrics
+            .lock()
+            .unwrap()
+            .collect()
+            .into_iter()
+            .find(|family| family.get_name() == "fping_active_targets")
+            .map(|family| family.get_metric()[0].get_gauge().get_value());
+        assert_eq!(active, Some(1.0));
+    }
+
+    #[test]
+    fn retarget_after_reload() {
+        let mut state = test_state(&["dns.google", "localhost"]);
+        state.on_output(
+            "[1611765997.71135] dns.google (8.8.8.8) : [0], 64 bytes, 10.2 ms (10.2 avg, 0% loss)",
+        );
+        state.on_output(
+            "[1611765997.71135] localhost (127.0.0.1) : [0], timed out (NaN avg, 100% loss)",
+        );
+        let released = request_summary(&mut state);
+
+        state.retarget(
+            &["dns.google", "1.1.1.1", "one.one.one.one"],
+            &["localhost".to_owned()],
+        );
+        assert!(released.get());
+        assert_eq!(state.expected_targets, 3);
+        assert_eq!(state.vitals.targets_up.load(Ordering::Relaxed), 1);
+        assert_eq!(state.vitals.targets_down.load(Ordering::Relaxed), 0);
+        let remaining: HashSet<_> = state
+            .metrics
+            .lock()
+            .unwrap()
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .flat_map(|metric| metric.get_label())
+            .filter(|label| label.get_name() == "target")
+            .map(|label| label.get_value().to_owned())
+            .collect();
+        assert_eq!(
+            remaining,
+            std::iter::once("dns.google".to_owned()).collect()
+        );
+
+        // the restarted fping reports unresolvable targets again
+        state.on_error("one.one.one.one: Name or service not known");
+        assert_eq!(state.expected_targets, 2);
+    }
+
+    #[test]
+    fn restart_keeps_generated_targets() {
+        let mut state = test_state(&[]).with_expected_targets(Some(4));
+        let lines: Vec<_> = (1..=4)
+            .map(|addr| format!(
+                "[1611765997.71135] 192.0.2.{0} (192.0.2.{0}) : [0], 64 bytes, 10.2 ms (10.2 avg, 0% loss)",
+                addr
+            ))
+            .collect();
+        for line in &lines {
+            state.on_output(line);
+        }
+        assert_eq!(state.expected_targets, 4);
+
+        // a respawn with --generate passes no targets of its own
+        state.retarget(&[] as &[String], &[]);
+        assert_eq!(state.expected_targets, 4);
+        assert_eq!(state.current_targets, 0);
+    }
+
+    #[test]
+    fn multiple_addresses_per_target() {
+        let mut state = test_state(&["dual.example"]);
+        state.on_output(
+            "[1611765997.71135] dual.example (192.0.2.1) : [0], 64 bytes, 10.2 ms (10.2 avg, 0% loss)",
+        );
+        state.on_output(
+            "[1611765997.71135] dual.example (2001:db8::1) : [0], 64 bytes, 11.4 ms (11.4 avg, 0% loss)",
+        );
+        assert_eq!(state.expected_targets, 2);
+
+        let released = request_summary(&mut state);
+        state.on_error("[16:55:13]");
+        state.on_error(
+            "dual.example (192.0.2.1) : xmt/rcv/%loss = 1/1/0%, min/avg/max = 10.2/10.2/10.2",
+        );
+        assert!(!released.get());
+        state.on_error(
+            "dual.example (2001:db8::1) : xmt/rcv/%loss = 1/1/0%, min/avg/max = 11.4/11.4/11.4",
+        );
+        assert!(released.get());
+    }
+
+    #[test]
+    fn ipdv_per_address() {
+        let mut state = test_state(&["dual.example"]);
+        for line in &[
+            "[1611765997.71135] dual.example (192.0.2.1) : [0], 64 bytes, 10.0 ms (10.0 avg, 0% loss)",
+            "[1611765997.71135] dual.example (2001:db8::1) : [0], 64 bytes, 30.0 ms (30.0 avg, 0% loss)",
+            "[1611765998.71135] dual.example (192.0.2.1) : [1], 64 bytes, 12.0 ms (11.0 avg, 0% loss)",
+            "[1611765998.71135] dual.example (2001:db8::1) : [1], 64 bytes, 34.0 ms (32.0 avg, 0% loss)",
+        ] {
+            state.on_output(line);
         }
+
+        let families = state.metrics.lock().unwrap().collect();
+        let ipdv: HashMap<String, (u64, f64)> = families
+            .iter()
+            .filter(|family| {
+                family.get_name() == "fping_instantaneous_packet_delay_variation_seconds"
+            })
+            .flat_map(|family| family.get_metric())
+            .map(|metric| {
+                let addr = metric
+                    .get_label()
+                    .iter()
+                    .find(|label| label.get_name() == "addr")
+                    .unwrap()
+                    .get_value()
+                    .to_owned();
+                let histogram = metric.get_histogram();
+                (
+                    addr,
+                    (histogram.get_sample_count(), histogram.get_sample_sum()),
+                )
+            })
+            .collect();
+        assert_eq!(ipdv.len(), 2);
+        // half of the change in round trip time to the same address
+        let (count, sum) = ipdv["192.0.2.1"];
+        assert_eq!(count, 1);
+        assert!((sum - 0.001).abs() < 1e-9, "{}", sum);
+        let (count, sum) = ipdv["2001:db8::1"];
+        assert_eq!(count, 1);
+        assert!((sum - 0.002).abs() < 1e-9, "{}", sum);
+
+        // --disable-ipdv skips the calculation altogether
+        let mut state = test_state(&["dual.example"]).with_ipdv(false);
+        state.on_output("[1611765997.71135] dual.example (192.0.2.1) : [0], 64 bytes, 10.0 ms");
+        assert!(state.last_result.is_empty());
     }
 
-    Ok(())
+    #[test]
+    fn summary_without_local_time() {
+        let mut state = test_state(&["dns.google", "localhost"]);
+        for _ in 0..2 {
+            let released = request_summary(&mut state);
+            state.on_error(
+                "dns.google (8.8.8.8) : xmt/rcv/%loss = 1/1/0%, min/avg/max = 10.2/10.2/10.2",
+            );
+            assert!(!released.get());
+            state.on_error(
+                "localhost (127.0.0.1) : xmt/rcv/%loss = 1/1/0%, min/avg/max = 0.02/0.02/0.02",
+            );
+            assert!(released.get());
+        }
+        assert_eq!(state.expected_targets, 2);
+    }
+
+    #[test]
+    fn observed_pings_reset_per_summary() {
+        let mut state = test_state(&["dns.google"]);
+        for _ in 0..3 {
+            state.on_output(
+                "[1611765997.71135] dns.google (8.8.8.8) : [0], 64 bytes, 10.2 ms (10.2 avg, 0% loss)",
+            );
+        }
+        assert_eq!(state.take_observed("dns.google", "8.8.8.8"), 3);
+        assert_eq!(state.take_observed("dns.google", "8.8.8.8"), 0);
+        assert_eq!(state.take_observed("localhost", "127.0.0.1"), 0);
+    }
+
+    #[test]
+    fn measurement_window_resets_ipdv() {
+        let mut state = test_state(&["dns.google"]);
+        assert!(state
+            .calc_ipdv("dns.google", "8.8.8.8", Duration::from_millis(10))
+            .is_none());
+        assert!(state
+            .calc_ipdv("dns.google", "8.8.8.8", Duration::from_millis(12))
+            .is_some());
+
+        let mut state = test_state(&["dns.google"]).with_measurement_window(Some(Duration::ZERO));
+        for rtt in [10, 12] {
+            state.check_window("dns.google");
+            state.check_window("dns.google");
+            assert!(state
+                .calc_ipdv("dns.google", "8.8.8.8", Duration::from_millis(rtt))
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn total_loss_requires_every_target_down() {
+        let (tx, mut rx) = oneshot::channel();
+        let mut state = test_state(&["dns.google", "localhost"])
+            .with_total_loss_exit(Duration::from_secs(60), tx);
+        let reply =
+            "[1611765997.71135] localhost (127.0.0.1) : [0], 64 bytes, 0.029 ms (0.040 avg, 0% loss)";
+        let timeouts = [
+            "[1611765997.71135] dns.google (8.8.8.8) : [0], timed out (NaN avg, 100% loss)",
+            "[1611765997.71135] localhost (127.0.0.1) : [0], timed out (NaN avg, 100% loss)",
+        ];
+        let backdate = |state: &mut TestState| {
+            let watch = state.total_loss.as_mut().unwrap();
+            watch.last_reply = Instant::now() - Duration::from_secs(61);
+        };
+
+        // localhost replied within the threshold
+        state.on_output(reply);
+        state.on_output(timeouts[0]);
+        assert!(rx.try_recv().is_err());
+
+        // a reply resets the threshold, however long ago the previous was
+        backdate(&mut state);
+        state.on_output(reply);
+        state.on_output(timeouts[0]);
+        assert!(rx.try_recv().is_err());
+
+        backdate(&mut state);
+        for timeout in &timeouts {
+            state.on_output(timeout);
+        }
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn vitals_track_target_status() {
+        let mut state = test_state(&["dns.google", "localhost"]);
+        state.on_output(
+            "[1611765997.71135] localhost (127.0.0.1) : [0], 64 bytes, 0.029 ms (0.040 avg, 0% loss)",
+        );
+        state.on_output(
+            "[1611765997.71135] dns.google (8.8.8.8) : [0], timed out (NaN avg, 100% loss)",
+        );
+        state.on_output(
+            "[1611765998.71135] dns.google (8.8.8.8) : [1], 64 bytes, 10.2 ms (10.2 avg, 50% loss)",
+        );
+
+        let vitals = state.vitals();
+        assert_eq!(vitals.pings.load(Ordering::Relaxed), 3);
+        assert_eq!(vitals.targets_up.load(Ordering::Relaxed), 2);
+        assert_eq!(vitals.targets_down.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn runtime_errors_are_not_skipped_targets() {
+        let mut state = test_state(&["dns.google", "localhost"]);
+        state.on_output(
+            "[1611765997.71135] localhost (127.0.0.1) : [0], 64 bytes, 0.029 ms (0.040 avg, 0% loss)",
+        );
+        state.on_error("dns.google: error while sending ping: No route to host");
+        assert_eq!(state.expected_targets, 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_codes_and_signals() {
+        use std::{os::unix::process::ExitStatusExt, process::ExitStatus};
+
+        // raw wait statuses: exit code in the high byte, signal in the low bits
+        assert_eq!(exit_code(ExitStatus::from_raw(0)), 0);
+        assert_eq!(exit_code(ExitStatus::from_raw(2 << 8)), 2);
+        assert_eq!(exit_code(ExitStatus::from_raw(9)), -9);
+    }
+
+    #[test]
+    fn exit_message_is_kept() {
+        let mut state = test_state(&["dns.google"]);
+        assert_eq!(state.exit_message, None);
+        state.on_error("fping: can't create socket (must run as root?)");
+        assert_eq!(
+            state.exit_message.as_deref(),
+            Some("can't create socket (must run as root?)")
+        );
+        // not mistaken for an error of a target
+        assert!(!state
+            .metrics
+            .lock()
+            .unwrap()
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .flat_map(|metric| metric.get_label())
+            .any(|label| label.get_value() == "fping"));
+    }
+
+    #[test]
+    fn expected_output_counts_as_parsed() {
+        use prometheus::core::Collector;
+
+        let mut state = test_state(&["dns.google"]);
+        state.on_output("xxxx");
+        state.on_output(
+            "[1611765997.71135] dns.google (8.8.8.8) : [0], 64 bytes, 10.2 ms (10.2 avg, 0% loss)",
+        );
+        state.on_error("");
+        state.on_error("[16:55:13]");
+        state.on_error(
+            "dns.google (8.8.8.8) : xmt/rcv/%loss = 1/1/0%, min/avg/max = 10.2/10.2/10.2",
+        );
+        state.on_error("something fping never prints");
+
+        let counts = |name: &str| {
+            let mut counts: Vec<(String, f64)> = state
+                .metrics
+                .lock()
+                .unwrap()
+                .collect()
+                .iter()
+                .filter(|family| family.get_name() == name)
+                .flat_map(|family| family.get_metric())
+                .map(|m| {
+                    let stream = m.get_label()[0].get_value().to_owned();
+                    (stream, m.get_counter().get_value())
+                })
+                .collect();
+            counts.sort_by(|a, b| a.0.cmp(&b.0));
+            counts
+        };
+        assert_eq!(
+            counts("fping_output_lines_total"),
+            vec![("stderr".to_owned(), 4.0), ("stdout".to_owned(), 2.0)]
+        );
+        assert_eq!(
+            counts("fping_parsed_lines_total"),
+            vec![("stderr".to_owned(), 3.0), ("stdout".to_owned(), 1.0)]
+        );
+    }
+
+    #[test]
+    fn incomplete_summary_times_out() {
+        let mut state = test_state(&["dns.google", "localhost"])
+            .with_summary_block_timeout(Some(Duration::from_millis(10)));
+        let released = request_summary(&mut state);
+        state.on_error("[16:55:13]");
+        state.on_error(
+            "dns.google (8.8.8.8) : xmt/rcv/%loss = 1/1/0%, min/avg/max = 10.2/10.2/10.2",
+        );
+        assert!(!released.get());
+
+        std::thread::sleep(Duration::from_millis(20));
+        state.on_output(
+            "[1611765997.71135] dns.google (8.8.8.8) : [1], 64 bytes, 10.2 ms (10.2 avg, 0% loss)",
+        );
+        assert!(released.get());
+        assert!(state.held_token.is_none());
+
+        // the next summary is unaffected
+        let released = request_summary(&mut state);
+        state.on_error("[16:55:14]");
+        state.on_error(
+            "dns.google (8.8.8.8) : xmt/rcv/%loss = 1/1/0%, min/avg/max = 10.2/10.2/10.2",
+        );
+        state.on_error(
+            "localhost (127.0.0.1) : xmt/rcv/%loss = 1/1/0%, min/avg/max = 0.02/0.02/0.02",
+        );
+        assert!(released.get());
+    }
+
+    #[tokio::test]
+    async fn health_check_follows_status() {
+        use warp::{http::StatusCode, Filter};
+
+        let alive = Arc::new(AtomicBool::new(true));
+        let routes = {
+            let alive = alive.clone();
+            warp::path("health").map(move || {
+                if alive.load(Ordering::Relaxed) {
+                    warp::reply::with_status("fping is running", StatusCode::OK)
+                } else {
+                    warp::reply::with_status("fping has exited", StatusCode::SERVICE_UNAVAILABLE)
+                }
+            })
+        };
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        assert_eq!(health_check(addr, false).await.unwrap(), "fping is running");
+        // as if bound to every address
+        let wildcard = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), addr.port());
+        assert!(health_check(wildcard, false).await.is_ok());
+
+        alive.store(false, Ordering::Relaxed);
+        let err = health_check(addr, false).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("503"), "{:#}", err);
+    }
+
+    #[tokio::test]
+    async fn reload_fetches_targets_again() {
+        use warp::{http::StatusCode, Filter};
+
+        let served = Arc::new(Mutex::new(Some("dns.google\n")));
+        let routes = {
+            let served = served.clone();
+            warp::path("targets").map(move || match *served.lock().unwrap() {
+                Some(targets) => warp::reply::with_status(targets, StatusCode::OK),
+                None => warp::reply::with_status("", StatusCode::SERVICE_UNAVAILABLE),
+            })
+        };
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let url = format!("http://{}/targets", addr);
+        let args = args::test_args(&["--targets-url", &url, "localhost"]);
+        let mut fetched = Vec::new();
+
+        let (targets, _) = reload_targets(&args, &mut fetched).await.unwrap();
+        assert_eq!(targets, vec!["localhost", "dns.google"]);
+
+        *served.lock().unwrap() = Some("one.one.one.one\n");
+        let (targets, _) = reload_targets(&args, &mut fetched).await.unwrap();
+        assert_eq!(targets, vec!["localhost", "one.one.one.one"]);
+
+        // a failed fetch keeps what was served last
+        *served.lock().unwrap() = None;
+        let (targets, _) = reload_targets(&args, &mut fetched).await.unwrap();
+        assert_eq!(targets, vec!["localhost", "one.one.one.one"]);
+    }
+
+    #[test]
+    fn restart_backoff() {
+        let delays: Vec<u64> = (0..8).map(|n| restart_delay(n).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(restart_delay(u32::MAX), MAX_RESTART_DELAY);
+    }
+
+    #[test]
+    fn packet_size_against_mtu() {
+        assert!(fits_mtu(fping::DEFAULT_PACKET_SIZE, None, 1500));
+        assert!(fits_mtu(1472, Some(fping::AddressFamily::Ipv4), 1500));
+        assert!(!fits_mtu(1473, None, 1500));
+        assert!(!fits_mtu(1472, Some(fping::AddressFamily::Ipv6), 1500));
+        assert!(fits_mtu(1452, Some(fping::AddressFamily::Ipv6), 1500));
+    }
+
+    #[test]
+    fn align_to_wall_clock() {
+        let at =
+            |secs, millis| UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis);
+        let ten = Duration::from_secs(10);
+        assert_eq!(alignment_delay(at(1_600_000_000, 0), ten), Duration::ZERO);
+        assert_eq!(
+            alignment_delay(at(1_600_000_003, 250), ten),
+            Duration::from_millis(6750)
+        );
+        assert_eq!(
+            alignment_delay(at(1_600_000_009, 999), ten),
+            Duration::from_millis(1)
+        );
+    }
+
+    #[test]
+    fn warmup_pings_skip_histograms() {
+        use prometheus::core::Collector;
+
+        let mut state = test_state(&["dns.google"]).with_warmup_samples(2);
+        for line in &[
+            "[1611765997.71135] dns.google (8.8.8.8) : [0], 64 bytes, 90.4 ms (90.4 avg, 0% loss)",
+            "[1611765998.71135] dns.google (8.8.8.8) : [1], 64 bytes, 10.2 ms (50.3 avg, 0% loss)",
+            "[1611765999.71135] dns.google (8.8.8.8) : [2], 64 bytes, 10.2 ms (36.9 avg, 0% loss)",
+        ] {
+            state.on_output(line);
+        }
+        let released = request_summary(&mut state);
+        state.on_error("[16:55:13]");
+        state.on_error(
+            "dns.google (8.8.8.8) : xmt/rcv/%loss = 3/3/0%, min/avg/max = 10.2/10.2/10.2",
+        );
+        assert!(released.get());
+
+        let families = state.metrics.lock().unwrap().collect();
+        let family = |name: &str| {
+            families
+                .iter()
+                .find(|family| family.get_name() == name)
+                .map(|family| &family.get_metric()[0])
+                .unwrap()
+        };
+        assert_eq!(
+            family("fping_icmp_round_trip_time_seconds")
+                .get_histogram()
+                .get_sample_count(),
+            1
+        );
+        assert_eq!(
+            family("fping_last_observed_sequence")
+                .get_gauge()
+                .get_value(),
+            2.0
+        );
+        assert_eq!(
+            family("fping_icmp_request_total").get_counter().get_value(),
+            3.0
+        );
+        // no delay variation until two pings have been observed after warmup
+        assert!(families
+            .iter()
+            .filter(
+                |family| family.get_name() == "fping_instantaneous_packet_delay_variation_seconds"
+            )
+            .all(|family| family.get_metric().is_empty()));
+    }
 }
@@ -0,0 +1,180 @@
+use std::{io, time::Duration};
+
+use nix::{
+    errno::Errno,
+    unistd::{sysconf, SysconfVar},
+};
+use prometheus::{
+    core::{Collector, Desc},
+    opts,
+    proto::MetricFamily,
+    Counter, IntGauge,
+};
+
+#[derive(Debug, Clone)]
+pub struct ChildProcess {
+    resident: IntGauge,
+    process_resident: IntGauge,
+    process_cpu: Counter,
+}
+
+impl ChildProcess {
+    pub fn new<S: Into<String> + Copy>(namespace: S) -> Self {
+        Self {
+            resident: IntGauge::with_opts(
+                opts!("child_memory_bytes", "resident memory of the fping process")
+                    .namespace(namespace),
+            )
+            .unwrap(),
+            process_resident: IntGauge::with_opts(
+                opts!(
+                    "process_resident_memory_bytes",
+                    "resident memory of the fping process, as process_resident_memory_bytes of other exporters"
+                )
+                .namespace(namespace),
+            )
+            .unwrap(),
+            process_cpu: Counter::with_opts(
+                opts!(
+                    "process_cpu_seconds_total",
+                    "user and system CPU time spent by fping, across restarts"
+                )
+                .namespace(namespace),
+            )
+            .unwrap(),
+        }
+    }
+}
+
+impl Collector for ChildProcess {
+    fn desc(&self) -> Vec<&Desc> {
+        [
+            self.resident.desc(),
+            self.process_resident.desc(),
+            self.process_cpu.desc(),
+        ]
+        .concat()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        [
+            self.resident.collect(),
+            self.process_resident.collect(),
+            self.process_cpu.collect(),
+        ]
+        .concat()
+    }
+}
+
+/// Resident pages are the second field of `/proc/<pid>/statm`
+fn resident_pages(statm: &str) -> Option<i64> {
+    statm.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// User and system time in clock ticks are the 14th and 15th field of
+/// `/proc/<pid>/stat`. The second field is the command name in parentheses,
+/// which may itself contain spaces and parentheses.
+fn cpu_ticks(stat: &str) -> Option<u64> {
+    let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace().skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some(utime + stime)
+}
+
+fn malformed(file: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("malformed {}", file))
+}
+
+async fn resident_bytes(pid: u32, page_size: i64) -> io::Result<i64> {
+    let statm = tokio::fs::read_to_string(format!("/proc/{}/statm", pid)).await?;
+    resident_pages(&statm)
+        .map(|pages| pages * page_size)
+        .ok_or_else(|| malformed("statm"))
+}
+
+async fn cpu_seconds(pid: u32, ticks_per_second: f64) -> io::Result<f64> {
+    let stat = tokio::fs::read_to_string(format!("/proc/{}/stat", pid)).await?;
+    cpu_ticks(&stat)
+        .map(|ticks| ticks as f64 / ticks_per_second)
+        .ok_or_else(|| malformed("stat"))
+}
+
+/// The directory is gone once fping has been reaped, reading a file that was
+/// opened just before fails with ESRCH instead
+fn exited(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::NotFound || e.raw_os_error() == Some(Errno::ESRCH as i32)
+}
+
+/// Samples the memory and CPU usage of fping until it can no longer be read,
+/// which usually means fping has exited.
+pub async fn watch_child_process(pid: u32, interval: Duration, metrics: ChildProcess) {
+    // c_long is only 32 bits wide on some targets
+    #[allow(clippy::useless_conversion)]
+    let (page_size, ticks_per_second) =
+        match (sysconf(SysconfVar::PAGE_SIZE), sysconf(SysconfVar::CLK_TCK)) {
+            (Ok(Some(size)), Ok(Some(ticks))) if ticks > 0 => (i64::from(size), ticks as f64),
+            _ => {
+                warn!("unable to determine the page size or clock ticks, not monitoring fping");
+                return;
+            }
+        };
+
+    // the counter continues where the previous fping left off
+    let mut previous_cpu = 0.0;
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let sample = async {
+            let cpu = cpu_seconds(pid, ticks_per_second).await?;
+            let bytes = resident_bytes(pid, page_size).await?;
+            Ok::<_, io::Error>((cpu, bytes))
+        };
+        match sample.await {
+            Ok((cpu, bytes)) => {
+                metrics.resident.set(bytes);
+                metrics.process_resident.set(bytes);
+                if cpu > previous_cpu {
+                    metrics.process_cpu.inc_by(cpu - previous_cpu);
+                    previous_cpu = cpu;
+                }
+            }
+            Err(e) if exited(&e) => {
+                debug!("stopped monitoring fping, it has exited");
+                return;
+            }
+            Err(e) => {
+                warn!("stopped monitoring fping: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_statm() {
+        assert_eq!(resident_pages("2270 436 387 4 0 178 0\n"), Some(436));
+        assert_eq!(resident_pages("2270"), None);
+    }
+
+    #[test]
+    fn parse_stat() {
+        let stat = "4242 (fping (v5) x) S 4200 4242 4200 0 -1 4194560 220 0 0 0 37 12 0 0 20 0 1 0 123456 9297920 436 18446744073709551615\n";
+        assert_eq!(cpu_ticks(stat), Some(49));
+        assert_eq!(cpu_ticks("4242 (fping) S 4200"), None);
+        assert_eq!(cpu_ticks("4242 fping"), None);
+    }
+
+    #[tokio::test]
+    async fn sample_own_process() {
+        let pid = std::process::id();
+        assert!(resident_bytes(pid, 4096).await.unwrap() > 0);
+        assert!(cpu_seconds(pid, 100.0).await.unwrap() >= 0.0);
+        // no pid is this large
+        let gone = resident_bytes(u32::MAX, 4096).await.unwrap_err();
+        assert!(exited(&gone), "{}", gone);
+    }
+}
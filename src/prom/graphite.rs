@@ -0,0 +1,169 @@
+//! Pushes the registry to Graphite using the plaintext protocol.
+//!
+//! Metric families are flattened into one path per series:
+//! `<prefix>.<name>.<label>.<value>...`, with labels in the order prometheus
+//! sorts them. Anything but `[A-Za-z0-9_-]` in label values is replaced with
+//! `_` since dots would introduce extra path segments. Histograms are
+//! written as `<name>_count`, `<name>_sum` and `<name>_bucket.le.<bound>`.
+
+use std::{
+    fmt::Write as _,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+use super::RegistryAccess;
+use crate::args::GraphiteArgs;
+
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+struct PathBuilder<'a> {
+    prefix: &'a str,
+    timestamp: u64,
+    out: String,
+}
+
+impl PathBuilder<'_> {
+    fn line(&mut self, name: &str, metric: &Metric, extra: Option<(&str, &str)>, value: f64) {
+        if !self.prefix.is_empty() {
+            self.out.push_str(self.prefix);
+            self.out.push('.');
+        }
+        self.out.push_str(name);
+        let labels = metric
+            .get_label()
+            .iter()
+            .map(|l| (l.get_name(), l.get_value()))
+            .chain(extra);
+        for (label, value) in labels {
+            let _ = write!(self.out, ".{}.{}", label, sanitize(value));
+        }
+        let _ = writeln!(self.out, " {} {}", value, self.timestamp);
+    }
+}
+
+pub(super) fn format_families(families: &[MetricFamily], prefix: &str, timestamp: u64) -> String {
+    let mut builder = PathBuilder {
+        prefix,
+        timestamp,
+        out: String::new(),
+    };
+
+    for family in families {
+        let name = family.get_name();
+        for metric in family.get_metric() {
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    builder.line(name, metric, None, metric.get_counter().get_value())
+                }
+                MetricType::GAUGE => {
+                    builder.line(name, metric, None, metric.get_gauge().get_value())
+                }
+                MetricType::UNTYPED => {
+                    builder.line(name, metric, None, metric.get_untyped().get_value())
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let bucket_name = format!("{}_bucket", name);
+                    for bucket in histogram.get_bucket() {
+                        let bound = bucket.get_upper_bound().to_string();
+                        builder.line(
+                            &bucket_name,
+                            metric,
+                            Some(("le", &bound)),
+                            bucket.get_cumulative_count() as f64,
+                        );
+                    }
+                    builder.line(
+                        &format!("{}_sum", name),
+                        metric,
+                        None,
+                        histogram.get_sample_sum(),
+                    );
+                    builder.line(
+                        &format!("{}_count", name),
+                        metric,
+                        None,
+                        histogram.get_sample_count() as f64,
+                    );
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    builder.line(
+                        &format!("{}_sum", name),
+                        metric,
+                        None,
+                        summary.get_sample_sum(),
+                    );
+                    builder.line(
+                        &format!("{}_count", name),
+                        metric,
+                        None,
+                        summary.get_sample_count() as f64,
+                    );
+                }
+            }
+        }
+    }
+
+    builder.out
+}
+
+pub async fn push_graphite<T: Send + 'static>(args: GraphiteArgs, reg: RegistryAccess<T>) {
+    info!(target: "metrics", "pushing metrics to graphite at {} every {}", args.addr, humantime::format_duration(args.interval));
+
+    let mut interval = tokio::time::interval(args.interval);
+    loop {
+        interval.tick().await;
+        let metrics = match reg.clone().gather().await {
+            Ok(metrics) => metrics,
+            Err(e) => {
+                debug!("skipping graphite push: {}", e);
+                continue;
+            }
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_secs();
+        let payload = format_families(&metrics, &args.prefix, timestamp);
+
+        let res = async {
+            let mut stream = TcpStream::connect(&args.addr).await?;
+            stream.write_all(payload.as_bytes()).await?;
+            stream.shutdown().await
+        }
+        .await;
+        match res {
+            Ok(()) => trace!("pushed {} bytes to {}", payload.len(), args.addr),
+            Err(e) => warn!("graphite push to {} failed: {}", args.addr, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::fixtures::families, *};
+
+    #[test]
+    fn dotted_paths() {
+        assert_eq!(
+            format_families(&families(), "lab", 1_600_000_000),
+            "lab.fping_icmp_reply_total.addr.8_8_8_8.target.dns_google 3 1600000000\n\
+             lab.loss NaN 1600000000\n\
+             lab.rtt_seconds_bucket.le.0_5 1 1600000000\n\
+             lab.rtt_seconds_sum 1 1600000000\n\
+             lab.rtt_seconds_count 2 1600000000\n"
+        );
+    }
+}
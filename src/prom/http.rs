@@ -1,63 +1,380 @@
-use std::{convert::Infallible, time::Duration};
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use prometheus::{proto::MetricFamily, Encoder, Registry, TextEncoder};
-use tokio::sync::{mpsc, oneshot};
-use warp::{reply::with_header, Filter, Rejection, Reply};
+use prometheus::{
+    core::{Collector, Desc},
+    histogram_opts, opts,
+    proto::MetricFamily,
+    Encoder, Gauge, Histogram, IntCounter, Registry, TextEncoder,
+};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio_stream::wrappers::{TcpListenerStream, UnixListenerStream};
+use warp::{filters::BoxedFilter, http::StatusCode, reply::with_header, Filter, Rejection, Reply};
 
-use crate::args::MetricArgs;
+use super::{
+    json::{self, prefers_json, JsonEncoder, JSON_FORMAT},
+    listener::{self, BindError},
+    openmetrics::{prefers_openmetrics, OpenMetricsEncoder},
+    tls::TlsMaterial,
+};
+use crate::args::{BasicAuth, MetricArgs, OverloadBehavior};
 
-fn encode_metrics<E: Encoder + Default>(
+pub(super) fn encode_to_vec<E: Encoder + Default>(
     metrics: &[MetricFamily],
-) -> prometheus::Result<impl Reply> {
+) -> prometheus::Result<Vec<u8>> {
     let enc: E = Default::default();
     let mut out = Vec::new();
     enc.encode(metrics, &mut out)?;
-    Ok(with_header(out, "Content-Type", enc.format_type()))
+    Ok(out)
+}
+
+fn encode_metrics<E: Encoder + Default>(
+    metrics: &[MetricFamily],
+) -> prometheus::Result<impl Reply> {
+    let out = encode_to_vec::<E>(metrics)?;
+    Ok(with_header(out, "Content-Type", E::default().format_type()))
+}
+
+/// JSON or OpenMetrics if the `Accept` header asks for it, the text format
+/// otherwise
+fn negotiate(accept: Option<&str>, metrics: &[MetricFamily]) -> warp::reply::Response {
+    let reply = if prefers_json(accept) {
+        encode_metrics::<JsonEncoder>(metrics).map(Reply::into_response)
+    } else if prefers_openmetrics(accept) {
+        encode_metrics::<OpenMetricsEncoder>(metrics).map(Reply::into_response)
+    } else {
+        encode_metrics::<TextEncoder>(metrics).map(Reply::into_response)
+    };
+    reply.unwrap()
+}
+
+#[derive(Debug, Clone)]
+pub struct ScrapeMetrics {
+    throttled: IntCounter,
+    control_full: IntCounter,
+    duration: Histogram,
+    last_success: Gauge,
+}
+
+impl ScrapeMetrics {
+    pub fn new<S: Into<String>>(namespace: S) -> Self {
+        let namespace = namespace.into();
+        Self {
+            throttled: IntCounter::with_opts(
+                opts!(
+                    "scrapes_throttled_total",
+                    "scrapes served without a new summary due to --scrape-cooldown"
+                )
+                .namespace(namespace.clone()),
+            )
+            .unwrap(),
+            control_full: IntCounter::with_opts(
+                opts!(
+                    "control_channel_full_total",
+                    "summary requests which found another request already queued, handled according to --overload-behavior"
+                )
+                .namespace(namespace.clone()),
+            )
+            .unwrap(),
+            duration: Histogram::with_opts(
+                histogram_opts!(
+                    "scrape_duration_seconds",
+                    "time taken to gather metrics, including waiting for the summary from fping"
+                )
+                .namespace(namespace.clone()),
+            )
+            .unwrap(),
+            last_success: Gauge::with_opts(
+                opts!(
+                    "last_scrape_timestamp_seconds",
+                    "unix time of the last successful scrape, scrapes whose summary request failed leave it unchanged"
+                )
+                .namespace(namespace),
+            )
+            .unwrap(),
+        }
+    }
+}
+
+impl Collector for ScrapeMetrics {
+    fn desc(&self) -> Vec<&Desc> {
+        [
+            self.throttled.desc(),
+            self.control_full.desc(),
+            self.duration.desc(),
+            self.last_success.desc(),
+        ]
+        .concat()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        [
+            self.throttled.collect(),
+            self.control_full.collect(),
+            self.duration.collect(),
+            self.last_success.collect(),
+        ]
+        .concat()
+    }
+}
+
+/// Time left until `--runtime-limit` shuts the exporter down, computed on
+/// every scrape. Absent without a runtime limit.
+#[derive(Debug, Clone)]
+pub struct ShutdownCountdown {
+    remaining: Gauge,
+    deadline: Arc<Mutex<Option<Instant>>>,
+}
+
+impl ShutdownCountdown {
+    pub fn new<S: Into<String>>(namespace: S) -> Self {
+        Self {
+            remaining: Gauge::with_opts(
+                opts!(
+                    "exporter_shutdown_seconds_remaining",
+                    "seconds until the exporter shuts down due to --runtime-limit"
+                )
+                .namespace(namespace),
+            )
+            .unwrap(),
+            deadline: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn start(&self, limit: Duration) {
+        *self.deadline.lock().unwrap() = Some(Instant::now() + limit);
+    }
+}
+
+impl Collector for ShutdownCountdown {
+    fn desc(&self) -> Vec<&Desc> {
+        self.remaining.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        match *self.deadline.lock().unwrap() {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                self.remaining.set(remaining.as_secs_f64());
+                self.remaining.collect()
+            }
+            None => vec![],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SummaryRequests<T> {
+    tx: mpsc::Sender<oneshot::Sender<T>>,
+    cooldown: Duration,
+    last_summary: Mutex<Option<Instant>>,
+    overload: OverloadBehavior,
+    metrics: ScrapeMetrics,
+}
+
+impl<T> SummaryRequests<T> {
+    fn cooling_down(&self) -> bool {
+        self.last_summary
+            .lock()
+            .unwrap()
+            .is_some_and(|last| last.elapsed() < self.cooldown)
+    }
+
+    async fn request(&self) -> Result<(), AccessError> {
+        let (tx, rx) = oneshot::channel();
+        // queueing behind a full channel would pile up scrapes
+        self.tx.try_send(tx).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => {
+                self.metrics.control_full.inc();
+                AccessError::ChannelFull
+            }
+            mpsc::error::TrySendError::Closed(_) => AccessError::FpingProcessDead,
+        })?;
+        // guard using return value
+        let _ = rx.await?;
+        *self.last_summary.lock().unwrap() = Some(Instant::now());
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 pub enum RegistryAccess<T = Infallible> {
-    Limited(Registry, mpsc::Sender<oneshot::Sender<T>>),
-    Unlimited(Registry),
+    Limited(Registry, Arc<SummaryRequests<T>>),
+    Unlimited(Registry, ScrapeMetrics),
 }
 
 #[derive(Debug, thiserror::Error)]
-enum AccessError {
+pub(super) enum AccessError {
     #[error("fping process terminated")]
     FpingProcessDead,
+    #[error("another summary request is already queued")]
+    ChannelFull,
     #[error("another request still in progress")]
     RequestDropped(#[from] oneshot::error::RecvError),
 }
 
 impl warp::reject::Reject for AccessError {}
 
+impl AccessError {
+    fn status(&self) -> StatusCode {
+        match self {
+            AccessError::FpingProcessDead | AccessError::ChannelFull => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            AccessError::RequestDropped(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Compares without returning early, so the time taken does not reveal how
+/// much of the credentials matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Checks the `Authorization: Basic` header, passes everything if no
+/// credentials are configured.
+fn authorized(auth: Option<&BasicAuth>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let expected: Option<Arc<[u8]>> = auth.map(|auth| {
+        format!("{}:{}", auth.user, auth.password)
+            .into_bytes()
+            .into()
+    });
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let expected = expected.clone();
+            async move {
+                let expected = match expected {
+                    Some(expected) => expected,
+                    None => return Ok(()),
+                };
+                let presented = header
+                    .as_deref()
+                    .and_then(|header| header.split_once(' '))
+                    .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+                    .and_then(|(_, credentials)| base64::decode(credentials.trim()).ok());
+                match presented {
+                    Some(presented) if constant_time_eq(&presented, &expected) => Ok(()),
+                    _ => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Plaintext responses for failed scrapes and missing credentials instead
+/// of warp's default page
+async fn recover_access_error(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        let reply = warp::reply::with_status("unauthorized", StatusCode::UNAUTHORIZED);
+        return Ok(
+            with_header(reply, "www-authenticate", "Basic realm=\"fping_exporter\"")
+                .into_response(),
+        );
+    }
+    match rejection.find::<AccessError>() {
+        Some(e) => Ok(warp::reply::with_status(e.to_string(), e.status()).into_response()),
+        None => Err(rejection),
+    }
+}
+
 impl<T> RegistryAccess<T> {
     pub fn new(
         reg: &Registry,
         buffer: Option<usize>,
+        cooldown: Duration,
+        overload: OverloadBehavior,
+        metrics: ScrapeMetrics,
     ) -> (Self, Option<mpsc::Receiver<oneshot::Sender<T>>>) {
         match buffer {
             Some(buffer) => {
                 let (tx, rx) = mpsc::channel(buffer);
-                (Self::Limited(reg.clone(), tx), Some(rx))
+                let requests = SummaryRequests {
+                    tx,
+                    cooldown,
+                    last_summary: Mutex::new(None),
+                    overload,
+                    metrics,
+                };
+                (Self::Limited(reg.clone(), Arc::new(requests)), Some(rx))
             }
-            None => (Self::Unlimited(reg.clone()), None),
+            None => (Self::Unlimited(reg.clone(), metrics), None),
+        }
+    }
+
+    /// Requests a summary regardless of the scrape cooldown
+    async fn summarize(&self) -> Result<(), AccessError> {
+        match self {
+            RegistryAccess::Limited(_, requests) => requests.request().await,
+            RegistryAccess::Unlimited(..) => Ok(()),
         }
     }
 
-    async fn gather(self) -> Result<Vec<MetricFamily>, AccessError> {
+    /// Summary requested on demand through the debug endpoint
+    async fn gather_now(self) -> Result<Vec<MetricFamily>, AccessError> {
+        self.summarize().await?;
         match self {
-            RegistryAccess::Limited(reg, tx) => {
-                let (tx2, rx) = oneshot::channel();
-                tx.send(tx2)
-                    .await
-                    .map_err(|_| AccessError::FpingProcessDead)?;
-                // guard using return value
-                let _ = rx.await?;
-                Ok(reg.gather())
+            RegistryAccess::Limited(reg, _) | RegistryAccess::Unlimited(reg, _) => Ok(reg.gather()),
+        }
+    }
+
+    pub(super) async fn gather(self) -> Result<Vec<MetricFamily>, AccessError> {
+        self.gather_timed(false).await
+    }
+
+    /// Gathers for a request to the metrics endpoint, the only gathering
+    /// which counts as a scrape for last_scrape_timestamp_seconds
+    async fn scrape(self) -> Result<Vec<MetricFamily>, AccessError> {
+        self.gather_timed(true).await
+    }
+
+    async fn gather_timed(self, scrape: bool) -> Result<Vec<MetricFamily>, AccessError> {
+        let start = Instant::now();
+        let duration = match &self {
+            RegistryAccess::Limited(_, requests) => requests.metrics.duration.clone(),
+            RegistryAccess::Unlimited(_, metrics) => metrics.duration.clone(),
+        };
+        let res = self.gather_untimed(scrape).await;
+        duration.observe(start.elapsed().as_secs_f64());
+        res
+    }
+
+    async fn gather_untimed(self, scrape: bool) -> Result<Vec<MetricFamily>, AccessError> {
+        let (reg, metrics) = match self {
+            RegistryAccess::Limited(reg, requests) => {
+                if requests.cooling_down() {
+                    // serve the results of the previous summary
+                    requests.metrics.throttled.inc();
+                } else {
+                    match requests.request().await {
+                        Err(AccessError::ChannelFull)
+                            if requests.overload == OverloadBehavior::Cached => {}
+                        res => res?,
+                    }
+                }
+                (reg, requests.metrics.clone())
             }
-            RegistryAccess::Unlimited(reg) => Ok(reg.gather()),
+            RegistryAccess::Unlimited(reg, metrics) => (reg, metrics),
+        };
+        // ahead of gathering, so the scrape carries its own timestamp
+        if scrape {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            metrics.last_success.set(now.as_secs_f64());
         }
+        Ok(reg.gather())
     }
 }
 
@@ -67,52 +384,692 @@ impl<T> Clone for RegistryAccess<T> {
             RegistryAccess::Limited(ref r, ref rx) => {
                 RegistryAccess::Limited(r.clone(), rx.clone())
             }
-            RegistryAccess::Unlimited(ref r) => RegistryAccess::Unlimited(r.clone()),
+            RegistryAccess::Unlimited(ref r, ref metrics) => {
+                RegistryAccess::Unlimited(r.clone(), metrics.clone())
+            }
+        }
+    }
+}
+
+/// Populates the summary derived metrics once fping has been running for
+/// `delay`, rather than waiting for the first scrape.
+pub async fn initial_summary<T>(delay: Duration, reg: RegistryAccess<T>) {
+    tokio::time::sleep(delay).await;
+    // requests are dropped until fping has produced output
+    while let Err(e) = reg.summarize().await {
+        trace!("initial summary failed: {}", e);
+        if let AccessError::FpingProcessDead = e {
+            return;
         }
+        tokio::time::sleep(Duration::from_millis(150)).await;
     }
+    debug!("initial summary completed");
+}
+
+/// Matches `path` exactly, one segment at a time since `warp::path` never
+/// matches across a `/`. Empty segments are ignored, so a leading slash is
+/// fine and an empty path is the root.
+fn exact_path(path: &str) -> BoxedFilter<()> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .fold(warp::any().boxed(), |filter, segment| {
+            filter.and(warp::path(segment.to_owned())).boxed()
+        })
+        .and(warp::path::end())
+        .boxed()
+}
+
+/// Liveness of fping for orchestrators, answered without requesting a
+/// summary so it stays fast while fping is busy.
+fn health(
+    fping_alive: Arc<AtomicBool>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("health").and(warp::path::end()).map(move || {
+        if fping_alive.load(Ordering::Relaxed) {
+            warp::reply::with_status("fping is running", StatusCode::OK)
+        } else {
+            warp::reply::with_status("fping has exited", StatusCode::SERVICE_UNAVAILABLE)
+        }
+    })
+}
+
+/// Sent to the owner of fping by POST /-/reload, answered with the reason
+/// the reload failed
+pub type ReloadRequest = oneshot::Sender<Result<(), String>>;
+
+fn reload(
+    tx: Option<mpsc::Sender<ReloadRequest>>,
+    auth: impl Filter<Extract = (), Error = Rejection> + Clone + Send + Sync + 'static,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path("-"))
+        .and(warp::path("reload"))
+        .and(warp::path::end())
+        .and(auth)
+        .and_then(move || {
+            let tx = tx.clone();
+            async move {
+                let tx = tx.ok_or_else(warp::reject::not_found)?;
+                let (responder, response) = oneshot::channel();
+                if tx.send(responder).await.is_err() {
+                    return Err(warp::reject::custom(AccessError::FpingProcessDead));
+                }
+                let reply = match response.await {
+                    Ok(Ok(())) => warp::reply::with_status("reloaded".to_owned(), StatusCode::OK),
+                    Ok(Err(e)) => warp::reply::with_status(e, StatusCode::INTERNAL_SERVER_ERROR),
+                    Err(_) => return Err(warp::reject::custom(AccessError::FpingProcessDead)),
+                };
+                Ok::<_, Rejection>(reply)
+            }
+        })
+}
+
+/// What is running, served on /version for deployment tooling
+#[derive(Debug, Clone)]
+pub struct VersionInfo {
+    pub fping_version: String,
+    /// Whether scrapes request summaries from fping
+    pub summaries: bool,
 }
 
+impl VersionInfo {
+    fn to_json(&self) -> String {
+        let mut out = String::from("{\"version\":");
+        json::string(&mut out, crate_version!());
+        out.push_str(",\"fping_version\":");
+        json::string(&mut out, &self.fping_version);
+        out.push_str(",\"summaries\":");
+        out.push_str(if self.summaries { "true" } else { "false" });
+        out.push('}');
+        out
+    }
+}
+
+fn version(info: &VersionInfo) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let body = info.to_json();
+    warp::path("version")
+        .and(warp::path::end())
+        .map(move || with_header(body.clone(), "Content-Type", JSON_FORMAT))
+}
+
+/// Resolves once `stop` is set, or never if its sender is gone
+async fn stopped(stop: &mut watch::Receiver<bool>) {
+    while !*stop.borrow() {
+        if stop.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Runs a server with graceful shutdown, cutting off whatever is still in
+/// flight once `timeout` has passed since `draining` fired.
+async fn drain(
+    server: impl std::future::Future<Output = ()>,
+    draining: oneshot::Receiver<()>,
+    timeout: Duration,
+) {
+    let deadline = async {
+        match draining.await {
+            Ok(()) => tokio::time::sleep(timeout).await,
+            Err(_) => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        _ = server => {}
+        _ = deadline => warn!(target: "metrics", "scrapes still in progress after {}, closing them", humantime::format_duration(timeout)),
+    }
+}
+
+/// Exporter state the endpoints besides the metrics report on or act upon
+#[derive(Debug)]
+pub struct ServerContext {
+    pub fping_alive: Arc<AtomicBool>,
+    pub countdown: ShutdownCountdown,
+    pub version: VersionInfo,
+    /// Only set with --enable-lifecycle
+    pub reload: Option<mpsc::Sender<ReloadRequest>>,
+}
+
+/// Serves metrics until the runtime limit passes or `stop` is set, then
+/// drains in-flight scrapes for at most `args.drain_timeout`.
 pub async fn publish_metrics<T: Send + 'static>(
     args: &MetricArgs,
+    tls: Option<TlsMaterial>,
     reg: RegistryAccess<T>,
-) -> Result<(), warp::Error> {
-    let mut count = 0;
-    loop {
-        count += 1;
-        let reg = reg.clone();
-        trace!("warming up round {}", count);
-        if reg.gather().await.is_ok() {
-            break;
-        } else {
-            tokio::time::sleep(Duration::from_millis(150)).await;
+    context: ServerContext,
+    mut stop: watch::Receiver<bool>,
+) -> Result<(), BindError> {
+    let ServerContext {
+        fping_alive,
+        countdown,
+        version: version_info,
+        reload: reload_tx,
+    } = context;
+    let warm_up = async {
+        let mut count = 0;
+        loop {
+            count += 1;
+            let reg = reg.clone();
+            trace!("warming up round {}", count);
+            if reg.gather().await.is_ok() {
+                break;
+            } else {
+                tokio::time::sleep(Duration::from_millis(150)).await;
+            }
         }
+    };
+    tokio::select! {
+        _ = warm_up => {}
+        _ = stopped(&mut stop) => return Ok(()),
     }
 
-    let handler = move || {
+    let summary_handler = {
         let reg = reg.clone();
-        async move {
-            let metrics = reg.gather().await?;
+        let enabled = args.debug_endpoints;
+        let in_progress = Arc::new(tokio::sync::Mutex::new(()));
+        move |accept: Option<String>| {
+            let reg = reg.clone();
+            let in_progress = in_progress.clone();
+            async move {
+                if !enabled {
+                    return Err(warp::reject::not_found());
+                }
+                let _guard = match in_progress.try_lock() {
+                    Ok(guard) => guard,
+                    Err(_) => {
+                        let reply = warp::reply::with_status(
+                            "manual summary already in progress",
+                            StatusCode::TOO_MANY_REQUESTS,
+                        );
+                        return Ok(reply.into_response());
+                    }
+                };
+                let metrics = reg.gather_now().await?;
 
-            Ok::<_, Rejection>(encode_metrics::<TextEncoder>(&metrics).unwrap())
+                Ok::<_, Rejection>(negotiate(accept.as_deref(), &metrics))
+            }
         }
     };
 
-    let metrics = warp::path(args.path.clone())
-        .and(warp::path::end())
-        .and_then(handler);
+    let handler = move |accept: Option<String>| {
+        let reg = reg.clone();
+        async move {
+            let metrics = reg.scrape().await?;
+
+            Ok::<_, Rejection>(negotiate(accept.as_deref(), &metrics))
+        }
+    };
 
-    let (_, server) = warp::serve(metrics).try_bind_with_graceful_shutdown(args.addr, {
-        info!(target: "metrics", "publishing metrics on http://{}/{}", args.addr, args.path);
+    if args.auth.is_some() && tls.is_none() {
+        warn!(target: "metrics", "basic auth credentials are sent in plaintext without TLS");
+    }
+    let auth = authorized(args.auth.as_ref());
+    let metrics = exact_path(&args.path)
+        .and(auth.clone())
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(handler)
+        .or(warp::post()
+            .and(warp::path("summary"))
+            .and(warp::path::end())
+            .and(auth.clone())
+            .and(warp::header::optional::<String>("accept"))
+            .and_then(summary_handler))
+        .or(reload(reload_tx, auth.clone()))
+        .or(health(fping_alive))
+        .or(version(&version_info))
+        .recover(recover_access_error);
 
+    let (start_drain, draining) = oneshot::channel();
+    let shutdown = {
         let timeout = args.runtime_limit;
         async move {
-            match timeout {
-                Some(timeout) => tokio::time::sleep(timeout).await,
-                None => std::future::pending().await,
+            let limit = async {
+                match timeout {
+                    Some(timeout) => {
+                        countdown.start(timeout);
+                        tokio::time::sleep(timeout).await
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = limit => {}
+                _ = stopped(&mut stop) => {}
+            }
+            debug!(target: "metrics", "draining metrics server");
+            let _ = start_drain.send(());
+        }
+    };
+
+    // TLS is rejected along with --metrics-socket
+    match (tls, args.socket.as_deref()) {
+        (_, Some(path)) => {
+            let listener = listener::bind_unix(path)?;
+            info!(target: "metrics", "publishing metrics on {:?} at /{}", path, args.path);
+            let incoming = UnixListenerStream::new(listener);
+            let server =
+                warp::serve(metrics).serve_incoming_with_graceful_shutdown(incoming, shutdown);
+            drain(server, draining, args.drain_timeout).await;
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!(target: "metrics", "unable to remove {:?}: {}", path, e);
             }
         }
-    })?;
+        (tls, None) => {
+            let listener = listener::bind(args)?;
+            // differs from args.addr when socket activated
+            let addr = listener.local_addr().unwrap_or(args.addr);
+            let reuse_port = if args.reuse_port {
+                " (SO_REUSEPORT)"
+            } else {
+                ""
+            };
+            let scheme = if tls.is_some() { "https" } else { "http" };
+            info!(target: "metrics", "publishing metrics on {}://{}/{}{}", scheme, addr, args.path, reuse_port);
+            match tls {
+                Some(tls) => {
+                    let (incoming, acceptor) = tls.accept(listener);
+                    let server = warp::serve(metrics)
+                        .serve_incoming_with_graceful_shutdown(incoming, shutdown);
+                    drain(server, draining, args.drain_timeout).await;
+                    acceptor.abort();
+                }
+                None => {
+                    let incoming = TcpListenerStream::new(listener);
+                    let server = warp::serve(metrics)
+                        .serve_incoming_with_graceful_shutdown(incoming, shutdown);
+                    drain(server, draining, args.drain_timeout).await;
+                }
+            }
+        }
+    }
 
-    server.await;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn overloaded(overload: OverloadBehavior) -> (Result<(), AccessError>, ScrapeMetrics) {
+        let metrics = ScrapeMetrics::new("fping");
+        let (reg, _rx) = RegistryAccess::<()>::new(
+            &Registry::new(),
+            Some(1),
+            Duration::ZERO,
+            overload,
+            metrics.clone(),
+        );
+        // nothing consumes the channel, so the first request stays queued
+        let queued = tokio::spawn(reg.clone().scrape());
+        tokio::task::yield_now().await;
+
+        let res = reg.scrape().await.map(|_| ());
+        queued.abort();
+        (res, metrics)
+    }
+
+    #[tokio::test]
+    async fn full_control_channel() {
+        let (res, metrics) = overloaded(OverloadBehavior::Shed).await;
+        assert!(matches!(res, Err(AccessError::ChannelFull)));
+        assert_eq!(metrics.control_full.get(), 1);
+
+        let (res, metrics) = overloaded(OverloadBehavior::Cached).await;
+        assert!(res.is_ok());
+        assert_eq!(metrics.control_full.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn last_scrape_only_on_success() {
+        let (res, metrics) = overloaded(OverloadBehavior::Shed).await;
+        assert!(res.is_err());
+        assert_eq!(metrics.last_success.get(), 0.0);
+
+        let (res, metrics) = overloaded(OverloadBehavior::Cached).await;
+        assert!(res.is_ok());
+        assert!(metrics.last_success.get() > 1.6e9);
+
+        // fping went away before answering the summary request
+        let metrics = ScrapeMetrics::new("fping");
+        let (reg, rx) = RegistryAccess::<()>::new(
+            &Registry::new(),
+            Some(1),
+            Duration::ZERO,
+            OverloadBehavior::Shed,
+            metrics.clone(),
+        );
+        let mut rx = rx.unwrap();
+        tokio::spawn(async move { drop(rx.recv().await) });
+        assert!(matches!(
+            reg.scrape().await,
+            Err(AccessError::RequestDropped(_))
+        ));
+        assert_eq!(metrics.last_success.get(), 0.0);
+
+        // a scrape carries its own timestamp
+        let metrics = ScrapeMetrics::new("fping");
+        let registry = Registry::new();
+        registry.register(Box::new(metrics.clone())).unwrap();
+        let (reg, _) = RegistryAccess::<()>::new(
+            &registry,
+            None,
+            Duration::ZERO,
+            OverloadBehavior::Shed,
+            metrics.clone(),
+        );
+        // warm-up, textfile and graphite gather without scraping
+        reg.clone().gather().await.unwrap();
+        assert_eq!(metrics.last_success.get(), 0.0);
+        let families = reg.scrape().await.unwrap();
+        let last = families
+            .iter()
+            .find(|family| family.get_name() == "fping_last_scrape_timestamp_seconds")
+            .unwrap();
+        assert!(last.get_metric()[0].get_gauge().get_value() > 1.6e9);
+    }
+
+    #[tokio::test]
+    async fn scrape_duration_includes_summary() {
+        let metrics = ScrapeMetrics::new("fping");
+        let (reg, rx) = RegistryAccess::<()>::new(
+            &Registry::new(),
+            Some(1),
+            Duration::ZERO,
+            OverloadBehavior::Shed,
+            metrics.clone(),
+        );
+        // answers the summary request after a delay, like fping would
+        let mut rx = rx.unwrap();
+        tokio::spawn(async move {
+            let summary = rx.recv().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let _ = summary.send(());
+        });
+
+        reg.gather().await.unwrap();
+        assert_eq!(metrics.duration.get_sample_count(), 1);
+        assert!(metrics.duration.get_sample_sum() >= 0.05);
+    }
+
+    #[tokio::test]
+    async fn health_follows_fping() {
+        let alive = Arc::new(AtomicBool::new(true));
+        let filter = health(alive.clone());
+
+        let res = warp::test::request().path("/health").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        alive.store(false, Ordering::Relaxed);
+        let res = warp::test::request().path("/health").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.body(), "fping has exited");
+    }
+
+    #[tokio::test]
+    async fn reload_is_answered_by_the_owner() {
+        let request = || warp::test::request().method("POST").path("/-/reload");
+
+        let res = request().reply(&reload(None, authorized(None))).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let (tx, mut rx) = mpsc::channel::<ReloadRequest>(1);
+        let filter = reload(Some(tx), authorized(None)).recover(recover_access_error);
+        let owner = tokio::spawn(async move {
+            rx.recv().await.unwrap().send(Ok(())).unwrap();
+            rx.recv()
+                .await
+                .unwrap()
+                .send(Err("invalid target in targets.txt".into()))
+                .unwrap();
+        });
+        let res = request().reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request().reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(res.body(), "invalid target in targets.txt");
+        owner.await.unwrap();
+
+        // the owner is gone once fping has exited
+        let res = request().reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let res = warp::test::request().path("/-/reload").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn multi_segment_path() {
+        for path in &["probe/metrics", "/probe/metrics", "probe//metrics/"] {
+            let filter = exact_path(path).map(|| "metrics");
+            let res = warp::test::request()
+                .path("/probe/metrics")
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), StatusCode::OK, "{}", path);
+            assert_eq!(res.body(), "metrics");
+            for other in &["/probe", "/metrics", "/probe/metrics/more"] {
+                let res = warp::test::request().path(other).reply(&filter).await;
+                assert_eq!(res.status(), StatusCode::NOT_FOUND, "{} on {}", other, path);
+            }
+        }
+
+        let filter = exact_path("/").map(|| "metrics");
+        let res = warp::test::request().path("/").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    fn test_context() -> ServerContext {
+        ServerContext {
+            fping_alive: Arc::new(AtomicBool::new(true)),
+            countdown: ShutdownCountdown::new("fping"),
+            version: VersionInfo {
+                fping_version: "5.1.0".into(),
+                summaries: false,
+            },
+            reload: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_tls() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let port = port.to_string();
+        let args =
+            crate::args::metric_args(&["--metrics-bind", "127.0.0.1", "--metrics-port", &port]);
+        let addr = args.addr;
+        let (reg, _) = RegistryAccess::<()>::new(
+            &Registry::new(),
+            None,
+            Duration::ZERO,
+            OverloadBehavior::Shed,
+            ScrapeMetrics::new("fping"),
+        );
+        let (stop_tx, stop) = watch::channel(false);
+        let tls = super::super::tls::test_material();
+        let server = tokio::spawn(async move {
+            publish_metrics(&args, Some(tls), reg, test_context(), stop).await
+        });
+
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        let url = format!("https://{}/health", addr);
+        let mut res = client.get(&url).send().await;
+        for _ in 0..50 {
+            if res.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            res = client.get(&url).send().await;
+        }
+        assert_eq!(res.unwrap().status(), StatusCode::OK);
+        // plaintext is not answered
+        assert!(reqwest::get(format!("http://{}/health", addr))
+            .await
+            .is_err());
+
+        stop_tx.send(true).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn tls_port_in_use() {
+        let occupied = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = occupied.local_addr().unwrap().port().to_string();
+        let args =
+            crate::args::metric_args(&["--metrics-bind", "127.0.0.1", "--metrics-port", &port]);
+        let (reg, _) = RegistryAccess::<()>::new(
+            &Registry::new(),
+            None,
+            Duration::ZERO,
+            OverloadBehavior::Shed,
+            ScrapeMetrics::new("fping"),
+        );
+        let (_stop_tx, stop) = watch::channel(false);
+        let tls = super::super::tls::test_material();
+        let err = publish_metrics(&args, Some(tls), reg, test_context(), stop)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, BindError::Socket(..)), "{:?}", err);
+        assert_eq!(
+            crate::exit::FailureClass::of(&err.into()) as i32,
+            4,
+            "exit code of a failed bind"
+        );
+    }
+
+    #[tokio::test]
+    async fn version_as_json() {
+        let filter = version(&VersionInfo {
+            fping_version: "5.1.0".into(),
+            summaries: true,
+        });
+        let res = warp::test::request().path("/version").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "application/json");
+        assert_eq!(
+            res.body(),
+            &format!(
+                r#"{{"version":"{}","fping_version":"5.1.0","summaries":true}}"#,
+                crate_version!()
+            )
+        );
+    }
+
+    #[test]
+    fn negotiated_content_type() {
+        let content_type = |accept| negotiate(accept, &[]).headers()["content-type"].clone();
+        assert_eq!(content_type(None), "text/plain; version=0.0.4");
+        assert_eq!(content_type(Some("application/json")), "application/json");
+        assert_eq!(
+            content_type(Some(
+                "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5"
+            )),
+            "application/openmetrics-text; version=1.0.0; charset=utf-8"
+        );
+    }
+
+    #[test]
+    fn countdown_only_with_runtime_limit() {
+        let countdown = ShutdownCountdown::new("fping");
+        assert!(countdown.collect().is_empty());
+
+        countdown.start(Duration::from_secs(60));
+        let families = countdown.collect();
+        assert_eq!(
+            families[0].get_name(),
+            "fping_exporter_shutdown_seconds_remaining"
+        );
+        let remaining = families[0].get_metric()[0].get_gauge().get_value();
+        assert!(remaining > 59.0 && remaining <= 60.0, "{}", remaining);
+    }
+
+    #[tokio::test]
+    async fn basic_auth() {
+        let auth = BasicAuth {
+            user: "prometheus".into(),
+            password: "secret".into(),
+        };
+        let filter = authorized(Some(&auth))
+            .map(|| "metrics")
+            .recover(recover_access_error);
+        let request = |authorization: Option<&str>| {
+            let request = warp::test::request().path("/metrics");
+            match authorization {
+                Some(value) => request.header("authorization", value),
+                None => request,
+            }
+        };
+
+        // prometheus:secret
+        let res = request(Some("Basic cHJvbWV0aGV1czpzZWNyZXQ="))
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        // prometheus:guess
+        for authorization in &[
+            None,
+            Some("Basic cHJvbWV0aGV1czpndWVzcw=="),
+            Some("Bearer x"),
+        ] {
+            let res = request(*authorization).reply(&filter).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(
+                res.headers()["www-authenticate"],
+                "Basic realm=\"fping_exporter\""
+            );
+        }
+
+        let open = authorized(None).map(|| "metrics");
+        let res = request(None).reply(&open).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn access_errors_as_plaintext() {
+        async fn respond(e: AccessError) -> (StatusCode, String) {
+            let reply = recover_access_error(warp::reject::custom(e))
+                .await
+                .unwrap()
+                .into_response();
+            let status = reply.status();
+            let body = warp::hyper::body::to_bytes(reply.into_body())
+                .await
+                .unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        }
+
+        assert_eq!(
+            respond(AccessError::FpingProcessDead).await,
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "fping process terminated".to_owned()
+            )
+        );
+        assert_eq!(
+            respond(AccessError::ChannelFull).await,
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "another summary request is already queued".to_owned()
+            )
+        );
+        let (tx, rx) = oneshot::channel::<()>();
+        drop(tx);
+        assert_eq!(
+            respond(AccessError::RequestDropped(rx.await.unwrap_err())).await,
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "another request still in progress".to_owned()
+            )
+        );
+
+        assert!(recover_access_error(warp::reject::not_found())
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,247 @@
+//! JSON rendering of the registry for clients that ask for it.
+//!
+//! Follows the layout of prom2json: a list of families with their `name`,
+//! `help`, `type` and `metrics`. Sample values are strings so `NaN` and
+//! `+Inf` survive, histograms and summaries carry `count`, `sum` and their
+//! `buckets` or `quantiles` keyed by bound.
+
+use std::{fmt::Write as _, io::Write};
+
+use prometheus::{
+    proto::{Metric, MetricFamily, MetricType},
+    Encoder,
+};
+
+pub const JSON_FORMAT: &str = "application/json";
+
+#[derive(Debug, Default)]
+pub struct JsonEncoder;
+
+pub(super) fn string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn number(out: &mut String, value: f64) {
+    let value = if value.is_nan() {
+        "NaN".to_owned()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_owned()
+    } else {
+        value.to_string()
+    };
+    string(out, &value);
+}
+
+fn key(out: &mut String, first: &mut bool, name: &str) {
+    if !std::mem::take(first) {
+        out.push(',');
+    }
+    string(out, name);
+    out.push(':');
+}
+
+/// `{"<bound>":"<value>",...}`
+fn bounds(out: &mut String, entries: impl Iterator<Item = (f64, f64)>) {
+    out.push('{');
+    let mut first = true;
+    for (bound, value) in entries {
+        let mut bound_key = String::new();
+        number(&mut bound_key, bound);
+        if !std::mem::take(&mut first) {
+            out.push(',');
+        }
+        out.push_str(&bound_key);
+        out.push(':');
+        number(out, value);
+    }
+    out.push('}');
+}
+
+fn metric(out: &mut String, kind: MetricType, metric: &Metric) {
+    out.push('{');
+    let mut first = true;
+    if !metric.get_label().is_empty() {
+        key(out, &mut first, "labels");
+        out.push('{');
+        let mut first_label = true;
+        for label in metric.get_label() {
+            key(out, &mut first_label, label.get_name());
+            string(out, label.get_value());
+        }
+        out.push('}');
+    }
+    if metric.has_timestamp_ms() {
+        key(out, &mut first, "timestamp_ms");
+        string(out, &metric.get_timestamp_ms().to_string());
+    }
+    match kind {
+        MetricType::COUNTER => {
+            key(out, &mut first, "value");
+            number(out, metric.get_counter().get_value());
+        }
+        MetricType::GAUGE => {
+            key(out, &mut first, "value");
+            number(out, metric.get_gauge().get_value());
+        }
+        MetricType::UNTYPED => {
+            key(out, &mut first, "value");
+            number(out, metric.get_untyped().get_value());
+        }
+        MetricType::HISTOGRAM => {
+            let histogram = metric.get_histogram();
+            key(out, &mut first, "buckets");
+            bounds(
+                out,
+                histogram
+                    .get_bucket()
+                    .iter()
+                    .map(|b| (b.get_upper_bound(), b.get_cumulative_count() as f64)),
+            );
+            key(out, &mut first, "count");
+            number(out, histogram.get_sample_count() as f64);
+            key(out, &mut first, "sum");
+            number(out, histogram.get_sample_sum());
+        }
+        MetricType::SUMMARY => {
+            let summary = metric.get_summary();
+            key(out, &mut first, "quantiles");
+            bounds(
+                out,
+                summary
+                    .get_quantile()
+                    .iter()
+                    .map(|q| (q.get_quantile(), q.get_value())),
+            );
+            key(out, &mut first, "count");
+            number(out, summary.get_sample_count() as f64);
+            key(out, &mut first, "sum");
+            number(out, summary.get_sample_sum());
+        }
+    }
+    out.push('}');
+}
+
+fn family(out: &mut String, family: &MetricFamily) {
+    out.push('{');
+    let mut first = true;
+    key(out, &mut first, "name");
+    string(out, family.get_name());
+    key(out, &mut first, "help");
+    string(out, family.get_help());
+    key(out, &mut first, "type");
+    string(out, &format!("{:?}", family.get_field_type()));
+    key(out, &mut first, "metrics");
+    out.push('[');
+    for (i, m) in family.get_metric().iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        metric(out, family.get_field_type(), m);
+    }
+    out.push_str("]}");
+}
+
+impl Encoder for JsonEncoder {
+    fn encode<W: Write>(
+        &self,
+        families: &[MetricFamily],
+        writer: &mut W,
+    ) -> prometheus::Result<()> {
+        let mut out = String::from("[");
+        for (i, f) in families.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            family(&mut out, f);
+        }
+        out.push(']');
+        writer.write_all(out.as_bytes())?;
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        JSON_FORMAT
+    }
+}
+
+/// Whether the `Accept` header prefers a media type for which `wanted`
+/// holds, given its parameters, over every other type. Scrapers that list
+/// several formats equally keep getting the text format.
+pub(super) fn preferred(accept: Option<&str>, wanted: impl Fn(&str, &[&str]) -> bool) -> bool {
+    let mut matched = 0.0;
+    let mut others = 0.0;
+    for range in accept.unwrap_or_default().split(',') {
+        let mut params = range.split(';').map(str::trim);
+        let media_type = params.next().unwrap_or_default();
+        let params: Vec<&str> = params.collect();
+        let quality: f64 = params
+            .iter()
+            .filter_map(|param| param.strip_prefix("q="))
+            .find_map(|q| q.parse().ok())
+            .unwrap_or(1.0);
+        if wanted(media_type, &params) {
+            matched = quality.max(matched);
+        } else if !media_type.is_empty() {
+            others = quality.max(others);
+        }
+    }
+    matched > others
+}
+
+/// Picks JSON only if the `Accept` header prefers it over every other type
+pub fn prefers_json(accept: Option<&str>) -> bool {
+    preferred(accept, |media_type, _| {
+        media_type.eq_ignore_ascii_case(JSON_FORMAT)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::fixtures::{families, PROMETHEUS_ACCEPT},
+        *,
+    };
+
+    #[test]
+    fn prom2json_layout() {
+        let families = families();
+        let mut out = vec![];
+        JsonEncoder.encode(&families, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                r#"[{"name":"fping_icmp_reply_total","help":"replies","type":"COUNTER","metrics":["#,
+                r#"{"labels":{"addr":"8.8.8.8","target":"dns.google"},"value":"3"}]},"#,
+                r#"{"name":"loss","help":"say \"hi\"","type":"GAUGE","metrics":[{"value":"NaN"}]},"#,
+                r#"{"name":"rtt_seconds","help":"rtt","type":"HISTOGRAM","metrics":["#,
+                r#"{"buckets":{"0.5":"1"},"count":"2","sum":"1"}]}]"#,
+            )
+        );
+    }
+
+    #[test]
+    fn accept_negotiation() {
+        assert!(!prefers_json(None));
+        assert!(!prefers_json(Some("text/plain")));
+        assert!(prefers_json(Some("application/json")));
+        assert!(prefers_json(Some("text/plain;q=0.5, Application/JSON")));
+        assert!(!prefers_json(Some("application/json, text/plain")));
+        assert!(!prefers_json(Some("application/json;q=0")));
+        // what prometheus sends
+        assert!(!prefers_json(Some(PROMETHEUS_ACCEPT)));
+    }
+}
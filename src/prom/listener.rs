@@ -0,0 +1,167 @@
+use std::{
+    env, io,
+    net::SocketAddr,
+    os::unix::{
+        fs::FileTypeExt,
+        io::{FromRawFd, RawFd},
+        net::UnixStream,
+    },
+    path::{Path, PathBuf},
+};
+
+use socket2::{Domain, Socket, Type};
+use thiserror::Error;
+use tokio::net::{TcpListener, UnixListener};
+
+use crate::args::MetricArgs;
+
+#[derive(Debug, Error)]
+pub enum BindError {
+    #[error("unable to bind {1}: {0}")]
+    Socket(#[source] io::Error, SocketAddr),
+    #[error("unable to use the socket passed by systemd: {0}")]
+    Inherited(#[source] io::Error),
+    #[error("unable to bind {1:?}: {0}")]
+    Unix(#[source] io::Error, PathBuf),
+}
+
+#[cfg(not(any(target_os = "solaris", target_os = "illumos")))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(any(target_os = "solaris", target_os = "illumos"))]
+fn set_reuse_port(_: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
+
+fn configure(args: &MetricArgs) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(args.addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if args.addr.is_ipv6() {
+        // the OS default differs between platforms, always set it explicitly
+        socket.set_only_v6(args.v6only)?;
+    }
+    if args.reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    socket.bind(&args.addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// First file descriptor passed through socket activation, see sd_listen_fds(3)
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+fn invalid_activation(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Takes over the socket systemd passed through socket activation, if any.
+/// `LISTEN_PID` keeps fping from picking up the variables as well.
+fn inherited() -> io::Result<Option<Socket>> {
+    match env::var("LISTEN_PID") {
+        Ok(pid) if pid.parse() == Ok(std::process::id()) => {}
+        _ => return Ok(None),
+    }
+    let fds = env::var("LISTEN_FDS").unwrap_or_default();
+    if fds != "1" {
+        return Err(invalid_activation(format!(
+            "expected a single socket, LISTEN_FDS is {:?}",
+            fds
+        )));
+    }
+
+    // systemd hands over ownership of the descriptor
+    let socket = unsafe { Socket::from_raw_fd(SD_LISTEN_FDS_START) };
+    socket.set_cloexec(true)?;
+    if socket.r#type()? != Type::STREAM || socket.local_addr()?.as_socket().is_none() {
+        return Err(invalid_activation(
+            "expected a TCP socket, check ListenStream= in the socket unit".to_owned(),
+        ));
+    }
+    // no-op if systemd already listens on it, which is the default
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(Some(socket))
+}
+
+/// Binds the metrics listener manually for socket options warp does not expose,
+/// being `SO_REUSEPORT` and `IPV6_V6ONLY`. A socket passed by systemd through
+/// socket activation is used instead if present, in which case the bind
+/// options are ignored.
+pub fn bind(args: &MetricArgs) -> Result<TcpListener, BindError> {
+    let socket = match inherited().map_err(BindError::Inherited)? {
+        Some(socket) => {
+            info!(target: "metrics", "using the socket passed by systemd");
+            socket
+        }
+        None => configure(args).map_err(|e| BindError::Socket(e, args.addr))?,
+    };
+    TcpListener::from_std(socket.into()).map_err(|e| BindError::Socket(e, args.addr))
+}
+
+/// Removes the socket a previous instance left behind, refusing to touch
+/// other files or a socket that still accepts connections.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "file exists and is not a socket",
+        ));
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "another process is listening on the socket",
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            info!(target: "metrics", "removing stale socket {:?}", path);
+            std::fs::remove_file(path)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+pub fn bind_unix(path: &Path) -> Result<UnixListener, BindError> {
+    remove_stale_socket(path)
+        .and_then(|_| UnixListener::bind(path))
+        .map_err(|e| BindError::Unix(e, path.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replace_stale_socket() {
+        let path =
+            std::env::temp_dir().join(format!("fping_exporter_socket_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let listener = bind_unix(&path).unwrap();
+        assert!(matches!(
+            bind_unix(&path),
+            Err(BindError::Unix(e, _)) if e.kind() == io::ErrorKind::AddrInUse
+        ));
+        drop(listener);
+        // the socket file outlives the listener
+        assert!(path.exists());
+        let listener = bind_unix(&path).unwrap();
+        drop(listener);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(bind_unix(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,49 +1,117 @@
 use std::{
+    collections::HashMap,
     convert::TryInto,
     sync::{Arc, Mutex},
 };
 
-use prometheus::{core::Collector, histogram_opts, opts, HistogramVec, IntCounterVec, IntGaugeVec};
+use prometheus::{
+    core::{Collector, MetricVec, MetricVecBuilder},
+    histogram_opts, opts,
+    proto::MetricFamily,
+    GaugeVec, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+};
+
+use crate::fping::{icmp_error_kind, Control, Ping, Protocol, SentReceivedSummary, LABEL_NAMES};
 
-use crate::fping::{Control, Ping, SentReceivedSummary, LABEL_NAMES};
+/// Upper bounds in seconds for the round-trip time and delay variation
+/// histograms, from a local network up to a congested satellite link.
+pub const DEFAULT_RTT_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// Removes every series of `vec` labeled with `target`, whatever its other
+/// labels are.
+fn remove_target_series<T: MetricVecBuilder>(vec: &MetricVec<T>, target: &str) {
+    for family in vec.collect() {
+        for metric in family.get_metric() {
+            let labels: HashMap<&str, &str> = metric
+                .get_label()
+                .iter()
+                .map(|l| (l.get_name(), l.get_value()))
+                .collect();
+            if labels.get("target") == Some(&target) {
+                let _ = vec.remove(&labels);
+            }
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct PingMetrics {
     round_trip_time: HistogramVec,
-    packet_delay_variation: HistogramVec,
+    // absent with --disable-ipdv
+    packet_delay_variation: Option<HistogramVec>,
     ping_sent: IntCounterVec,
     ping_received: IntCounterVec,
     ping_errors: IntCounterVec,
+    icmp_errors: IntCounterVec,
+    ping_timeouts: IntCounterVec,
+    ping_duplicates: IntCounterVec,
     last_observed_seq: IntGaugeVec,
+    reply_ttl: IntGaugeVec,
+    sequence_gaps: IntCounterVec,
+    skipped_targets: IntGaugeVec,
+    summary_targets_reported: IntGauge,
+    active_targets: IntGauge,
+    malformed_summaries: IntCounter,
+    incomplete_summaries: IntCounter,
+    observed_drift: IntGaugeVec,
+    loss_drift: GaugeVec,
+    loss_ratio: GaugeVec,
+    rtt_min: GaugeVec,
+    rtt_avg: GaugeVec,
+    rtt_max: GaugeVec,
+    running_rtt_avg: GaugeVec,
+    oversized_lines: IntCounterVec,
+    output_lines: IntCounterVec,
+    parsed_lines: IntCounterVec,
+    icmp_redirects: IntCounterVec,
+    protocol: Protocol,
+    // fraction of replies dropped per target, see inject_loss
+    injected_loss: Option<HashMap<String, f64>>,
+    // milliseconds since the epoch of the last ping per (target, addr)
+    timestamps: Option<HashMap<(String, String), i64>>,
+    // previous sequence number per (target, addr), see record_sequence
+    last_seq: HashMap<(String, String), u64>,
 }
 
 impl PingMetrics {
-    pub fn new<S: Into<String> + Copy>(namespace: S) -> Arc<Mutex<Self>> {
-        Arc::new(Mutex::new(Self::internal_new(namespace)))
+    pub fn new<S: Into<String> + Copy>(
+        namespace: S,
+        protocol: Protocol,
+        buckets: &[f64],
+    ) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self::internal_new(namespace, protocol, buckets)))
     }
 
-    fn internal_new<S: Into<String> + Copy>(namespace: S) -> Self {
+    fn internal_new<S: Into<String> + Copy>(
+        namespace: S,
+        protocol: Protocol,
+        buckets: &[f64],
+    ) -> Self {
         Self {
             round_trip_time: HistogramVec::new(
                 histogram_opts!(
                     "icmp_round_trip_time_seconds",
                     "icmp echo round-trip time as reported by fping",
-                    vec![f64::INFINITY]
+                    buckets.to_vec()
                 )
                 .namespace(namespace),
                 &LABEL_NAMES,
             )
             .unwrap(),
-            packet_delay_variation: HistogramVec::new(
-                histogram_opts!(
-                    "instantaneous_packet_delay_variation_seconds",
-                    "packet delay variation between two successive icmp responses",
-                    vec![f64::INFINITY]
+            packet_delay_variation: Some(
+                HistogramVec::new(
+                    histogram_opts!(
+                        "instantaneous_packet_delay_variation_seconds",
+                        "packet delay variation between two successive icmp responses",
+                        buckets.to_vec()
+                    )
+                    .namespace(namespace),
+                    &LABEL_NAMES,
                 )
-                .namespace(namespace),
-                &LABEL_NAMES,
-            )
-            .unwrap(),
+                .unwrap(),
+            ),
             ping_sent: IntCounterVec::new(
                 opts!("icmp_request_total", "ICMP ECHO REQUEST sent").namespace(namespace),
                 &LABEL_NAMES,
@@ -59,6 +127,33 @@ impl PingMetrics {
                 &["target", "type"],
             )
             .unwrap(),
+            icmp_errors: IntCounterVec::new(
+                opts!(
+                    "icmp_errors_total",
+                    "ICMP errors received in response to echo requests, by kind"
+                )
+                .namespace(namespace),
+                &["target", "error"],
+            )
+            .unwrap(),
+            ping_timeouts: IntCounterVec::new(
+                opts!(
+                    "icmp_timeouts_total",
+                    "ICMP ECHO REQUEST which fping reported as timed out, rather than rejected with an ICMP error"
+                )
+                .namespace(namespace),
+                &LABEL_NAMES,
+            )
+            .unwrap(),
+            ping_duplicates: IntCounterVec::new(
+                opts!(
+                    "icmp_duplicate_total",
+                    "duplicate ICMP ECHO REPLY received, kept out of the round-trip time histogram"
+                )
+                .namespace(namespace),
+                &LABEL_NAMES,
+            )
+            .unwrap(),
             last_observed_seq: IntGaugeVec::new(
                 opts!(
                     "last_observed_sequence",
@@ -68,36 +163,429 @@ impl PingMetrics {
                 &LABEL_NAMES,
             )
             .unwrap(),
+            reply_ttl: IntGaugeVec::new(
+                opts!(
+                    "icmp_reply_ttl",
+                    "TTL of the last reply, a shift means the path to the target changed"
+                )
+                .namespace(namespace),
+                &LABEL_NAMES,
+            )
+            .unwrap(),
+            sequence_gaps: IntCounterVec::new(
+                opts!(
+                    "icmp_sequence_gaps_total",
+                    "sequence numbers skipped between successive results reported by fping"
+                )
+                .namespace(namespace),
+                &LABEL_NAMES,
+            )
+            .unwrap(),
+            skipped_targets: IntGaugeVec::new(
+                opts!(
+                    "skipped_targets",
+                    "targets fping dropped at launch, usually because they could not be resolved"
+                )
+                .namespace(namespace),
+                &["target"],
+            )
+            .unwrap(),
+            summary_targets_reported: IntGauge::with_opts(
+                opts!(
+                    "summary_targets_reported",
+                    "number of targets fping reported in the previous summary"
+                )
+                .namespace(namespace),
+            )
+            .unwrap(),
+            active_targets: IntGauge::with_opts(
+                opts!(
+                    "active_targets",
+                    "number of target addresses fping is expected to report, without skipped targets"
+                )
+                .namespace(namespace),
+            )
+            .unwrap(),
+            malformed_summaries: IntCounter::with_opts(
+                opts!(
+                    "malformed_summaries_total",
+                    "summary lines from fping which could not be parsed"
+                )
+                .namespace(namespace),
+            )
+            .unwrap(),
+            incomplete_summaries: IntCounter::with_opts(
+                opts!(
+                    "incomplete_summaries_total",
+                    "summary requests released by --summary-block-timeout before every target was summarized"
+                )
+                .namespace(namespace),
+            )
+            .unwrap(),
+            observed_drift: IntGaugeVec::new(
+                opts!(
+                    "observed_vs_reported_diff",
+                    "packets fping reported as sent in the previous summary minus the ping lines the exporter observed"
+                )
+                .namespace(namespace),
+                &LABEL_NAMES,
+            )
+            .unwrap(),
+            loss_drift: GaugeVec::new(
+                opts!(
+                    "loss_calculation_drift",
+                    "difference in percentage points between the loss of the previous summary calculated from its counts and the loss fping reported, fping rounds down so anything from 1 up points to a counting problem"
+                )
+                .namespace(namespace),
+                &LABEL_NAMES,
+            )
+            .unwrap(),
+            loss_ratio: GaugeVec::new(
+                opts!(
+                    "packet_loss_ratio",
+                    "packet loss fping reported in the previous summary, including replies dropped by --inject-loss, between 0 and 1"
+                )
+                .namespace(namespace),
+                &LABEL_NAMES,
+            )
+            .unwrap(),
+            rtt_min: GaugeVec::new(
+                opts!(
+                    "icmp_rtt_min_seconds",
+                    "minimum round-trip time fping reported in the previous summary"
+                )
+                .namespace(namespace),
+                &LABEL_NAMES,
+            )
+            .unwrap(),
+            rtt_avg: GaugeVec::new(
+                opts!(
+                    "icmp_rtt_avg_seconds",
+                    "average round-trip time fping reported in the previous summary"
+                )
+                .namespace(namespace),
+                &LABEL_NAMES,
+            )
+            .unwrap(),
+            rtt_max: GaugeVec::new(
+                opts!(
+                    "icmp_rtt_max_seconds",
+                    "maximum round-trip time fping reported in the previous summary"
+                )
+                .namespace(namespace),
+                &LABEL_NAMES,
+            )
+            .unwrap(),
+            running_rtt_avg: GaugeVec::new(
+                opts!(
+                    "icmp_running_rtt_avg_seconds",
+                    "running average round-trip time fping printed with the last reply"
+                )
+                .namespace(namespace),
+                &LABEL_NAMES,
+            )
+            .unwrap(),
+            oversized_lines: IntCounterVec::new(
+                opts!(
+                    "oversized_lines_total",
+                    "lines from fping discarded for exceeding the maximum line length"
+                )
+                .namespace(namespace),
+                &["stream"],
+            )
+            .unwrap(),
+            output_lines: IntCounterVec::new(
+                opts!("output_lines_total", "lines fping wrote to stdout or stderr")
+                    .namespace(namespace),
+                &["stream"],
+            )
+            .unwrap(),
+            parsed_lines: IntCounterVec::new(
+                opts!(
+                    "parsed_lines_total",
+                    "lines from fping which were recognized, a falling share of output_lines_total points at unsupported output"
+                )
+                .namespace(namespace),
+                &["stream"],
+            )
+            .unwrap(),
+            icmp_redirects: IntCounterVec::new(
+                opts!(
+                    "icmp_redirects_total",
+                    "ICMP redirects received in response to echo requests"
+                )
+                .namespace(namespace),
+                &["target", "gateway"],
+            )
+            .unwrap(),
+            protocol,
+            injected_loss: None,
+            timestamps: None,
+            last_seq: HashMap::new(),
+        }
+    }
+
+    /// Drops a fraction of the replies reported by summaries for the given
+    /// targets. All request and reply series gain a `synthetic` label so
+    /// injected loss cannot be mistaken for a real measurement, the loss
+    /// ratio of those targets includes the dropped replies.
+    pub fn inject_loss(&mut self, targets: HashMap<String, f64>) {
+        let labels = [LABEL_NAMES[0], LABEL_NAMES[1], LABEL_NAMES[2], "synthetic"];
+        let relabel = |counter: &IntCounterVec| {
+            let desc = counter.desc()[0];
+            IntCounterVec::new(opts!(desc.fq_name.clone(), desc.help.clone()), &labels).unwrap()
+        };
+        self.ping_sent = relabel(&self.ping_sent);
+        self.ping_received = relabel(&self.ping_received);
+        self.injected_loss = Some(targets);
+    }
+
+    /// Drops the packet delay variation histogram, must be called before the
+    /// metrics are registered
+    pub fn disable_ipdv(&mut self) {
+        self.packet_delay_variation = None;
+    }
+
+    /// Export per-ping metrics with the time fping reported for the ping
+    pub fn emit_timestamps(&mut self) {
+        self.timestamps.get_or_insert_with(HashMap::new);
+    }
+
+    fn stamp(&self, families: &mut [MetricFamily]) {
+        let timestamps = match &self.timestamps {
+            Some(timestamps) => timestamps,
+            None => return,
+        };
+        for metric in families.iter_mut().flat_map(|f| f.mut_metric().iter_mut()) {
+            let label = |name: &str| {
+                metric
+                    .get_label()
+                    .iter()
+                    .find(|l| l.get_name() == name)
+                    .map(|l| l.get_value().to_owned())
+            };
+            let key = (label("target"), label("addr"));
+            if let (Some(target), Some(addr)) = key {
+                if let Some(ts) = timestamps.get(&(target, addr)) {
+                    metric.set_timestamp_ms(*ts);
+                }
+            }
         }
     }
 
-    pub fn ping(&self, ping: Ping<&str>, ipdv: Option<f64>) {
-        let labels = ping.labels();
+    /// Values for `LABEL_NAMES`
+    fn labels<'a>(&self, [target, addr]: [&'a str; 2]) -> [&'a str; 3] {
+        [target, addr, self.protocol.label()]
+    }
+
+    pub fn ping(&mut self, ping: Ping<&str>, ipdv: Option<f64>) {
+        self.record_ping(ping, ipdv, true)
+    }
+
+    /// Warmup pings are kept out of the round-trip time histogram, they
+    /// still count as timeouts and update the sequence.
+    pub fn warmup_ping(&mut self, ping: Ping<&str>) {
+        self.record_ping(ping, None, false)
+    }
 
-        if let Some(rtt) = ping.result {
-            self.round_trip_time
+    fn record_ping(&mut self, ping: Ping<&str>, ipdv: Option<f64>, observe_rtt: bool) {
+        let labels = self.labels(ping.labels());
+
+        // the request was already answered, its sequence number would be
+        // mistaken for a restart
+        if ping.duplicate {
+            self.ping_duplicates.with_label_values(&labels).inc();
+            return;
+        }
+
+        if let Some(timestamps) = self.timestamps.as_mut() {
+            if let Ok(secs) = ping.timestamp.parse::<f64>() {
+                timestamps.insert(
+                    (ping.target.to_owned(), ping.addr.to_owned()),
+                    (secs * 1000.0) as i64,
+                );
+            }
+        }
+
+        // loss is derived from the summaries, this only tells silent drops
+        // apart from errors
+        match ping.result {
+            Some(rtt) if observe_rtt => self
+                .round_trip_time
                 .with_label_values(&labels)
-                .observe(rtt.as_secs_f64());
+                .observe(rtt.as_secs_f64()),
+            Some(_) => {}
+            None => self.ping_timeouts.with_label_values(&labels).inc(),
         }
-        if let Some(ipdv) = ipdv {
-            self.packet_delay_variation
+        if let Some(avg) = ping.avg {
+            self.running_rtt_avg
                 .with_label_values(&labels)
-                .observe(ipdv);
+                .set(avg / 1000.0);
+        }
+        if let (Some(ipdv), Some(histogram)) = (ipdv, &self.packet_delay_variation) {
+            histogram.with_label_values(&labels).observe(ipdv);
+        }
+        if let Some(ttl) = ping.ttl {
+            self.reply_ttl.with_label_values(&labels).set(ttl.into());
         }
         self.last_observed_seq
             .with_label_values(&labels)
             .set(ping.seq.try_into().unwrap());
+        self.record_sequence(&ping, &labels);
+    }
+
+    /// Counts the sequence numbers skipped since the previous result for the
+    /// same address. A sequence number that does not increase means fping
+    /// was restarted or the sequence wrapped around, it becomes the new
+    /// baseline without counting a gap.
+    fn record_sequence(&mut self, ping: &Ping<&str>, labels: &[&str; 3]) {
+        let previous = self
+            .last_seq
+            .insert((ping.target.to_owned(), ping.addr.to_owned()), ping.seq);
+        if let Some(previous) = previous {
+            if ping.seq > previous + 1 {
+                self.sequence_gaps
+                    .with_label_values(labels)
+                    .inc_by(ping.seq - previous - 1);
+            }
+        }
+    }
+
+    /// fping was restarted and numbers its pings from 0 again
+    pub fn reset_sequences(&mut self) {
+        self.last_seq.clear();
     }
 
+    /// Summaries requested through SIGQUIT are interval splits, fping resets
+    /// its per-target counts after printing them. Each summary therefore
+    /// holds only the packets since the previous one and is added as-is;
+    /// the u32 splits cannot overflow the u64 counters in practice.
     pub fn summary(&self, summary: SentReceivedSummary<&str>) {
-        let labels = summary.labels();
+        let [target, addr, protocol] = self.labels(summary.labels());
+        let (labels, received, injected): (&[&str], u32, bool) = match &self.injected_loss {
+            None => (&[target, addr, protocol], summary.received, false),
+            Some(injected) => match injected.get(target) {
+                Some(ratio) => (
+                    &[target, addr, protocol, "true"],
+                    (f64::from(summary.received) * (1.0 - ratio)).round() as u32,
+                    true,
+                ),
+                None => (&[target, addr, protocol, "false"], summary.received, false),
+            },
+        };
 
         self.ping_sent
-            .with_label_values(&labels)
+            .with_label_values(labels)
             .inc_by(summary.sent.into());
         self.ping_received
-            .with_label_values(&labels)
-            .inc_by(summary.received.into());
+            .with_label_values(labels)
+            .inc_by(received.into());
+
+        // nothing was received if these are missing, stale values would
+        // suggest otherwise
+        let stats = [
+            (&self.rtt_min, summary.min),
+            (&self.rtt_avg, summary.avg),
+            (&self.rtt_max, summary.max),
+        ];
+        for (gauge, millis) in &stats {
+            match millis {
+                Some(millis) => gauge
+                    .with_label_values(&[target, addr, protocol])
+                    .set(millis / 1000.0),
+                None => {
+                    let _ = gauge.remove_label_values(&[target, addr, protocol]);
+                }
+            }
+        }
+
+        // loss is undefined if nothing was sent
+        if let (Some(reported), true) = (summary.loss, summary.sent > 0) {
+            // consistent with the reply counter rather than with fping
+            let ratio = if injected {
+                f64::from(summary.sent.saturating_sub(received)) / f64::from(summary.sent)
+            } else {
+                f64::from(reported) / 100.0
+            };
+            self.loss_ratio
+                .with_label_values(&[target, addr, protocol])
+                .set(ratio);
+
+            let lost = summary.sent.saturating_sub(summary.received);
+            let calculated = f64::from(lost) * 100.0 / f64::from(summary.sent);
+            self.loss_drift
+                .with_label_values(&[target, addr, protocol])
+                .set((calculated - f64::from(reported)).abs());
+        }
+    }
+
+    /// A few pings are usually in flight while fping prints a summary, only
+    /// a persistent or growing difference means stdout lines are being lost.
+    pub fn observed_drift(&self, summary: &SentReceivedSummary<&str>, observed: u32) {
+        self.observed_drift
+            .with_label_values(&self.labels(summary.labels()))
+            .set(i64::from(summary.sent) - i64::from(observed));
+    }
+
+    pub fn oversized_line(&self, stream: &str) {
+        self.oversized_lines.with_label_values(&[stream]).inc();
+        self.line(stream, false);
+    }
+
+    pub fn line(&self, stream: &str, parsed: bool) {
+        self.output_lines.with_label_values(&[stream]).inc();
+        if parsed {
+            self.parsed_lines.with_label_values(&[stream]).inc();
+        }
+    }
+
+    pub fn summary_cycle(&self, reported: u32, active: u32) {
+        self.summary_targets_reported.set(reported.into());
+        self.active_targets.set(active.into());
+    }
+
+    pub fn incomplete_summary(&self) {
+        self.incomplete_summaries.inc();
+    }
+
+    pub fn skipped(&self, target: &str) {
+        self.skipped_targets.with_label_values(&[target]).set(1);
+    }
+
+    /// fping reports skipped targets again when it is restarted
+    pub fn clear_skipped(&self) {
+        self.skipped_targets.reset();
+    }
+
+    /// Drops every series of a target that is no longer pinged, so it does
+    /// not linger with its last values.
+    pub fn remove_target(&mut self, target: &str) {
+        remove_target_series(&self.round_trip_time, target);
+        if let Some(histogram) = &self.packet_delay_variation {
+            remove_target_series(histogram, target);
+        }
+        remove_target_series(&self.ping_sent, target);
+        remove_target_series(&self.ping_received, target);
+        remove_target_series(&self.ping_errors, target);
+        remove_target_series(&self.icmp_errors, target);
+        remove_target_series(&self.ping_timeouts, target);
+        remove_target_series(&self.ping_duplicates, target);
+        remove_target_series(&self.last_observed_seq, target);
+        remove_target_series(&self.reply_ttl, target);
+        remove_target_series(&self.sequence_gaps, target);
+        remove_target_series(&self.skipped_targets, target);
+        remove_target_series(&self.observed_drift, target);
+        remove_target_series(&self.loss_drift, target);
+        remove_target_series(&self.loss_ratio, target);
+        remove_target_series(&self.rtt_min, target);
+        remove_target_series(&self.rtt_avg, target);
+        remove_target_series(&self.rtt_max, target);
+        remove_target_series(&self.running_rtt_avg, target);
+        remove_target_series(&self.icmp_redirects, target);
+        if let Some(timestamps) = self.timestamps.as_mut() {
+            timestamps.retain(|(t, _), _| t != target);
+        }
+        self.last_seq.retain(|(t, _), _| t != target);
     }
 
     pub fn error(&self, control: Control<&str>) {
@@ -105,8 +593,18 @@ impl PingMetrics {
             Control::FpingError { target, .. } => {
                 self.ping_errors.with_label_values(&[target, "fping"]).inc();
             }
-            Control::IcmpError { target, .. } => {
+            Control::IcmpError { target, error, .. } => {
                 self.ping_errors.with_label_values(&[target, "icmp"]).inc();
+                self.icmp_errors
+                    .with_label_values(&[target, icmp_error_kind(error)])
+                    .inc();
+            }
+            Control::IcmpRedirect { target, addr } => {
+                // addr is the router which sent the redirect
+                self.icmp_redirects.with_label_values(&[target, addr]).inc();
+            }
+            Control::MalformedSummary(_) => {
+                self.malformed_summaries.inc();
             }
             _ => {}
         }
@@ -115,26 +613,628 @@ impl PingMetrics {
 
 impl Collector for PingMetrics {
     fn desc(&self) -> Vec<&prometheus::core::Desc> {
-        vec![
+        [
             self.round_trip_time.desc(),
-            self.packet_delay_variation.desc(),
+            self.packet_delay_variation
+                .as_ref()
+                .map_or_else(Vec::new, |histogram| histogram.desc()),
             self.ping_sent.desc(),
             self.ping_received.desc(),
             self.ping_errors.desc(),
+            self.icmp_errors.desc(),
+            self.ping_timeouts.desc(),
+            self.ping_duplicates.desc(),
             self.last_observed_seq.desc(),
+            self.reply_ttl.desc(),
+            self.sequence_gaps.desc(),
+            self.skipped_targets.desc(),
+            self.summary_targets_reported.desc(),
+            self.active_targets.desc(),
+            self.malformed_summaries.desc(),
+            self.incomplete_summaries.desc(),
+            self.observed_drift.desc(),
+            self.loss_drift.desc(),
+            self.loss_ratio.desc(),
+            self.rtt_min.desc(),
+            self.rtt_avg.desc(),
+            self.rtt_max.desc(),
+            self.running_rtt_avg.desc(),
+            self.oversized_lines.desc(),
+            self.output_lines.desc(),
+            self.parsed_lines.desc(),
+            self.icmp_redirects.desc(),
         ]
         .concat()
     }
 
     fn collect(&self) -> Vec<prometheus::proto::MetricFamily> {
-        vec![
+        // only these are updated for every ping
+        let mut per_ping = [
             self.round_trip_time.collect(),
-            self.packet_delay_variation.collect(),
+            self.packet_delay_variation
+                .as_ref()
+                .map_or_else(Vec::new, |histogram| histogram.collect()),
+            self.running_rtt_avg.collect(),
+            self.last_observed_seq.collect(),
+            self.reply_ttl.collect(),
+        ]
+        .concat();
+        self.stamp(&mut per_ping);
+
+        [
+            per_ping,
             self.ping_sent.collect(),
             self.ping_received.collect(),
             self.ping_errors.collect(),
-            self.last_observed_seq.collect(),
+            self.icmp_errors.collect(),
+            self.ping_timeouts.collect(),
+            self.ping_duplicates.collect(),
+            self.sequence_gaps.collect(),
+            self.skipped_targets.collect(),
+            self.summary_targets_reported.collect(),
+            self.active_targets.collect(),
+            self.malformed_summaries.collect(),
+            self.incomplete_summaries.collect(),
+            self.observed_drift.collect(),
+            self.loss_drift.collect(),
+            self.loss_ratio.collect(),
+            self.rtt_min.collect(),
+            self.rtt_avg.collect(),
+            self.rtt_max.collect(),
+            self.oversized_lines.collect(),
+            self.output_lines.collect(),
+            self.parsed_lines.collect(),
+            self.icmp_redirects.collect(),
         ]
         .concat()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use prometheus::proto::MetricType;
+
+    use super::*;
+
+    /// Flattens a metric family into `(labels, value)` pairs for assertions
+    fn series(collector: &impl Collector, name: &str) -> Vec<(Vec<String>, f64)> {
+        let mut series: Vec<(Vec<String>, f64)> = collector
+            .collect()
+            .into_iter()
+            .filter(|family| family.get_name() == name)
+            .flat_map(|family| {
+                let kind = family.get_field_type();
+                family
+                    .get_metric()
+                    .iter()
+                    .map(|m| {
+                        let labels = m.get_label().iter().map(|l| l.get_value().to_owned());
+                        let value = match kind {
+                            MetricType::COUNTER => m.get_counter().get_value(),
+                            MetricType::GAUGE => m.get_gauge().get_value(),
+                            MetricType::UNTYPED => m.get_untyped().get_value(),
+                            MetricType::HISTOGRAM => m.get_histogram().get_sample_count() as f64,
+                            MetricType::SUMMARY => m.get_summary().get_sample_count() as f64,
+                        };
+                        (labels.collect(), value)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        series.sort_by(|a, b| a.0.cmp(&b.0));
+        series
+    }
+
+    #[test]
+    fn successive_summaries_accumulate_splits() {
+        let metrics = PingMetrics::internal_new("fping", Protocol::Icmp, &DEFAULT_RTT_BUCKETS);
+        for (sent, received) in &[(104, 104), (98, 97)] {
+            metrics.summary(SentReceivedSummary {
+                target: "dns.google",
+                addr: "8.8.8.8",
+                sent: *sent,
+                received: *received,
+                loss: None,
+                min: None,
+                avg: None,
+                max: None,
+            });
+        }
+
+        let labels = vec![
+            "8.8.8.8".to_owned(),
+            "icmp".to_owned(),
+            "dns.google".to_owned(),
+        ];
+        assert_eq!(
+            series(&metrics, "fping_icmp_request_total"),
+            vec![(labels.clone(), 202.0)]
+        );
+        assert_eq!(
+            series(&metrics, "fping_icmp_reply_total"),
+            vec![(labels, 201.0)]
+        );
+    }
+
+    #[test]
+    fn timeouts_are_counted_separately() {
+        let mut metrics = PingMetrics::internal_new("fping", Protocol::Icmp, &DEFAULT_RTT_BUCKETS);
+        for line in &[
+            "[1611765997.71135] dns.google (8.8.8.8) : [0], timed out (NaN avg, 100% loss)",
+            "[1611765998.71135] dns.google (8.8.8.8) : [1], 64 bytes, 10.2 ms (10.2 avg, 50% loss)",
+        ] {
+            metrics.ping(Ping::parse(line).unwrap(), None);
+        }
+        metrics.error(Control::parse(
+            "ICMP Host Unreachable from 10.0.0.1 for ICMP Echo sent to dns.google",
+        ));
+        metrics.error(Control::parse(
+            "ICMP Time Exceeded from 10.0.0.1 for ICMP Echo sent to dns.google",
+        ));
+
+        assert_eq!(
+            series(&metrics, "fping_icmp_timeouts_total"),
+            vec![(
+                vec!["8.8.8.8".into(), "icmp".into(), "dns.google".into()],
+                1.0
+            )]
+        );
+        assert_eq!(
+            series(&metrics, "fping_errors_total"),
+            vec![(vec!["dns.google".into(), "icmp".into()], 2.0)]
+        );
+        assert_eq!(
+            series(&metrics, "fping_icmp_errors_total"),
+            vec![
+                (vec!["time_exceeded".into(), "dns.google".into()], 1.0),
+                (vec!["unreachable".into(), "dns.google".into()], 1.0),
+            ]
+        );
+        assert_eq!(
+            series(&metrics, "fping_icmp_round_trip_time_seconds"),
+            vec![(
+                vec!["8.8.8.8".into(), "icmp".into(), "dns.google".into()],
+                1.0
+            )]
+        );
+        assert_eq!(
+            series(&metrics, "fping_icmp_running_rtt_avg_seconds"),
+            vec![(
+                vec!["8.8.8.8".into(), "icmp".into(), "dns.google".into()],
+                10.2 / 1000.0
+            )]
+        );
+    }
+
+    #[test]
+    fn duplicates_are_kept_out_of_rtt() {
+        let mut metrics = PingMetrics::internal_new("fping", Protocol::Icmp, &DEFAULT_RTT_BUCKETS);
+        for line in &[
+            "[1611765997.71135] dns.google (8.8.8.8) : [0], 64 bytes, 10.2 ms (10.2 avg, 0% loss)",
+            "[1611765997.72135] dns.google (8.8.8.8) : [1], 64 bytes, 10.4 ms (10.3 avg, 0% loss)",
+            "[1611765997.72135] dns.google (8.8.8.8) : [0], 64 bytes, 20.2 ms (13.6 avg, 0% loss) (DUP!)",
+            "[1611765998.71135] dns.google (8.8.8.8) : [2], 64 bytes, 10.2 ms (12.7 avg, 0% loss)",
+        ] {
+            metrics.ping(Ping::parse(line).unwrap(), None);
+        }
+
+        let labels = vec!["8.8.8.8".to_owned(), "icmp".into(), "dns.google".into()];
+        assert_eq!(
+            series(&metrics, "fping_icmp_duplicate_total"),
+            vec![(labels.clone(), 1.0)]
+        );
+        assert_eq!(
+            series(&metrics, "fping_icmp_round_trip_time_seconds"),
+            vec![(labels, 3.0)]
+        );
+        // the repeated sequence number is not taken for a restart
+        assert!(series(&metrics, "fping_icmp_sequence_gaps_total").is_empty());
+    }
+
+    #[test]
+    fn timestamps_from_pings() {
+        let mut metrics = PingMetrics::internal_new("fping", Protocol::Icmp, &DEFAULT_RTT_BUCKETS);
+        metrics.emit_timestamps();
+        metrics.ping(
+            Ping {
+                timestamp: "1611765997.71135",
+                target: "dns.google",
+                addr: "8.8.8.8",
+                seq: 0,
+                result: Some(Duration::from_millis(1)),
+                avg: None,
+                loss: None,
+                duplicate: false,
+                ttl: None,
+            },
+            None,
+        );
+        metrics.summary(SentReceivedSummary {
+            target: "dns.google",
+            addr: "8.8.8.8",
+            sent: 1,
+            received: 1,
+            loss: None,
+            min: None,
+            avg: None,
+            max: None,
+        });
+
+        let timestamps: Vec<_> = metrics
+            .collect()
+            .iter()
+            .filter_map(|family| {
+                let ts = family.get_metric().first()?.get_timestamp_ms();
+                Some((family.get_name().to_owned(), ts)).filter(|_| ts != 0)
+            })
+            .collect();
+        assert_eq!(
+            timestamps,
+            vec![
+                (
+                    "fping_icmp_round_trip_time_seconds".to_owned(),
+                    1611765997711
+                ),
+                ("fping_last_observed_sequence".to_owned(), 1611765997711),
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_addr_keeps_targets_apart() {
+        let mut metrics = PingMetrics::internal_new("fping", Protocol::Icmp, &DEFAULT_RTT_BUCKETS);
+        for (target, seq) in &[("anycast-a.example", 1), ("anycast-b.example", 2)] {
+            metrics.ping(
+                Ping {
+                    timestamp: "1611765997.71135",
+                    target,
+                    addr: "192.0.2.1",
+                    seq: *seq,
+                    result: Some(Duration::from_millis(1)),
+                    avg: None,
+                    loss: None,
+                    duplicate: false,
+                    ttl: None,
+                },
+                None,
+            );
+        }
+        metrics.summary(SentReceivedSummary {
+            target: "anycast-a.example",
+            addr: "192.0.2.1",
+            sent: 10,
+            received: 10,
+            loss: None,
+            min: None,
+            avg: None,
+            max: None,
+        });
+        metrics.summary(SentReceivedSummary {
+            target: "anycast-b.example",
+            addr: "192.0.2.1",
+            sent: 10,
+            received: 5,
+            loss: None,
+            min: None,
+            avg: None,
+            max: None,
+        });
+
+        assert_eq!(
+            series(&metrics, "fping_icmp_reply_total"),
+            vec![
+                (
+                    vec![
+                        "192.0.2.1".into(),
+                        "icmp".into(),
+                        "anycast-a.example".into()
+                    ],
+                    10.0
+                ),
+                (
+                    vec![
+                        "192.0.2.1".into(),
+                        "icmp".into(),
+                        "anycast-b.example".into()
+                    ],
+                    5.0
+                ),
+            ]
+        );
+        assert_eq!(
+            series(&metrics, "fping_last_observed_sequence"),
+            vec![
+                (
+                    vec![
+                        "192.0.2.1".into(),
+                        "icmp".into(),
+                        "anycast-a.example".into()
+                    ],
+                    1.0
+                ),
+                (
+                    vec![
+                        "192.0.2.1".into(),
+                        "icmp".into(),
+                        "anycast-b.example".into()
+                    ],
+                    2.0
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn summary_rtt_stats() {
+        let metrics = PingMetrics::internal_new("fping", Protocol::Icmp, &DEFAULT_RTT_BUCKETS);
+        let summary = |received, stats: Option<(f64, f64, f64)>| SentReceivedSummary {
+            target: "dns.google",
+            addr: "8.8.8.8",
+            sent: 10,
+            received,
+            loss: None,
+            min: stats.map(|s| s.0),
+            avg: stats.map(|s| s.1),
+            max: stats.map(|s| s.2),
+        };
+        let labels = vec![
+            "8.8.8.8".to_owned(),
+            "icmp".to_owned(),
+            "dns.google".to_owned(),
+        ];
+
+        metrics.summary(summary(10, Some((10.5, 18.5, 78.0))));
+        assert_eq!(
+            series(&metrics, "fping_icmp_rtt_min_seconds"),
+            vec![(labels.clone(), 0.0105)]
+        );
+        assert_eq!(
+            series(&metrics, "fping_icmp_rtt_max_seconds"),
+            vec![(labels, 0.078)]
+        );
+
+        metrics.summary(summary(0, None));
+        assert!(series(&metrics, "fping_icmp_rtt_avg_seconds").is_empty());
+    }
+
+    #[test]
+    fn loss_drift_against_reported_loss() {
+        let metrics = PingMetrics::internal_new("fping", Protocol::Icmp, &DEFAULT_RTT_BUCKETS);
+        for (target, received, loss) in &[("dns.google", 2, 33), ("localhost", 2, 50)] {
+            metrics.summary(SentReceivedSummary {
+                target,
+                addr: "192.0.2.1",
+                sent: 3,
+                received: *received,
+                loss: Some(*loss),
+                min: None,
+                avg: None,
+                max: None,
+            });
+        }
+
+        let drift: Vec<_> = series(&metrics, "fping_loss_calculation_drift")
+            .into_iter()
+            .map(|(labels, drift)| (labels[2].clone(), (drift * 100.0).round() / 100.0))
+            .collect();
+        assert_eq!(
+            drift,
+            vec![
+                ("dns.google".to_owned(), 0.33),
+                ("localhost".to_owned(), 16.67)
+            ]
+        );
+    }
+
+    #[test]
+    fn injected_loss_is_labeled() {
+        let mut metrics = PingMetrics::internal_new("fping", Protocol::Icmp, &DEFAULT_RTT_BUCKETS);
+        metrics.inject_loss(vec![("dns.google".to_owned(), 0.25)].into_iter().collect());
+        for target in &["dns.google", "localhost"] {
+            metrics.summary(SentReceivedSummary {
+                target,
+                addr: "192.0.2.1",
+                sent: 100,
+                received: 100,
+                loss: Some(0),
+                min: None,
+                avg: None,
+                max: None,
+            });
+        }
+
+        let labels = |target: &str, synthetic: &str| {
+            vec![
+                "192.0.2.1".to_owned(),
+                "icmp".to_owned(),
+                synthetic.to_owned(),
+                target.to_owned(),
+            ]
+        };
+        assert_eq!(
+            series(&metrics, "fping_icmp_request_total"),
+            vec![
+                (labels("localhost", "false"), 100.0),
+                (labels("dns.google", "true"), 100.0),
+            ]
+        );
+        assert_eq!(
+            series(&metrics, "fping_icmp_reply_total"),
+            vec![
+                (labels("localhost", "false"), 100.0),
+                (labels("dns.google", "true"), 75.0),
+            ]
+        );
+        let unlabeled =
+            |target: &str| vec!["192.0.2.1".to_owned(), "icmp".to_owned(), target.to_owned()];
+        assert_eq!(
+            series(&metrics, "fping_packet_loss_ratio"),
+            vec![
+                (unlabeled("dns.google"), 0.25),
+                (unlabeled("localhost"), 0.0)
+            ]
+        );
+        // fping itself reported its numbers consistently
+        assert_eq!(
+            series(&metrics, "fping_loss_calculation_drift")
+                .iter()
+                .map(|(_, drift)| *drift)
+                .collect::<Vec<_>>(),
+            vec![0.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn sequence_gaps() {
+        let mut metrics = PingMetrics::internal_new("fping", Protocol::Icmp, &DEFAULT_RTT_BUCKETS);
+        let mut ping = |target: &str, seq: u64| {
+            let line = format!(
+                "[1611765997.71135] {} (8.8.8.8) : [{}], 64 bytes, 10 ms (10.0 avg, 0% loss)",
+                target, seq
+            );
+            metrics.ping(Ping::parse(&line).unwrap(), None);
+        };
+        // 1 and 4..=5 are missing
+        for seq in &[0, 2, 3, 6] {
+            ping("dns.google", *seq);
+        }
+        // a restart starts over at 0 without counting a gap
+        for seq in &[0, 1] {
+            ping("dns.google", *seq);
+        }
+        // the first result of a target is the baseline
+        ping("8.8.8.8", 5);
+        let gaps = |metrics: &PingMetrics| series(metrics, "fping_icmp_sequence_gaps_total");
+        assert_eq!(
+            gaps(&metrics),
+            vec![(
+                vec![
+                    "8.8.8.8".to_owned(),
+                    "icmp".to_owned(),
+                    "dns.google".to_owned()
+                ],
+                3.0
+            )]
+        );
+
+        metrics.reset_sequences();
+        metrics.ping(
+            Ping::parse("[1611765997.71135] dns.google (8.8.8.8) : [9], 64 bytes, 10 ms").unwrap(),
+            None,
+        );
+        assert_eq!(gaps(&metrics)[0].1, 3.0);
+    }
+
+    #[test]
+    fn reply_ttl_follows_the_last_reply() {
+        let mut metrics = PingMetrics::internal_new("fping", Protocol::Icmp, &DEFAULT_RTT_BUCKETS);
+        let ttl = |metrics: &PingMetrics| series(metrics, "fping_icmp_reply_ttl");
+        metrics.ping(
+            Ping::parse("[1611765997.71135] dns.google (8.8.8.8) : [0], 64 bytes, 10 ms").unwrap(),
+            None,
+        );
+        assert!(ttl(&metrics).is_empty());
+
+        let labels = vec!["8.8.8.8".to_owned(), "icmp".into(), "dns.google".into()];
+        for (line, expected) in &[
+            (
+                "[1611765997.71135] dns.google (8.8.8.8) : [1], 64 bytes, 10 ms (TTL 58)",
+                58.0,
+            ),
+            (
+                "[1611765998.71135] dns.google (8.8.8.8) : [2], 64 bytes, 12 ms (TTL 57)",
+                57.0,
+            ),
+            // timeouts carry no TTL, the last one stays
+            (
+                "[1611765999.71135] dns.google (8.8.8.8) : [3], timed out",
+                57.0,
+            ),
+        ] {
+            metrics.ping(Ping::parse(line).unwrap(), None);
+            assert_eq!(ttl(&metrics), vec![(labels.clone(), *expected)]);
+        }
+
+        metrics.remove_target("dns.google");
+        assert!(ttl(&metrics).is_empty());
+    }
+
+    #[test]
+    fn ipdv_can_be_disabled() {
+        let ipdv = "fping_instantaneous_packet_delay_variation_seconds";
+        let line = "[1611765997.71135] dns.google (8.8.8.8) : [0], 64 bytes, 10 ms";
+
+        let mut metrics = PingMetrics::internal_new("fping", Protocol::Icmp, &DEFAULT_RTT_BUCKETS);
+        metrics.ping(Ping::parse(line).unwrap(), Some(0.001));
+        assert_eq!(series(&metrics, ipdv).len(), 1);
+
+        metrics.disable_ipdv();
+        metrics.ping(Ping::parse(line).unwrap(), Some(0.001));
+        assert!(series(&metrics, ipdv).is_empty());
+        assert!(!metrics.desc().iter().any(|desc| desc.fq_name == ipdv));
+        assert_eq!(
+            series(&metrics, "fping_icmp_round_trip_time_seconds").len(),
+            1
+        );
+    }
+
+    #[test]
+    fn removed_targets_are_dropped() {
+        let mut metrics = PingMetrics::internal_new("fping", Protocol::Icmp, &DEFAULT_RTT_BUCKETS);
+        metrics.emit_timestamps();
+        for line in &[
+            "[1611765997.71135] dns.google (8.8.8.8) : [0], 64 bytes, 10 ms (10.0 avg, 0% loss)",
+            "[1611765997.71135] localhost (127.0.0.1) : [0], 64 bytes, 1 ms (1.00 avg, 0% loss)",
+        ] {
+            metrics.ping(Ping::parse(line).unwrap(), None);
+        }
+        for (target, addr) in &[("dns.google", "8.8.8.8"), ("localhost", "127.0.0.1")] {
+            metrics.summary(SentReceivedSummary {
+                target,
+                addr,
+                sent: 1,
+                received: 1,
+                loss: Some(0),
+                min: Some(1.0),
+                avg: Some(1.0),
+                max: Some(1.0),
+            });
+        }
+        metrics.error(Control::parse(
+            "dns.google: error while sending ping: No route to host",
+        ));
+        metrics.skipped("dns.google");
+
+        metrics.remove_target("dns.google");
+        let localhost = vec![
+            "127.0.0.1".to_owned(),
+            "icmp".to_owned(),
+            "localhost".to_owned(),
+        ];
+        for name in &[
+            "fping_icmp_round_trip_time_seconds",
+            "fping_icmp_request_total",
+            "fping_icmp_rtt_avg_seconds",
+            "fping_last_observed_sequence",
+        ] {
+            let targets: Vec<_> = series(&metrics, name)
+                .into_iter()
+                .map(|(labels, _)| labels)
+                .collect();
+            assert_eq!(targets, vec![localhost.clone()], "{}", name);
+        }
+        assert!(series(&metrics, "fping_errors_total").is_empty());
+        assert!(series(&metrics, "fping_skipped_targets").is_empty());
+        assert_eq!(
+            metrics
+                .timestamps
+                .as_ref()
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>(),
+            vec![&("localhost".to_owned(), "127.0.0.1".to_owned())]
+        );
+    }
+}
@@ -1,10 +1,29 @@
+mod child;
+#[cfg(feature = "graphite")]
+mod graphite;
 mod http;
+mod json;
+mod listener;
 mod metrics;
+mod openmetrics;
+mod target_info;
+mod textfile;
+mod tls;
 
-pub use http::{publish_metrics, RegistryAccess};
-pub use metrics::PingMetrics;
+pub use child::{watch_child_process, ChildProcess};
+#[cfg(feature = "graphite")]
+pub use graphite::push_graphite;
+pub use http::{
+    initial_summary, publish_metrics, RegistryAccess, ReloadRequest, ScrapeMetrics, ServerContext,
+    ShutdownCountdown, VersionInfo,
+};
+pub use listener::BindError;
+pub use metrics::{PingMetrics, DEFAULT_RTT_BUCKETS};
 use prometheus::core::{Collector, Desc};
 use std::sync::{Arc, Mutex};
+pub use target_info::TargetInfo;
+pub use textfile::{write_textfile, write_textfile_once};
+pub use tls::{TlsError, TlsMaterial};
 
 #[derive(Debug)]
 pub struct LockedCollector<C>(Vec<Desc>, Arc<Mutex<C>>);
@@ -31,3 +50,35 @@ impl<C: Collector> Collector for LockedCollector<C> {
         self.1.lock().unwrap().collect()
     }
 }
+
+/// The same families for every encoder to render
+#[cfg(test)]
+mod fixtures {
+    use prometheus::{
+        core::Collector, histogram_opts, opts, proto::MetricFamily, CounterVec, Gauge, Histogram,
+    };
+
+    /// What prometheus sends as `Accept` when scraping
+    pub const PROMETHEUS_ACCEPT: &str =
+        "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+
+    /// A labeled counter, a gauge with a quoted help text and NaN value and a
+    /// histogram with an observation on either side of its only bound
+    pub fn families() -> Vec<MetricFamily> {
+        let counter = CounterVec::new(
+            opts!("fping_icmp_reply_total", "replies"),
+            &["target", "addr"],
+        )
+        .unwrap();
+        counter
+            .with_label_values(&["dns.google", "8.8.8.8"])
+            .inc_by(3.0);
+        let gauge = Gauge::new("loss", "say \"hi\"").unwrap();
+        gauge.set(f64::NAN);
+        let histogram =
+            Histogram::with_opts(histogram_opts!("rtt_seconds", "rtt", vec![0.5])).unwrap();
+        histogram.observe(0.25);
+        histogram.observe(0.75);
+        [counter.collect(), gauge.collect(), histogram.collect()].concat()
+    }
+}
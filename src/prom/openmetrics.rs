@@ -0,0 +1,251 @@
+//! OpenMetrics rendering of the registry for scrapers that negotiate it.
+//!
+//! Differs from the classic text format in the details OpenMetrics 1.0.0
+//! insists on: counter families drop their `_total` suffix while their
+//! samples keep it, untyped metrics are `unknown`, histograms always carry
+//! a `+Inf` bucket, timestamps are in seconds and the exposition ends with
+//! `# EOF`.
+
+use std::{fmt::Write as _, io::Write};
+
+use prometheus::{
+    proto::{LabelPair, Metric, MetricFamily, MetricType},
+    Encoder,
+};
+
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+const MEDIA_TYPE: &str = "application/openmetrics-text";
+const VERSION: &str = "1.0.0";
+
+#[derive(Debug, Default)]
+pub struct OpenMetricsEncoder;
+
+fn escape(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+}
+
+fn number(out: &mut String, value: f64) {
+    if value.is_nan() {
+        out.push_str("NaN");
+    } else if value.is_infinite() {
+        out.push_str(if value > 0.0 { "+Inf" } else { "-Inf" });
+    } else {
+        let _ = write!(out, "{}", value);
+    }
+}
+
+/// `name{labels,extra="value"} value timestamp`
+fn sample(
+    out: &mut String,
+    name: &str,
+    labels: &[LabelPair],
+    extra: Option<(&str, f64)>,
+    value: f64,
+    metric: &Metric,
+) {
+    out.push_str(name);
+    if !labels.is_empty() || extra.is_some() {
+        out.push('{');
+        let mut first = true;
+        for label in labels {
+            if !std::mem::take(&mut first) {
+                out.push(',');
+            }
+            out.push_str(label.get_name());
+            out.push_str("=\"");
+            escape(out, label.get_value());
+            out.push('"');
+        }
+        if let Some((name, bound)) = extra {
+            if !first {
+                out.push(',');
+            }
+            out.push_str(name);
+            out.push_str("=\"");
+            number(out, bound);
+            out.push('"');
+        }
+        out.push('}');
+    }
+    out.push(' ');
+    number(out, value);
+    if metric.has_timestamp_ms() {
+        let _ = write!(out, " {}", metric.get_timestamp_ms() as f64 / 1000.0);
+    }
+    out.push('\n');
+}
+
+fn metric(out: &mut String, name: &str, kind: MetricType, metric: &Metric) {
+    let labels = metric.get_label();
+    match kind {
+        MetricType::COUNTER => {
+            let value = metric.get_counter().get_value();
+            sample(out, &format!("{}_total", name), labels, None, value, metric);
+        }
+        MetricType::GAUGE => {
+            let value = metric.get_gauge().get_value();
+            sample(out, name, labels, None, value, metric);
+        }
+        MetricType::UNTYPED => {
+            let value = metric.get_untyped().get_value();
+            sample(out, name, labels, None, value, metric);
+        }
+        MetricType::HISTOGRAM => {
+            let histogram = metric.get_histogram();
+            let bucket = format!("{}_bucket", name);
+            let mut infinite = false;
+            for b in histogram.get_bucket() {
+                let bound = b.get_upper_bound();
+                infinite |= bound == f64::INFINITY;
+                let count = b.get_cumulative_count() as f64;
+                sample(out, &bucket, labels, Some(("le", bound)), count, metric);
+            }
+            let count = histogram.get_sample_count() as f64;
+            if !infinite {
+                let inf = Some(("le", f64::INFINITY));
+                sample(out, &bucket, labels, inf, count, metric);
+            }
+            sample(
+                out,
+                &format!("{}_sum", name),
+                labels,
+                None,
+                histogram.get_sample_sum(),
+                metric,
+            );
+            sample(out, &format!("{}_count", name), labels, None, count, metric);
+        }
+        MetricType::SUMMARY => {
+            let summary = metric.get_summary();
+            for q in summary.get_quantile() {
+                let quantile = Some(("quantile", q.get_quantile()));
+                sample(out, name, labels, quantile, q.get_value(), metric);
+            }
+            sample(
+                out,
+                &format!("{}_sum", name),
+                labels,
+                None,
+                summary.get_sample_sum(),
+                metric,
+            );
+            let count = summary.get_sample_count() as f64;
+            sample(out, &format!("{}_count", name), labels, None, count, metric);
+        }
+    }
+}
+
+fn family(out: &mut String, family: &MetricFamily) {
+    let kind = family.get_field_type();
+    let name = match kind {
+        MetricType::COUNTER => family
+            .get_name()
+            .strip_suffix("_total")
+            .unwrap_or_else(|| family.get_name()),
+        _ => family.get_name(),
+    };
+    let type_name = match kind {
+        MetricType::COUNTER => "counter",
+        MetricType::GAUGE => "gauge",
+        MetricType::UNTYPED => "unknown",
+        MetricType::HISTOGRAM => "histogram",
+        MetricType::SUMMARY => "summary",
+    };
+    let _ = writeln!(out, "# TYPE {} {}", name, type_name);
+    if !family.get_help().is_empty() {
+        let _ = write!(out, "# HELP {} ", name);
+        escape(out, family.get_help());
+        out.push('\n');
+    }
+    for m in family.get_metric() {
+        metric(out, name, kind, m);
+    }
+}
+
+impl Encoder for OpenMetricsEncoder {
+    fn encode<W: Write>(
+        &self,
+        families: &[MetricFamily],
+        writer: &mut W,
+    ) -> prometheus::Result<()> {
+        let mut out = String::new();
+        for f in families {
+            family(&mut out, f);
+        }
+        out.push_str("# EOF\n");
+        writer.write_all(out.as_bytes())?;
+        Ok(())
+    }
+
+    fn format_type(&self) -> &str {
+        OPENMETRICS_FORMAT
+    }
+}
+
+/// Picks OpenMetrics only if the `Accept` header prefers it over every other
+/// type, either without a version or with the one we produce
+pub fn prefers_openmetrics(accept: Option<&str>) -> bool {
+    super::json::preferred(accept, |media_type, params| {
+        media_type.eq_ignore_ascii_case(MEDIA_TYPE)
+            && params
+                .iter()
+                .filter_map(|param| param.strip_prefix("version="))
+                .all(|version| version == VERSION)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::fixtures::{families, PROMETHEUS_ACCEPT},
+        *,
+    };
+
+    #[test]
+    fn openmetrics_layout() {
+        let families = families();
+        let mut out = vec![];
+        OpenMetricsEncoder.encode(&families, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                "# TYPE fping_icmp_reply counter\n",
+                "# HELP fping_icmp_reply replies\n",
+                "fping_icmp_reply_total{addr=\"8.8.8.8\",target=\"dns.google\"} 3\n",
+                "# TYPE loss gauge\n",
+                "# HELP loss say \\\"hi\\\"\n",
+                "loss NaN\n",
+                "# TYPE rtt_seconds histogram\n",
+                "# HELP rtt_seconds rtt\n",
+                "rtt_seconds_bucket{le=\"0.5\"} 1\n",
+                "rtt_seconds_bucket{le=\"+Inf\"} 2\n",
+                "rtt_seconds_sum 1\n",
+                "rtt_seconds_count 2\n",
+                "# EOF\n",
+            )
+        );
+    }
+
+    #[test]
+    fn accept_negotiation() {
+        assert!(!prefers_openmetrics(None));
+        assert!(!prefers_openmetrics(Some("text/plain")));
+        assert!(prefers_openmetrics(Some("application/openmetrics-text")));
+        // what prometheus sends
+        assert!(prefers_openmetrics(Some(PROMETHEUS_ACCEPT)));
+        assert!(!prefers_openmetrics(Some(
+            "application/openmetrics-text;version=0.0.1,text/plain;version=0.0.4;q=0.5"
+        )));
+        assert!(!prefers_openmetrics(Some(
+            "application/openmetrics-text;q=0.5,text/plain"
+        )));
+    }
+}
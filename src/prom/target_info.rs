@@ -0,0 +1,130 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use prometheus::{
+    core::{Collector, Desc, Describer},
+    opts,
+    proto::MetricFamily,
+    IntGaugeVec, Opts,
+};
+
+use crate::targets::TargetLabels;
+
+/// Exposes the labels of annotated targets as `target_info{target, ...} 1`,
+/// to be joined onto the ping metrics by `target`. The label names are the
+/// union of all annotations, so the vec is rebuilt whenever they change.
+#[derive(Debug, Clone)]
+pub struct TargetInfo {
+    opts: Opts,
+    desc: Desc,
+    info: Arc<Mutex<Option<IntGaugeVec>>>,
+}
+
+impl TargetInfo {
+    pub fn new<S: Into<String>>(namespace: S) -> Self {
+        let opts = opts!(
+            "target_info",
+            "labels from the target file annotations of each target"
+        )
+        .namespace(namespace);
+        let desc = opts
+            .clone()
+            .variable_labels(vec!["target".to_owned()])
+            .describe()
+            .unwrap();
+        Self {
+            opts,
+            desc,
+            info: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn set_labels(&self, labels: &HashMap<String, TargetLabels>) {
+        let names: BTreeSet<&str> = labels
+            .values()
+            .flat_map(|annotations| annotations.keys().map(String::as_str))
+            .collect();
+        let info = if labels.is_empty() {
+            None
+        } else {
+            let label_names: Vec<&str> = std::iter::once("target").chain(names).collect();
+            let vec = IntGaugeVec::new(self.opts.clone(), &label_names).unwrap();
+            for (target, annotations) in labels {
+                let values: Vec<&str> = label_names
+                    .iter()
+                    .map(|&name| match name {
+                        "target" => target.as_str(),
+                        name => annotations.get(name).map_or("", String::as_str),
+                    })
+                    .collect();
+                vec.with_label_values(&values).set(1);
+            }
+            Some(vec)
+        };
+        *self.info.lock().unwrap() = info;
+    }
+}
+
+impl Collector for TargetInfo {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        match &*self.info.lock().unwrap() {
+            Some(info) => info.collect(),
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::{Encoder, TextEncoder};
+
+    use super::*;
+
+    fn encode(info: &TargetInfo) -> String {
+        let mut out = vec![];
+        TextEncoder.encode(&info.collect(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn annotated(pairs: &[(&str, &[(&str, &str)])]) -> HashMap<String, TargetLabels> {
+        pairs
+            .iter()
+            .map(|(target, labels)| {
+                let labels = labels
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect();
+                (target.to_string(), labels)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn labels_are_the_union_of_annotations() {
+        let info = TargetInfo::new("fping");
+        assert!(info.collect().is_empty());
+
+        info.set_labels(&annotated(&[
+            ("8.8.8.8", &[("env", "prod"), ("region", "us")]),
+            ("1.1.1.1", &[("env", "lab")]),
+        ]));
+        let encoded = encode(&info);
+        assert!(encoded.contains(r#"fping_target_info{env="lab",region="",target="1.1.1.1"} 1"#));
+        assert!(encoded.contains(r#"fping_target_info{env="prod",region="us",target="8.8.8.8"} 1"#));
+
+        // reloads drop targets and label names that are no longer listed
+        info.set_labels(&annotated(&[("1.1.1.1", &[("env", "lab")])]));
+        let encoded = encode(&info);
+        assert!(encoded.contains(r#"fping_target_info{env="lab",target="1.1.1.1"} 1"#));
+        assert!(!encoded.contains("8.8.8.8"));
+
+        info.set_labels(&HashMap::new());
+        assert!(info.collect().is_empty());
+    }
+}
@@ -0,0 +1,48 @@
+use std::{ffi::OsString, io, path::Path};
+
+use prometheus::{proto::MetricFamily, Registry, TextEncoder};
+
+use super::{http::encode_to_vec, RegistryAccess};
+use crate::args::TextfileArgs;
+
+/// Replace `path` without readers ever observing a partially written file.
+///
+/// The temporary file lives next to the destination so the rename stays on
+/// the same filesystem, its suffix keeps it out of the textfile collector's
+/// `*.prom` glob.
+async fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp = OsString::from(path.as_os_str());
+    tmp.push(".tmp");
+
+    tokio::fs::write(&tmp, contents).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+async fn write_metrics(path: &Path, metrics: &[MetricFamily]) -> io::Result<()> {
+    let out = encode_to_vec::<TextEncoder>(metrics)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    write_atomic(path, &out).await?;
+    trace!("wrote {} bytes to {}", out.len(), path.display());
+    Ok(())
+}
+
+/// Writes the registry as-is, without requesting a summary from fping
+pub async fn write_textfile_once(args: &TextfileArgs, reg: &Registry) -> io::Result<()> {
+    write_metrics(&args.path, &reg.gather()).await
+}
+
+pub async fn write_textfile<T: Send + 'static>(
+    args: &TextfileArgs,
+    reg: RegistryAccess<T>,
+) -> io::Result<()> {
+    info!(target: "metrics", "writing metrics to {} every {}", args.path.display(), humantime::format_duration(args.interval));
+
+    let mut interval = tokio::time::interval(args.interval);
+    loop {
+        interval.tick().await;
+        match reg.clone().gather().await {
+            Ok(metrics) => write_metrics(&args.path, &metrics).await?,
+            Err(e) => debug!("skipping textfile output: {}", e),
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::{future, io, time::Duration};
+
+use tokio::{process::Child, sync::mpsc};
+
+use crate::{
+    args::Args,
+    event_stream::{
+        util::signal::{Interruptable, KnownSignals},
+        EventHandler,
+    },
+    fping::{self, Launcher},
+};
+
+const LOOPBACK: &str = "127.0.0.1";
+const PING_DURATION: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Default)]
+struct Probe {
+    pings: u32,
+    summaries: u32,
+    unparsed: Vec<String>,
+}
+
+impl EventHandler for Probe {
+    type Output = String;
+    type Error = String;
+    type Handle = Child;
+    type Token = ();
+
+    fn on_output(&mut self, event: Self::Output) {
+        match fping::Ping::parse(&event) {
+            Some(_) => self.pings += 1,
+            None => self.unparsed.push(event),
+        }
+    }
+
+    fn on_error(&mut self, event: Self::Error) {
+        match fping::Control::parse(&event) {
+            fping::Control::TargetSummary(_) => self.summaries += 1,
+            fping::Control::BlankLine | fping::Control::SummaryLocalTime => {}
+            _ => self.unparsed.push(event),
+        }
+    }
+
+    fn on_control(&mut self, handle: &mut Self::Handle, _: Self::Token) -> io::Result<()> {
+        handle.interrupt(KnownSignals::sigquit()).map(|_| ())
+    }
+
+    fn on_oversized_line(&mut self, stream: &str) {
+        self.unparsed
+            .push(format!("<oversized line on {}>", stream));
+    }
+}
+
+struct Report {
+    failed: bool,
+}
+
+impl Report {
+    fn step(&mut self, passed: bool, description: std::fmt::Arguments) {
+        self.failed |= !passed;
+        println!("[{}] {}", if passed { "PASS" } else { "FAIL" }, description);
+    }
+
+    fn note(&self, description: std::fmt::Arguments) {
+        println!("[INFO] {}", description);
+    }
+}
+
+/// Checks that fping can be launched and its output parsed, printing a
+/// report meant to be pasted into bug reports.
+pub async fn run(launcher: &Launcher<'_>, args: &Args) -> anyhow::Result<()> {
+    let target = args.targets.first().map_or(LOOPBACK, String::as_str);
+    let mut report = Report { failed: false };
+
+    println!("fping_exporter {} self-test", crate_version!());
+    report.step(
+        true,
+        format_args!(
+            "found fping {} at {}",
+            args.fping_version,
+            launcher.program()
+        ),
+    );
+    if args.supports_summary() {
+        report.note(format_args!("summary requests (SIGQUIT) are supported"));
+    } else {
+        report.note(format_args!(
+            "summary requests (SIGQUIT) need fping 4.3.0 or newer, packet loss will not be available"
+        ));
+    }
+
+    let (tx, rx) = mpsc::channel(1);
+    let mut fping = match launcher.spawn(&[target], &args.spawn_options).await {
+        Ok(fping) => fping.with_controls(Some(rx)),
+        Err(e) => {
+            report.step(false, format_args!("spawning fping failed: {}", e));
+            anyhow::bail!("self-test failed");
+        }
+    };
+    report.step(true, format_args!("spawned fping pinging {}", target));
+
+    let mut probe = Probe::default();
+    let summary_trigger = async {
+        tokio::time::sleep(PING_DURATION / 2).await;
+        if args.supports_summary() {
+            let _ = tx.send(()).await;
+        }
+        future::pending::<()>().await
+    };
+    let listened = tokio::time::timeout(PING_DURATION, async {
+        tokio::select! {
+            res = fping.listen(&mut probe) => res,
+            _ = summary_trigger => unreachable!(),
+        }
+    })
+    .await;
+    if let Ok(res) = listened {
+        report.step(false, format_args!("fping exited early: {:?}", res));
+    }
+
+    let mut handle = fping.dispose();
+    if handle.try_wait()?.is_none() {
+        handle.interrupt(KnownSignals::sigint())?;
+        handle.wait().await?;
+    }
+
+    report.step(
+        probe.pings > 0,
+        format_args!("parsed {} ping results", probe.pings),
+    );
+    if args.supports_summary() {
+        report.step(
+            probe.summaries > 0,
+            format_args!("parsed {} summary lines", probe.summaries),
+        );
+    }
+    report.step(
+        probe.unparsed.is_empty(),
+        format_args!("{} unrecognized lines", probe.unparsed.len()),
+    );
+    for line in &probe.unparsed {
+        println!("       {}", line);
+    }
+
+    if report.failed {
+        anyhow::bail!("self-test failed");
+    }
+    println!("self-test passed");
+    Ok(())
+}
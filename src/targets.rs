@@ -0,0 +1,223 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::IpAddr,
+    time::Duration,
+};
+
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use thiserror::Error;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum TargetsError {
+    #[error("unable to fetch targets from {1}: {0}")]
+    Fetch(#[source] reqwest::Error, String),
+    #[error("invalid target {0:?}")]
+    InvalidTarget(String),
+    #[error("invalid label {0:?}, expected <name>=<value> with a name other than target")]
+    InvalidLabel(String),
+    #[error("{0} does not list any targets")]
+    Empty(String),
+}
+
+/// Annotations of a target, exported through `fping_target_info`
+pub type TargetLabels = BTreeMap<String, String>;
+
+fn parse_label(label: &str) -> Result<(String, String), TargetsError> {
+    let invalid = || TargetsError::InvalidLabel(label.to_owned());
+    let (name, value) = label.split_once('=').ok_or_else(invalid)?;
+    let mut chars = name.chars();
+    let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        // reserved by prometheus, target identifies the series to join on
+        && !name.starts_with("__")
+        && name != "target";
+    if valid {
+        Ok((name.to_owned(), value.to_owned()))
+    } else {
+        Err(invalid())
+    }
+}
+
+/// Newline delimited targets, blank lines and `#` comments are ignored.
+/// A target may be followed by whitespace separated `<name>=<value>` labels.
+pub fn parse_annotated_target_list(
+    list: &str,
+) -> Result<Vec<(String, TargetLabels)>, TargetsError> {
+    list.lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            let target = fields.next().unwrap();
+            // targets are passed as arguments, fping would treat these as flags
+            if target.starts_with('-') {
+                return Err(TargetsError::InvalidTarget(target.to_owned()));
+            }
+            let labels = fields.map(parse_label).collect::<Result<_, _>>()?;
+            Ok((target.to_owned(), labels))
+        })
+        .collect()
+}
+
+/// Like `parse_annotated_target_list`, without the labels
+pub fn parse_target_list(list: &str) -> Result<Vec<String>, TargetsError> {
+    Ok(parse_annotated_target_list(list)?
+        .into_iter()
+        .map(|(target, _)| target)
+        .collect())
+}
+
+/// Label for `fping_targets_by_type`, hostnames have to be resolved by
+/// fping and may change address over time.
+pub fn target_type(target: &str) -> &'static str {
+    // scoped IPv6 addresses like fe80::1%eth0
+    let addr = target.split('%').next().unwrap();
+    if addr.parse::<IpAddr>().is_ok() {
+        "ip"
+    } else {
+        "hostname"
+    }
+}
+
+/// Appends `extra` to `targets`, skipping any target listed before
+pub fn merge_targets(targets: &mut Vec<String>, extra: Vec<String>) {
+    let mut seen: HashSet<String> = targets.iter().cloned().collect();
+    targets.extend(
+        extra
+            .into_iter()
+            .filter(|target| seen.insert(target.clone())),
+    );
+}
+
+/// fping probes targets in the order they are given, shuffling keeps the
+/// first targets from always being probed at the same point in a round.
+pub fn shuffle_targets(targets: &mut [String], seed: Option<u64>) {
+    let seed = seed.unwrap_or_else(rand::random);
+    info!("shuffling targets with seed {}", seed);
+    targets.shuffle(&mut StdRng::seed_from_u64(seed));
+}
+
+pub async fn fetch_targets(url: &str) -> Result<Vec<String>, TargetsError> {
+    let fetch = async {
+        reqwest::Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .build()?
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await
+    };
+    let list = fetch
+        .await
+        .map_err(|e| TargetsError::Fetch(e, url.to_owned()))?;
+
+    let mut targets = vec![];
+    merge_targets(&mut targets, parse_target_list(&list)?);
+    if targets.is_empty() {
+        return Err(TargetsError::Empty(url.to_owned()));
+    }
+    info!("fetched {} targets from {}", targets.len(), url);
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_merge() {
+        let fetched = parse_target_list(
+            "# core\n\
+             dns.google\n\
+             \n\
+             1.1.1.1   # cloudflare\r\n\
+             localhost\n",
+        )
+        .unwrap();
+        assert_eq!(fetched, vec!["dns.google", "1.1.1.1", "localhost"]);
+
+        let mut targets = vec!["localhost".to_owned()];
+        merge_targets(&mut targets, fetched);
+        assert_eq!(targets, vec!["localhost", "dns.google", "1.1.1.1"]);
+
+        assert!(matches!(
+            parse_target_list("dns.google\n-f /etc/hosts"),
+            Err(TargetsError::InvalidTarget(_))
+        ));
+    }
+
+    #[test]
+    fn annotated_targets() {
+        let listed = parse_annotated_target_list(
+            "8.8.8.8   env=prod region=us # google\n\
+             localhost\n\
+             1.1.1.1\tenv=\n",
+        )
+        .unwrap();
+        let labels = |pairs: &[(&str, &str)]| -> TargetLabels {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            listed,
+            vec![
+                (
+                    "8.8.8.8".to_owned(),
+                    labels(&[("env", "prod"), ("region", "us")])
+                ),
+                ("localhost".to_owned(), labels(&[])),
+                ("1.1.1.1".to_owned(), labels(&[("env", "")])),
+            ]
+        );
+
+        for invalid in &[
+            "dns.google prod",
+            "dns.google target=x",
+            "dns.google __name__=x",
+            "dns.google 1env=x",
+            "dns.google site-a=x",
+        ] {
+            assert!(
+                matches!(
+                    parse_annotated_target_list(invalid),
+                    Err(TargetsError::InvalidLabel(_))
+                ),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn classify_targets() {
+        assert_eq!(target_type("1.1.1.1"), "ip");
+        assert_eq!(target_type("2001:db8::1"), "ip");
+        assert_eq!(target_type("fe80::1%eth0"), "ip");
+        assert_eq!(target_type("dns.google"), "hostname");
+        assert_eq!(target_type("localhost"), "hostname");
+    }
+
+    #[test]
+    fn seeded_shuffle_is_reproducible() {
+        let targets: Vec<String> = (0..16).map(|i| format!("10.0.0.{}", i)).collect();
+        let shuffled = |seed| {
+            let mut targets = targets.clone();
+            shuffle_targets(&mut targets, Some(seed));
+            targets
+        };
+
+        assert_eq!(shuffled(42), shuffled(42));
+        assert_ne!(shuffled(42), targets);
+        let mut sorted = shuffled(42);
+        sorted.sort();
+        let mut expected = targets.clone();
+        expected.sort();
+        assert_eq!(sorted, expected);
+    }
+}